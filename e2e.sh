@@ -18,14 +18,14 @@ keys=$($SHADE gen-keys)
 public_key=$(echo "$keys" | jq -r .public)
 private_key=$(echo "$keys" | jq -r .private)
 
-# Register the private key
-$SHADE register-key --private-key "$private_key"
+# Register the public key
+$SHADE register-key --public-key "$public_key"
 
 # List keys
 $SHADE list-keys
 
 # register host
-$SHADE register-host --public-key "$public_key" --url "http://localhost:3000"
+$SHADE register-host --private-key "$private_key" --url "http://localhost:3000"
 
 # List host
 $SHADE list-hosts
@@ -0,0 +1,603 @@
+use crate::audit::{AuditAction, AuditEvent, Auditor, Decision};
+use crate::config::AdminConfig;
+use crate::models::{
+    CreateHostRequest, CreateKeyRequest, ExpireKeyRequest, HostResponse, KeyResponse,
+    RevokeKeyResponse,
+};
+use crate::reload::LiveConfig;
+use crate::storage::{normalize_network, KeyRecord, StorageBackend};
+use actix_tls::accept::rustls_0_23::TlsStream;
+use actix_web::body::{EitherBody, MessageBody};
+use actix_web::dev::{Extensions, ServiceRequest, ServiceResponse};
+use actix_web::http::header::{HeaderMap, AUTHORIZATION, WWW_AUTHENTICATE};
+use actix_web::middleware::{from_fn, Next};
+use actix_web::rt::net::TcpStream;
+use actix_web::{delete, get, post, web, HttpMessage, HttpResponse};
+use base64::{engine::general_purpose, Engine as _};
+use chrono::Utc;
+use ipnet::IpNet;
+use rand::rngs::OsRng;
+use rand::RngCore;
+use sha2::{Digest, Sha256};
+use std::any::Any;
+use std::net::IpAddr;
+use std::sync::Arc;
+use tracing::{error, warn};
+use uuid::Uuid;
+
+type Storage = web::Data<Arc<dyn StorageBackend>>;
+
+/// The admin credential a request was authenticated with
+#[derive(Debug, Clone)]
+pub struct Admin {
+    name: String,
+}
+
+impl Admin {
+    fn event(&self, action: AuditAction) -> AuditEvent {
+        AuditEvent::new(action, &format!("admin:{}", self.name), Decision::Allow)
+    }
+}
+
+/// SPKI pin of the certificate a client presented to the API's TLS listener
+#[derive(Debug, Clone)]
+struct PeerCertificate(String);
+
+/// Keep the client certificate of a TLS connection, if any, for
+/// `require_admin` to check against `server.admin.client_certs`.
+pub fn on_connect(connection: &dyn Any, data: &mut Extensions) {
+    if let Some(stream) = connection.downcast_ref::<TlsStream<TcpStream>>()
+        && let Some(cert) = stream
+            .get_ref()
+            .1
+            .peer_certificates()
+            .and_then(|certs| certs.first())
+        && let Ok(pin) = crate::tls::spki_sha256(cert)
+    {
+        data.insert(PeerCertificate(pin));
+    }
+}
+
+pub fn configure(cfg: &mut web::ServiceConfig) {
+    cfg.service(
+        web::scope("/admin")
+            .wrap(from_fn(require_admin))
+            .service(list_keys)
+            .service(create_key)
+            .service(get_key)
+            .service(revoke_key)
+            .service(expire_key)
+            .service(list_hosts)
+            .service(create_host)
+            .service(get_host)
+            .service(delete_host),
+    );
+}
+
+/// Let a request through only with a configured bearer token or client
+/// certificate. Credentials are read from the live configuration, so they
+/// can be rotated with a reload.
+async fn require_admin(
+    req: ServiceRequest,
+    next: Next<impl MessageBody>,
+) -> Result<ServiceResponse<EitherBody<impl MessageBody>>, actix_web::Error> {
+    let (authenticated, client_ip) = {
+        let config = req
+            .app_data::<web::Data<LiveConfig>>()
+            .map(|live_config| Arc::clone(&live_config.borrow()));
+        let Some(config) = config else {
+            return Err(actix_web::error::ErrorInternalServerError(
+                "no configuration",
+            ));
+        };
+        let client_ip = req
+            .peer_addr()
+            .and_then(|peer| {
+                crate::client_ip::resolve(peer.ip(), req.headers(), &config.server.trusted_proxies)
+            })
+            .map(|(_source, ip)| ip);
+        let cert = req.conn_data::<PeerCertificate>();
+        (
+            authenticate(&config.server.admin, req.headers(), cert),
+            client_ip,
+        )
+    };
+
+    match authenticated {
+        Ok(admin) => {
+            req.extensions_mut().insert(admin);
+            next.call(req)
+                .await
+                .map(ServiceResponse::map_into_left_body)
+        }
+        Err(reason) => {
+            warn!(client = ?client_ip, reason, "admin request rejected");
+            if let Some(audit) = req.app_data::<web::Data<Auditor>>() {
+                let event = AuditEvent::new(AuditAction::AdminAccess, "api", Decision::Deny)
+                    .with_ip(client_ip)
+                    .with_reason(reason);
+                audit.record_sampled(event);
+            }
+            let response = HttpResponse::Unauthorized()
+                .insert_header((WWW_AUTHENTICATE, "Bearer"))
+                .body("Admin credentials required");
+            Ok(req.into_response(response).map_into_right_body())
+        }
+    }
+}
+
+/// Match a bearer token, or failing that a client certificate, against the
+/// configured credentials. Errors are audit reasons.
+fn authenticate(
+    config: &AdminConfig,
+    headers: &HeaderMap,
+    cert: Option<&PeerCertificate>,
+) -> Result<Admin, &'static str> {
+    if let Some(authorization) = headers.get(AUTHORIZATION) {
+        let token = authorization
+            .to_str()
+            .ok()
+            .and_then(|value| value.strip_prefix("Bearer "))
+            .ok_or("malformed_authorization")?;
+        let digest = token_sha256(token.trim());
+        return config
+            .tokens
+            .iter()
+            .find(|admin| admin.sha256.eq_ignore_ascii_case(&digest))
+            .map(|admin| Admin {
+                name: admin.name.clone(),
+            })
+            .ok_or("unknown_token");
+    }
+    if let Some(PeerCertificate(pin)) = cert {
+        return config
+            .client_certs
+            .iter()
+            .find(|admin| admin.spki_sha256.trim() == pin)
+            .map(|admin| Admin {
+                name: admin.name.clone(),
+            })
+            .ok_or("unknown_client_certificate");
+    }
+    Err("no_credentials")
+}
+
+/// A random bearer token and the hex SHA-256 to configure for it.
+pub fn generate_token() -> (String, String) {
+    let mut token = [0u8; 32];
+    OsRng.fill_bytes(&mut token);
+    let token = general_purpose::URL_SAFE_NO_PAD.encode(token);
+    let digest = token_sha256(&token);
+    (token, digest)
+}
+
+fn token_sha256(token: &str) -> String {
+    Sha256::digest(token.as_bytes())
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect()
+}
+
+fn storage_error(e: anyhow::Error) -> HttpResponse {
+    error!("admin storage operation failed: {:#}", e);
+    HttpResponse::InternalServerError().body("Storage error")
+}
+
+#[utoipa::path(
+    get,
+    path = "/admin/keys",
+    responses(
+        (status = 200, description = "Every registered key", body = [KeyResponse]),
+        (status = 401, description = "Missing or unknown admin credentials")
+    ),
+    security(("admin_token" = []), ("admin_certificate" = []))
+)]
+#[get("/keys")]
+async fn list_keys(storage: Storage) -> HttpResponse {
+    match storage.list_keys().await {
+        Ok(keys) => {
+            HttpResponse::Ok().json(keys.into_iter().map(KeyResponse::from).collect::<Vec<_>>())
+        }
+        Err(e) => storage_error(e),
+    }
+}
+
+#[utoipa::path(
+    post,
+    path = "/admin/keys",
+    request_body = CreateKeyRequest,
+    responses(
+        (status = 201, description = "Key registered", body = KeyResponse),
+        (status = 400, description = "Invalid key, prefix length, group or pin"),
+        (status = 401, description = "Missing or unknown admin credentials")
+    ),
+    security(("admin_token" = []), ("admin_certificate" = []))
+)]
+#[tracing::instrument(name = "admin_create_key", skip_all, fields(admin = %admin.name))]
+#[post("/keys")]
+async fn create_key(
+    admin: web::ReqData<Admin>,
+    storage: Storage,
+    audit: web::Data<Auditor>,
+    body: web::Json<CreateKeyRequest>,
+) -> HttpResponse {
+    let body = body.into_inner();
+    let key = match KeyRecord::new(body.public_key, body.expires_at)
+        .and_then(|key| key.with_prefix_lens(body.ipv4_prefix_len, body.ipv6_prefix_len))
+        .and_then(|key| key.with_groups(body.groups))
+        .and_then(|key| key.with_spki_sha256(body.spki_sha256))
+    {
+        Ok(key) => key,
+        Err(e) => return HttpResponse::BadRequest().body(format!("{:#}", e)),
+    };
+
+    let registered = storage.register_key(key.clone()).await;
+    let event = admin
+        .event(AuditAction::KeyRegistered)
+        .with_key(Some(key.id));
+    audit.record(event.with_outcome(&registered));
+    match registered {
+        Ok(()) => HttpResponse::Created().json(KeyResponse::from(key)),
+        Err(e) => storage_error(e),
+    }
+}
+
+#[utoipa::path(
+    get,
+    path = "/admin/keys/{id}",
+    params(("id" = Uuid, Path, description = "Key ID")),
+    responses(
+        (status = 200, description = "The key", body = KeyResponse),
+        (status = 401, description = "Missing or unknown admin credentials"),
+        (status = 404, description = "No such key")
+    ),
+    security(("admin_token" = []), ("admin_certificate" = []))
+)]
+#[get("/keys/{id}")]
+async fn get_key(storage: Storage, id: web::Path<Uuid>) -> HttpResponse {
+    key_response(&storage, *id).await
+}
+
+#[utoipa::path(
+    delete,
+    path = "/admin/keys/{id}",
+    params(("id" = Uuid, Path, description = "Key ID")),
+    responses(
+        (status = 200, description = "Key revoked along with every host it enrolled", body = RevokeKeyResponse),
+        (status = 401, description = "Missing or unknown admin credentials"),
+        (status = 404, description = "No such key")
+    ),
+    security(("admin_token" = []), ("admin_certificate" = []))
+)]
+#[tracing::instrument(name = "admin_revoke_key", skip_all, fields(admin = %admin.name, key_id = %id))]
+#[delete("/keys/{id}")]
+async fn revoke_key(
+    admin: web::ReqData<Admin>,
+    storage: Storage,
+    audit: web::Data<Auditor>,
+    id: web::Path<Uuid>,
+) -> HttpResponse {
+    let id = id.into_inner();
+    let revoked = storage.revoke_key(id).await;
+    let event = admin.event(AuditAction::KeyRevoked).with_key(Some(id));
+    audit.record(event.with_found(&revoked));
+    match revoked {
+        Ok(Some(hosts_removed)) => HttpResponse::Ok().json(RevokeKeyResponse { hosts_removed }),
+        Ok(None) => HttpResponse::NotFound().body("Key not found"),
+        Err(e) => storage_error(e),
+    }
+}
+
+#[utoipa::path(
+    post,
+    path = "/admin/keys/{id}/expire",
+    params(("id" = Uuid, Path, description = "Key ID")),
+    request_body = ExpireKeyRequest,
+    responses(
+        (status = 200, description = "Key expiry set; its hosts lapse with it", body = KeyResponse),
+        (status = 401, description = "Missing or unknown admin credentials"),
+        (status = 404, description = "No such key")
+    ),
+    security(("admin_token" = []), ("admin_certificate" = []))
+)]
+#[tracing::instrument(name = "admin_expire_key", skip_all, fields(admin = %admin.name, key_id = %id))]
+#[post("/keys/{id}/expire")]
+async fn expire_key(
+    admin: web::ReqData<Admin>,
+    storage: Storage,
+    audit: web::Data<Auditor>,
+    id: web::Path<Uuid>,
+    body: web::Json<ExpireKeyRequest>,
+) -> HttpResponse {
+    let id = id.into_inner();
+    let expires_at = body.expires_at.unwrap_or_else(Utc::now);
+
+    let expired = storage.expire_key(id, expires_at).await;
+    if matches!(expired, Ok(false)) {
+        return HttpResponse::NotFound().body("Key not found");
+    }
+    let event = admin
+        .event(AuditAction::KeyExpired)
+        .with_key(Some(id))
+        .with_reason(format!("expires_at={}", expires_at.to_rfc3339()));
+    audit.record(event.with_outcome(&expired));
+    if let Err(e) = expired {
+        return storage_error(e);
+    }
+    key_response(&storage, id).await
+}
+
+#[utoipa::path(
+    get,
+    path = "/admin/hosts",
+    responses(
+        (status = 200, description = "Every enrolled host and allowed network", body = [HostResponse]),
+        (status = 401, description = "Missing or unknown admin credentials")
+    ),
+    security(("admin_token" = []), ("admin_certificate" = []))
+)]
+#[get("/hosts")]
+async fn list_hosts(storage: Storage) -> HttpResponse {
+    match storage.list_hosts().await {
+        Ok(hosts) => HttpResponse::Ok().json(
+            hosts
+                .into_iter()
+                .map(HostResponse::from)
+                .collect::<Vec<_>>(),
+        ),
+        Err(e) => storage_error(e),
+    }
+}
+
+#[utoipa::path(
+    post,
+    path = "/admin/hosts",
+    request_body = CreateHostRequest,
+    responses(
+        (status = 201, description = "Network allowed", body = HostResponse),
+        (status = 401, description = "Missing or unknown admin credentials"),
+        (status = 404, description = "No such owning key")
+    ),
+    security(("admin_token" = []), ("admin_certificate" = []))
+)]
+#[tracing::instrument(name = "admin_create_host", skip_all, fields(admin = %admin.name, network = %body.network))]
+#[post("/hosts")]
+async fn create_host(
+    admin: web::ReqData<Admin>,
+    storage: Storage,
+    audit: web::Data<Auditor>,
+    body: web::Json<CreateHostRequest>,
+) -> HttpResponse {
+    let CreateHostRequest {
+        network,
+        key_id,
+        expires_at,
+    } = body.into_inner();
+    let network = normalize_network(network);
+    if let Some(key_id) = key_id {
+        match storage.get_key(key_id).await {
+            Ok(Some(_)) => {}
+            Ok(None) => return HttpResponse::NotFound().body("Key not found"),
+            Err(e) => return storage_error(e),
+        }
+    }
+
+    let allowed = storage.allow_network(network, key_id, expires_at).await;
+    let event = admin
+        .event(AuditAction::CidrAllowed)
+        .with_key(key_id)
+        .with_network(Some(network));
+    audit.record(event.with_outcome(&allowed));
+    if let Err(e) = allowed {
+        return storage_error(e);
+    }
+    match find_host(&storage, network).await {
+        Ok(Some(host)) => HttpResponse::Created().json(host),
+        Ok(None) => HttpResponse::NotFound().body("Host not found"),
+        Err(e) => storage_error(e),
+    }
+}
+
+#[utoipa::path(
+    get,
+    path = "/admin/hosts/{address}/{prefix_len}",
+    params(
+        ("address" = String, Path, description = "Network address, e.g. 203.0.113.0"),
+        ("prefix_len" = u8, Path, description = "Network prefix length, e.g. 24")
+    ),
+    responses(
+        (status = 200, description = "The host entry", body = HostResponse),
+        (status = 400, description = "Invalid network"),
+        (status = 401, description = "Missing or unknown admin credentials"),
+        (status = 404, description = "No such host entry")
+    ),
+    security(("admin_token" = []), ("admin_certificate" = []))
+)]
+#[get("/hosts/{address}/{prefix_len}")]
+async fn get_host(storage: Storage, path: web::Path<(IpAddr, u8)>) -> HttpResponse {
+    let Ok(network) = IpNet::new(path.0, path.1) else {
+        return HttpResponse::BadRequest().body("Invalid prefix length");
+    };
+    match find_host(&storage, normalize_network(network)).await {
+        Ok(Some(host)) => HttpResponse::Ok().json(host),
+        Ok(None) => HttpResponse::NotFound().body("Host not found"),
+        Err(e) => storage_error(e),
+    }
+}
+
+#[utoipa::path(
+    delete,
+    path = "/admin/hosts/{address}/{prefix_len}",
+    params(
+        ("address" = String, Path, description = "Network address, e.g. 203.0.113.0"),
+        ("prefix_len" = u8, Path, description = "Network prefix length, e.g. 24")
+    ),
+    responses(
+        (status = 204, description = "Host entry deleted"),
+        (status = 400, description = "Invalid network"),
+        (status = 401, description = "Missing or unknown admin credentials"),
+        (status = 404, description = "No such host entry")
+    ),
+    security(("admin_token" = []), ("admin_certificate" = []))
+)]
+#[tracing::instrument(name = "admin_delete_host", skip_all, fields(admin = %admin.name))]
+#[delete("/hosts/{address}/{prefix_len}")]
+async fn delete_host(
+    admin: web::ReqData<Admin>,
+    storage: Storage,
+    audit: web::Data<Auditor>,
+    path: web::Path<(IpAddr, u8)>,
+) -> HttpResponse {
+    let Ok(network) = IpNet::new(path.0, path.1) else {
+        return HttpResponse::BadRequest().body("Invalid prefix length");
+    };
+    let network = normalize_network(network);
+
+    let deleted = storage.delete_host(network).await;
+    if matches!(deleted, Ok(false)) {
+        return HttpResponse::NotFound().body("Host not found");
+    }
+    let event = admin
+        .event(AuditAction::HostDeleted)
+        .with_network(Some(network));
+    audit.record(event.with_outcome(&deleted));
+    match deleted {
+        Ok(_) => HttpResponse::NoContent().finish(),
+        Err(e) => storage_error(e),
+    }
+}
+
+async fn key_response(storage: &Storage, id: Uuid) -> HttpResponse {
+    match storage.get_key(id).await {
+        Ok(Some(key)) => HttpResponse::Ok().json(KeyResponse::from(key)),
+        Ok(None) => HttpResponse::NotFound().body("Key not found"),
+        Err(e) => storage_error(e),
+    }
+}
+
+async fn find_host(storage: &Storage, network: IpNet) -> anyhow::Result<Option<HostResponse>> {
+    let hosts = storage.list_hosts().await?;
+    Ok(hosts
+        .into_iter()
+        .find(|host| host.network == network)
+        .map(HostResponse::from))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{AdminClientCert, AdminToken, AuditConfig, Config};
+    use actix_web::http::StatusCode;
+    use actix_web::{test as http, App};
+
+    fn admin_config(token_sha256: &str) -> AdminConfig {
+        AdminConfig {
+            tokens: vec![AdminToken {
+                name: "ops".to_string(),
+                sha256: token_sha256.to_string(),
+            }],
+            client_certs: vec![AdminClientCert {
+                name: "laptop".to_string(),
+                spki_sha256: "pinned".to_string(),
+            }],
+        }
+    }
+
+    fn bearer(token: &str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(AUTHORIZATION, format!("Bearer {}", token).parse().unwrap());
+        headers
+    }
+
+    #[test]
+    fn tokens_are_matched_by_digest() {
+        let (token, digest) = generate_token();
+        let config = admin_config(&digest.to_uppercase());
+
+        let admin = authenticate(&config, &bearer(&token), None).unwrap();
+        assert_eq!(admin.name, "ops");
+        assert_eq!(
+            authenticate(&config, &bearer("not-the-token"), None).unwrap_err(),
+            "unknown_token"
+        );
+        assert_eq!(
+            authenticate(&config, &HeaderMap::new(), None).unwrap_err(),
+            "no_credentials"
+        );
+
+        let mut basic = HeaderMap::new();
+        basic.insert(AUTHORIZATION, "Basic b3BzOm9wcw==".parse().unwrap());
+        assert_eq!(
+            authenticate(&config, &basic, None).unwrap_err(),
+            "malformed_authorization"
+        );
+    }
+
+    #[test]
+    fn client_certificates_are_matched_by_pin() {
+        let (_token, digest) = generate_token();
+        let config = admin_config(&digest);
+
+        let pinned = PeerCertificate("pinned".to_string());
+        let admin = authenticate(&config, &HeaderMap::new(), Some(&pinned)).unwrap();
+        assert_eq!(admin.name, "laptop");
+
+        let other = PeerCertificate("other".to_string());
+        assert_eq!(
+            authenticate(&config, &HeaderMap::new(), Some(&other)).unwrap_err(),
+            "unknown_client_certificate"
+        );
+        // A presented token is decisive, even alongside a pinned certificate
+        assert_eq!(
+            authenticate(&config, &bearer("not-the-token"), Some(&pinned)).unwrap_err(),
+            "unknown_token"
+        );
+    }
+
+    #[actix_web::test]
+    async fn admin_routes_require_a_configured_token() {
+        let storage: Arc<dyn StorageBackend> =
+            Arc::new(crate::storage::SqliteStorage::in_memory().await.unwrap());
+        let audit = AuditConfig {
+            storage: false,
+            file: None,
+        };
+        let audit = Auditor::start(&audit, storage.clone()).await.unwrap();
+        let (token, digest) = generate_token();
+        let mut config = Config::default();
+        config.server.admin = admin_config(&digest);
+        let (_config, live_config) = tokio::sync::watch::channel(Arc::new(config));
+
+        let app = http::init_service(
+            App::new()
+                .app_data(web::Data::new(storage))
+                .app_data(web::Data::new(live_config))
+                .app_data(web::Data::from(audit))
+                .configure(configure),
+        )
+        .await;
+        let peer = "127.0.0.1:40000".parse().unwrap();
+        let get_keys = |authorization: Option<String>| {
+            let request = http::TestRequest::get().uri("/admin/keys").peer_addr(peer);
+            match authorization {
+                Some(value) => request.insert_header((AUTHORIZATION, value)),
+                None => request,
+            }
+            .to_request()
+        };
+
+        let res = http::call_service(&app, get_keys(None)).await;
+        assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+        assert_eq!(res.headers().get(WWW_AUTHENTICATE).unwrap(), "Bearer");
+
+        let wrong = Some("Bearer not-the-token".to_string());
+        let res = http::call_service(&app, get_keys(wrong)).await;
+        assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+
+        let right = Some(format!("Bearer {}", token));
+        let res = http::call_service(&app, get_keys(right)).await;
+        assert_eq!(res.status(), StatusCode::OK);
+        let keys: Vec<serde_json::Value> = http::read_body_json(res).await;
+        assert!(keys.is_empty());
+    }
+}
@@ -0,0 +1,489 @@
+use crate::config::{AllowlistConfig, RouteConfig, StaleFallback};
+use crate::storage::{AllowEntry, HostChange, StorageBackend};
+use anyhow::Result;
+use chrono::{DateTime, Utc};
+use ipnet::IpNet;
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant};
+use tokio::sync::broadcast::error::RecvError;
+use tracing::{error, info, warn};
+use uuid::Uuid;
+
+/// In-memory copy of the host allowlist, so the proxy does not have to query
+/// storage for every accepted connection.
+///
+/// The cache is loaded in full at start and every `refresh_secs`, and kept
+/// coherent in between by the storage layer's change notifications. Changes
+/// made by another process (e.g. the CLI in file mode) are noticed by polling
+/// storage's allowlist version every `poll_ms`, which triggers a reload. If
+/// no reload has succeeded for `max_stale_secs`, or
+/// notifications were missed and the catch-up reload failed, lookups follow
+/// `stale_fallback` instead.
+#[derive(Debug)]
+pub struct Allowlist {
+    storage: Arc<dyn StorageBackend>,
+    config: AllowlistConfig,
+    state: RwLock<State>,
+    // Bumped whenever the cache changes, so callers can reuse a lookup
+    generation: AtomicU64,
+}
+
+#[derive(Debug, Default)]
+struct State {
+    trie: PrefixTrie,
+    owners: HashMap<IpNet, Option<Uuid>>,
+    synced_at: Option<Instant>,
+    // Storage's allowlist version when the cache was loaded
+    version: Option<i64>,
+}
+
+impl Allowlist {
+    /// Load the allowlist from `storage` and keep it in sync in the background.
+    pub async fn start(
+        storage: Arc<dyn StorageBackend>,
+        config: AllowlistConfig,
+    ) -> Result<Arc<Self>> {
+        // Subscribe before the initial load so no change can slip in between
+        let mut changes = storage.subscribe();
+        let allowlist = Arc::new(Self {
+            storage,
+            config,
+            state: RwLock::new(State::default()),
+            generation: AtomicU64::new(0),
+        });
+        allowlist.reload().await?;
+
+        let this = Arc::clone(&allowlist);
+        tokio::spawn(async move {
+            let mut ticker = tokio::time::interval(Duration::from_secs(this.config.refresh_secs));
+            ticker.tick().await; // the first tick completes immediately
+            let mut poll = tokio::time::interval(Duration::from_millis(this.config.poll_ms));
+            poll.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+            let mut listening = true;
+            loop {
+                tokio::select! {
+                    change = changes.recv(), if listening => match change {
+                        Ok(change) => this.apply(change),
+                        Err(RecvError::Lagged(missed)) => {
+                            warn!("allowlist missed {} storage changes, reloading", missed);
+                            this.mark_stale();
+                            this.reload_logged().await;
+                        }
+                        Err(RecvError::Closed) => {
+                            warn!("storage change notifications closed, relying on periodic reloads");
+                            listening = false;
+                        }
+                    },
+                    _ = ticker.tick() => this.reload_logged().await,
+                    _ = poll.tick() => this.reload_if_changed().await,
+                }
+            }
+        });
+
+        Ok(allowlist)
+    }
+
+    /// The most specific unexpired host entry that `ip` falls inside and that
+    /// grants access to `route`, if any.
+    pub async fn lookup(&self, ip: IpAddr, route: &RouteConfig) -> Result<Option<AllowEntry>> {
+        {
+            let state = self.state.read().unwrap_or_else(|e| e.into_inner());
+            let fresh = state.synced_at.is_some_and(|synced_at| {
+                synced_at.elapsed() < Duration::from_secs(self.config.max_stale_secs)
+            });
+            if fresh || self.config.stale_fallback == StaleFallback::Cache {
+                return Ok(state
+                    .trie
+                    .lookup(ip, Utc::now(), |entry| route.permits(entry))
+                    .cloned());
+            }
+        }
+
+        match self.config.stale_fallback {
+            StaleFallback::Storage => Ok(self
+                .storage
+                .find_allow_entries(ip)
+                .await?
+                .into_iter()
+                .filter(|entry| route.permits(entry))
+                .max_by_key(|entry| entry.network.prefix_len())),
+            StaleFallback::Deny => Ok(None),
+            StaleFallback::Cache => unreachable!("answered from the cache above"),
+        }
+    }
+
+    /// Changes whenever a lookup might give a different answer than before,
+    /// other than through an entry running past its expiry.
+    pub fn generation(&self) -> u64 {
+        self.generation.load(Ordering::Acquire)
+    }
+
+    fn changed(&self) {
+        self.generation.fetch_add(1, Ordering::AcqRel);
+    }
+
+    /// Replace the cache with the current contents of storage.
+    async fn reload(&self) -> Result<()> {
+        // Read first, so a change made during the load is caught next poll
+        let version = self.storage.allowlist_version().await?;
+        let entries = self.storage.list_allow_entries().await?;
+        let mut fresh = State {
+            synced_at: Some(Instant::now()),
+            version: Some(version),
+            ..State::default()
+        };
+        for entry in entries {
+            fresh.upsert(entry);
+        }
+        let count = fresh.owners.len();
+        *self.state.write().unwrap_or_else(|e| e.into_inner()) = fresh;
+        self.changed();
+        info!("allowlist loaded {} host entries", count);
+        Ok(())
+    }
+
+    async fn reload_logged(&self) {
+        if let Err(e) = self.reload().await {
+            error!("Failed to reload allowlist: {}", e);
+        }
+    }
+
+    /// Reload if storage has changed since the cache was loaded, whether
+    /// through this process or another.
+    async fn reload_if_changed(&self) {
+        let version = match self.storage.allowlist_version().await {
+            Ok(version) => version,
+            Err(e) => {
+                error!("Failed to check allowlist for changes: {}", e);
+                return;
+            }
+        };
+        let loaded = self.state.read().unwrap_or_else(|e| e.into_inner()).version;
+        if loaded != Some(version) {
+            self.reload_logged().await;
+        }
+    }
+
+    fn mark_stale(&self) {
+        self.state
+            .write()
+            .unwrap_or_else(|e| e.into_inner())
+            .synced_at = None;
+        self.changed();
+    }
+
+    fn apply(&self, change: HostChange) {
+        let mut state = self.state.write().unwrap_or_else(|e| e.into_inner());
+        match change {
+            HostChange::Upserted(entry) => state.upsert(entry),
+            HostChange::Removed(network) => state.remove(network),
+            HostChange::KeyRevoked(key_id) => {
+                let owned: Vec<IpNet> = state
+                    .owners
+                    .iter()
+                    .filter(|(_, owner)| **owner == Some(key_id))
+                    .map(|(network, _)| *network)
+                    .collect();
+                for network in owned {
+                    state.remove(network);
+                }
+            }
+        }
+        drop(state);
+        self.changed();
+    }
+}
+
+impl State {
+    fn upsert(&mut self, entry: AllowEntry) {
+        self.owners.insert(entry.network, entry.key_id);
+        self.trie.insert(entry);
+    }
+
+    fn remove(&mut self, network: IpNet) {
+        self.trie.remove(network);
+        self.owners.remove(&network);
+    }
+}
+
+/// Binary trie over 128-bit addresses, with IPv4 held as `::ffff:a.b.c.d`
+/// the same way storage bounds are. Each node may carry the host entry whose
+/// prefix ends there. Removed entries leave their nodes
+/// behind until the next full reload rebuilds the trie.
+#[derive(Debug)]
+struct PrefixTrie {
+    nodes: Vec<Node>,
+}
+
+#[derive(Debug, Default)]
+struct Node {
+    children: [Option<u32>; 2],
+    entry: Option<AllowEntry>,
+}
+
+impl Default for PrefixTrie {
+    fn default() -> Self {
+        Self {
+            nodes: vec![Node::default()],
+        }
+    }
+}
+
+impl PrefixTrie {
+    fn insert(&mut self, entry: AllowEntry) {
+        let (bits, prefix_len) = prefix_bits(entry.network);
+        let mut node = 0;
+        for depth in 0..prefix_len {
+            let bit = bit_at(bits, depth);
+            node = match self.nodes[node].children[bit] {
+                Some(child) => child as usize,
+                None => {
+                    self.nodes.push(Node::default());
+                    let child = self.nodes.len() - 1;
+                    self.nodes[node].children[bit] = Some(child as u32);
+                    child
+                }
+            };
+        }
+        self.nodes[node].entry = Some(entry);
+    }
+
+    fn remove(&mut self, network: IpNet) {
+        if let Some(node) = self.find(network) {
+            self.nodes[node].entry = None;
+        }
+    }
+
+    fn find(&self, network: IpNet) -> Option<usize> {
+        let (bits, prefix_len) = prefix_bits(network);
+        let mut node = 0;
+        for depth in 0..prefix_len {
+            node = self.nodes[node].children[bit_at(bits, depth)]? as usize;
+        }
+        Some(node)
+    }
+
+    /// The longest unexpired entry covering `ip` that satisfies `permits`.
+    fn lookup(
+        &self,
+        ip: IpAddr,
+        now: DateTime<Utc>,
+        permits: impl Fn(&AllowEntry) -> bool,
+    ) -> Option<&AllowEntry> {
+        let bits = address_bits(ip);
+        let mut node = 0;
+        let mut found = None;
+        for depth in 0..=128 {
+            if let Some(entry) = &self.nodes[node].entry
+                && entry.expires_at.is_none_or(|expires_at| expires_at > now)
+                && permits(entry)
+            {
+                found = Some(entry);
+            }
+            if depth == 128 {
+                break;
+            }
+            match self.nodes[node].children[bit_at(bits, depth)] {
+                Some(child) => node = child as usize,
+                None => break,
+            }
+        }
+        found
+    }
+}
+
+fn address_bits(ip: IpAddr) -> u128 {
+    match ip.to_canonical() {
+        IpAddr::V4(v4) => u128::from(v4.to_ipv6_mapped()),
+        IpAddr::V6(v6) => u128::from(v6),
+    }
+}
+
+fn prefix_bits(network: IpNet) -> (u128, u8) {
+    let network = crate::storage::normalize_network(network);
+    match network {
+        IpNet::V4(v4) => (address_bits(IpAddr::V4(v4.network())), v4.prefix_len() + 96),
+        IpNet::V6(v6) => (address_bits(IpAddr::V6(v6.network())), v6.prefix_len()),
+    }
+}
+
+fn bit_at(bits: u128, depth: u8) -> usize {
+    ((bits >> (127 - depth)) & 1) as usize
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::{KeyRecord, SqliteStorage};
+
+    async fn open(path: &std::path::Path) -> Arc<dyn StorageBackend> {
+        let url = format!("sqlite://{}", path.display());
+        Arc::new(SqliteStorage::new(&url).await.unwrap())
+    }
+
+    /// Wait up to two seconds for `ip`'s access to `route` to become `allowed`.
+    async fn until_allowed(allowlist: &Allowlist, ip: IpAddr, route: &RouteConfig, allowed: bool) {
+        let deadline = Instant::now() + Duration::from_secs(2);
+        while allowlist.lookup(ip, route).await.unwrap().is_some() != allowed {
+            assert!(
+                Instant::now() < deadline,
+                "{} allowed is not {}",
+                ip,
+                allowed
+            );
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+    }
+
+    #[tokio::test]
+    async fn changes_made_by_another_process_are_picked_up() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("shade.db");
+        // Two pools on one file, as `shade server` and the CLI in file mode
+        let server = open(&path).await;
+        let cli = open(&path).await;
+        let config = AllowlistConfig {
+            poll_ms: 50,
+            ..AllowlistConfig::default()
+        };
+        let allowlist = Allowlist::start(server, config).await.unwrap();
+        let route = RouteConfig::new("test", "127.0.0.1:0", "127.0.0.1:1");
+        let ip: IpAddr = "192.0.2.1".parse().unwrap();
+        assert!(allowlist.lookup(ip, &route).await.unwrap().is_none());
+
+        let (_, public_key) = crate::cert::generate_keys().unwrap();
+        let key = KeyRecord::new(public_key, None).unwrap();
+        cli.register_key(key.clone()).await.unwrap();
+        cli.store_client_ip("192.0.2.1/32".parse().unwrap(), key.id, None, None)
+            .await
+            .unwrap();
+        until_allowed(&allowlist, ip, &route, true).await;
+
+        cli.revoke_key(key.id).await.unwrap();
+        until_allowed(&allowlist, ip, &route, false).await;
+    }
+
+    fn entry(network: &str) -> AllowEntry {
+        AllowEntry {
+            network: network.parse().unwrap(),
+            key_id: None,
+            groups: Vec::new(),
+            expires_at: None,
+        }
+    }
+
+    fn trie(networks: &[&str]) -> PrefixTrie {
+        let mut trie = PrefixTrie::default();
+        for network in networks {
+            trie.insert(entry(network));
+        }
+        trie
+    }
+
+    fn allows(trie: &PrefixTrie, ip: &str) -> bool {
+        trie.lookup(ip.parse().unwrap(), Utc::now(), |_| true)
+            .is_some()
+    }
+
+    #[test]
+    fn lookup_matches_covering_prefixes() {
+        let trie = trie(&["192.0.2.0/24", "198.51.100.7/32", "2001:db8::/32"]);
+        assert!(allows(&trie, "192.0.2.1"));
+        assert!(allows(&trie, "192.0.2.255"));
+        assert!(!allows(&trie, "192.0.3.1"));
+        assert!(allows(&trie, "198.51.100.7"));
+        assert!(!allows(&trie, "198.51.100.8"));
+        assert!(allows(&trie, "2001:db8:ffff::1"));
+        assert!(!allows(&trie, "2001:db9::1"));
+    }
+
+    #[test]
+    fn ipv4_mapped_addresses_match_ipv4_entries() {
+        let trie = trie(&["192.0.2.0/24"]);
+        assert!(allows(&trie, "::ffff:192.0.2.9"));
+        assert!(!allows(&trie, "::192.0.2.9"));
+    }
+
+    #[test]
+    fn zero_length_prefixes_match_their_family() {
+        let v4 = trie(&["0.0.0.0/0"]);
+        assert!(allows(&v4, "203.0.113.1"));
+        assert!(!allows(&v4, "2001:db8::1"));
+        let v6 = trie(&["::/0"]);
+        assert!(allows(&v6, "2001:db8::1"));
+    }
+
+    #[test]
+    fn removal_keeps_other_prefixes() {
+        let mut trie = trie(&["192.0.2.0/24", "192.0.2.128/25", "192.0.2.1/32"]);
+        trie.remove("192.0.2.0/24".parse().unwrap());
+        assert!(allows(&trie, "192.0.2.1"));
+        assert!(allows(&trie, "192.0.2.200"));
+        assert!(!allows(&trie, "192.0.2.2"));
+
+        trie.remove("192.0.2.128/25".parse().unwrap());
+        trie.remove("192.0.2.1/32".parse().unwrap());
+        assert!(!allows(&trie, "192.0.2.1"));
+        assert!(!allows(&trie, "192.0.2.200"));
+    }
+
+    #[test]
+    fn lookup_returns_the_most_specific_entry() {
+        let (wide, narrow) = (Uuid::new_v4(), Uuid::new_v4());
+        let mut trie = PrefixTrie::default();
+        trie.insert(AllowEntry {
+            key_id: Some(wide),
+            ..entry("192.0.2.0/24")
+        });
+        trie.insert(AllowEntry {
+            key_id: Some(narrow),
+            ..entry("192.0.2.0/28")
+        });
+        let lookup = |ip: &str| {
+            trie.lookup(ip.parse().unwrap(), Utc::now(), |_| true)
+                .and_then(|entry| entry.key_id)
+        };
+        assert_eq!(lookup("192.0.2.1"), Some(narrow));
+        assert_eq!(lookup("192.0.2.100"), Some(wide));
+    }
+
+    #[test]
+    fn removing_an_absent_prefix_is_a_no_op() {
+        let mut trie = trie(&["192.0.2.0/24"]);
+        trie.remove("192.0.2.0/25".parse().unwrap());
+        trie.remove("10.0.0.0/8".parse().unwrap());
+        assert!(allows(&trie, "192.0.2.1"));
+    }
+
+    #[test]
+    fn expired_and_unpermitted_entries_are_skipped() {
+        let mut trie = trie(&["192.0.2.0/24"]);
+        trie.insert(AllowEntry {
+            expires_at: Some(Utc::now() - chrono::Duration::seconds(1)),
+            ..entry("192.0.2.1/32")
+        });
+        // The expired /32 falls through to the covering /24
+        assert!(allows(&trie, "192.0.2.1"));
+
+        let owner = Uuid::new_v4();
+        trie.insert(AllowEntry {
+            key_id: Some(owner),
+            ..entry("198.51.100.0/24")
+        });
+        let ip = "198.51.100.1".parse().unwrap();
+        let found = trie.lookup(ip, Utc::now(), |e| e.key_id == Some(owner));
+        assert_eq!(found.unwrap().key_id, Some(owner));
+        assert!(trie
+            .lookup(ip, Utc::now(), |e| e.key_id.is_none())
+            .is_none());
+
+        trie.insert(AllowEntry {
+            expires_at: Some(Utc::now() - chrono::Duration::seconds(1)),
+            ..entry("203.0.113.0/24")
+        });
+        assert!(!allows(&trie, "203.0.113.1"));
+    }
+}
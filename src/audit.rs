@@ -0,0 +1,455 @@
+use crate::config::AuditConfig;
+use crate::metrics::metrics;
+use crate::storage::StorageBackend;
+use anyhow::{Context, Result};
+use chrono::{DateTime, Utc};
+use ipnet::IpNet;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::io::AsyncWriteExt;
+use tokio::sync::mpsc::error::TrySendError;
+use tokio::sync::{mpsc, oneshot};
+use tracing::error;
+use uuid::Uuid;
+
+/// Events queued for the writer; further events are dropped until it catches up
+const QUEUE_CAPACITY: usize = 4096;
+/// Events the writer takes off the queue at a time
+const WRITE_BATCH: usize = 256;
+/// Identical sampled denials are recorded once per window, with a count
+const SAMPLE_WINDOW: Duration = Duration::from_secs(60);
+/// Distinct sampled denials tracked at once; beyond this new ones are dropped
+const MAX_SAMPLES: usize = 4096;
+
+/// What an audit event records
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AuditAction {
+    KeyRegistered,
+    KeyRevoked,
+    KeyExpired,
+    CidrAllowed,
+    HostRegistered,
+    HostRenewed,
+    HostDeleted,
+    ProxyConnection,
+    AdminAccess,
+}
+
+impl AuditAction {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            AuditAction::KeyRegistered => "key_registered",
+            AuditAction::KeyRevoked => "key_revoked",
+            AuditAction::KeyExpired => "key_expired",
+            AuditAction::CidrAllowed => "cidr_allowed",
+            AuditAction::HostRegistered => "host_registered",
+            AuditAction::HostRenewed => "host_renewed",
+            AuditAction::HostDeleted => "host_deleted",
+            AuditAction::ProxyConnection => "proxy_connection",
+            AuditAction::AdminAccess => "admin_access",
+        }
+    }
+}
+
+impl std::str::FromStr for AuditAction {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        serde_json::from_value(serde_json::Value::String(s.to_string()))
+            .with_context(|| format!("unknown audit action {:?}", s))
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Decision {
+    Allow,
+    Deny,
+}
+
+impl Decision {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Decision::Allow => "allow",
+            Decision::Deny => "deny",
+        }
+    }
+}
+
+impl std::str::FromStr for Decision {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "allow" => Ok(Decision::Allow),
+            "deny" => Ok(Decision::Deny),
+            _ => anyhow::bail!("unknown audit decision {:?}", s),
+        }
+    }
+}
+
+/// A security-relevant event: who did what, to which key, host or route,
+/// and whether it was allowed.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AuditEvent {
+    pub timestamp: DateTime<Utc>,
+    pub action: AuditAction,
+    /// What performed the action, e.g. `api`, `socket`, `cli`, `proxy` or
+    /// `admin:<credential name>`
+    pub actor: String,
+    pub key_id: Option<Uuid>,
+    /// The client address the event concerns
+    pub ip: Option<IpAddr>,
+    /// The network enrolled or allowed
+    pub network: Option<IpNet>,
+    pub route: Option<String>,
+    pub decision: Decision,
+    pub reason: Option<String>,
+    /// Identical events folded into this one by sampling, and not recorded
+    /// on their own
+    #[serde(default)]
+    pub repeats: u64,
+}
+
+impl AuditEvent {
+    pub fn new(action: AuditAction, actor: &str, decision: Decision) -> Self {
+        Self {
+            timestamp: Utc::now(),
+            action,
+            actor: actor.to_string(),
+            key_id: None,
+            ip: None,
+            network: None,
+            route: None,
+            decision,
+            reason: None,
+            repeats: 0,
+        }
+    }
+
+    pub fn with_key(mut self, key_id: Option<Uuid>) -> Self {
+        self.key_id = key_id;
+        self
+    }
+
+    pub fn with_ip(mut self, ip: Option<IpAddr>) -> Self {
+        self.ip = ip.map(|ip| ip.to_canonical());
+        self
+    }
+
+    pub fn with_network(mut self, network: Option<IpNet>) -> Self {
+        self.network = network;
+        self
+    }
+
+    pub fn with_route(mut self, route: &str) -> Self {
+        self.route = Some(route.to_string());
+        self
+    }
+
+    pub fn with_reason(mut self, reason: impl Into<String>) -> Self {
+        self.reason = Some(reason.into());
+        self
+    }
+
+    /// Deny the event, giving the error as the reason, if the change it
+    /// records failed.
+    pub fn with_outcome<T>(self, result: &Result<T>) -> Self {
+        match result {
+            Ok(_) => self,
+            Err(e) => Self {
+                decision: Decision::Deny,
+                ..self
+            }
+            .with_reason(e.to_string()),
+        }
+    }
+
+    /// As `with_outcome`, also denying the event as `not_found` when the
+    /// change found nothing to apply to.
+    pub fn with_found<T>(self, result: &Result<Option<T>>) -> Self {
+        match result {
+            Ok(None) => Self {
+                decision: Decision::Deny,
+                ..self
+            }
+            .with_reason("not_found"),
+            _ => self.with_outcome(result),
+        }
+    }
+}
+
+impl std::fmt::Display for AuditEvent {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        fn or_dash<T: ToString>(value: &Option<T>) -> String {
+            value.as_ref().map_or_else(|| "-".to_string(), T::to_string)
+        }
+        write!(
+            f,
+            "{} {} {} actor={} key={} ip={} network={} route={} reason={} repeats={}",
+            self.timestamp
+                .to_rfc3339_opts(chrono::SecondsFormat::Millis, true),
+            self.action.as_str(),
+            self.decision.as_str(),
+            self.actor,
+            or_dash(&self.key_id),
+            or_dash(&self.ip),
+            or_dash(&self.network),
+            or_dash(&self.route),
+            or_dash(&self.reason),
+            self.repeats,
+        )
+    }
+}
+
+/// Which events to return from a query
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct AuditFilter {
+    pub since: Option<DateTime<Utc>>,
+    pub key_id: Option<Uuid>,
+    pub ip: Option<IpAddr>,
+    /// Return only the most recent events
+    pub limit: Option<u32>,
+}
+
+impl AuditFilter {
+    pub fn matches(&self, event: &AuditEvent) -> bool {
+        self.since.is_none_or(|since| event.timestamp >= since)
+            && self
+                .key_id
+                .is_none_or(|key_id| event.key_id == Some(key_id))
+            && self.ip.is_none_or(|ip| event.ip == Some(ip.to_canonical()))
+    }
+}
+
+enum Message {
+    Event(AuditEvent),
+    Close(oneshot::Sender<()>),
+}
+
+/// Records audit events in the order they happen. Events are handed to a
+/// background writer, which appends them to storage and/or the audit file.
+/// Recording never waits: if the writer falls behind and its queue is full,
+/// events are dropped and counted in `audit_events_dropped_total`.
+pub struct Auditor {
+    queue: mpsc::Sender<Message>,
+    samples: Arc<Mutex<HashMap<SampleKey, Sample>>>,
+}
+
+/// What makes two sampled events identical
+#[derive(Debug, PartialEq, Eq, Hash)]
+struct SampleKey {
+    action: AuditAction,
+    actor: String,
+    decision: Decision,
+    ip: Option<IpAddr>,
+    route: Option<String>,
+    reason: Option<String>,
+}
+
+struct Sample {
+    started: Instant,
+    /// The most recent event suppressed in this window, with their count
+    latest: Option<AuditEvent>,
+}
+
+impl Auditor {
+    /// Open the configured audit file and start the writer.
+    pub async fn start(
+        config: &AuditConfig,
+        storage: Arc<dyn StorageBackend>,
+    ) -> Result<Arc<Self>> {
+        let file = match &config.file {
+            Some(path) => Some(
+                tokio::fs::OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(path)
+                    .await
+                    .with_context(|| format!("failed to open audit file {}", path))?,
+            ),
+            None => None,
+        };
+        let storage = config.storage.then_some(storage);
+        let (queue, events) = mpsc::channel(QUEUE_CAPACITY);
+        tokio::spawn(write_events(events, storage, file));
+        let auditor = Arc::new(Self {
+            queue,
+            samples: Arc::default(),
+        });
+
+        // Record what each sampling window suppressed once it closes
+        let this = Arc::downgrade(&auditor);
+        tokio::spawn(async move {
+            let mut ticker = tokio::time::interval(SAMPLE_WINDOW / 4);
+            loop {
+                ticker.tick().await;
+                match this.upgrade() {
+                    Some(auditor) if !auditor.queue.is_closed() => auditor.flush_samples(false),
+                    _ => return,
+                }
+            }
+        });
+        Ok(auditor)
+    }
+
+    pub fn record(&self, event: AuditEvent) {
+        match self.queue.try_send(Message::Event(event)) {
+            Ok(()) => {}
+            Err(TrySendError::Full(Message::Event(event))) => metrics()
+                .audit_dropped
+                .with_label_values(&[event.action.as_str()])
+                .inc(),
+            Err(_) => error!("audit writer has stopped, event not recorded"),
+        }
+    }
+
+    /// Record a denial that an unauthenticated peer can trigger at will.
+    /// The first of a run of identical events (same action, actor, decision,
+    /// address, route and reason) is recorded straight away; the rest within
+    /// `SAMPLE_WINDOW` are folded into one event, recorded with their count
+    /// in `repeats` when the window closes.
+    pub fn record_sampled(&self, event: AuditEvent) {
+        let key = SampleKey {
+            action: event.action,
+            actor: event.actor.clone(),
+            decision: event.decision,
+            ip: event.ip,
+            route: event.route.clone(),
+            reason: event.reason.clone(),
+        };
+        {
+            let mut samples = self.samples.lock().unwrap_or_else(|e| e.into_inner());
+            if let Some(sample) = samples.get_mut(&key) {
+                let repeats = sample.latest.as_ref().map_or(0, |latest| latest.repeats);
+                sample.latest = Some(AuditEvent {
+                    repeats: repeats + 1,
+                    ..event
+                });
+                metrics()
+                    .audit_suppressed
+                    .with_label_values(&[key.action.as_str()])
+                    .inc();
+                return;
+            }
+            if samples.len() >= MAX_SAMPLES {
+                metrics()
+                    .audit_dropped
+                    .with_label_values(&[key.action.as_str()])
+                    .inc();
+                return;
+            }
+            samples.insert(
+                key,
+                Sample {
+                    started: Instant::now(),
+                    latest: None,
+                },
+            );
+        }
+        self.record(event);
+    }
+
+    /// Record the events suppressed in closed sampling windows, or in every
+    /// window when `all` is set.
+    fn flush_samples(&self, all: bool) {
+        let mut closed = Vec::new();
+        self.samples
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .retain(|_, sample| {
+                if all || sample.started.elapsed() >= SAMPLE_WINDOW {
+                    closed.extend(sample.latest.take());
+                    false
+                } else {
+                    true
+                }
+            });
+        for event in closed {
+            self.record(event);
+        }
+    }
+
+    /// Wait for every event recorded so far to be written, then stop the
+    /// writer. Events recorded afterwards are lost.
+    pub async fn close(&self) {
+        self.flush_samples(true);
+        let (done, written) = oneshot::channel();
+        if self.queue.send(Message::Close(done)).await.is_ok() {
+            let _ = written.await;
+        }
+    }
+}
+
+async fn write_events(
+    mut events: mpsc::Receiver<Message>,
+    storage: Option<Arc<dyn StorageBackend>>,
+    mut file: Option<tokio::fs::File>,
+) {
+    let mut batch = Vec::with_capacity(WRITE_BATCH);
+    while events.recv_many(&mut batch, WRITE_BATCH).await > 0 {
+        for message in batch.drain(..) {
+            let event = match message {
+                Message::Event(event) => event,
+                Message::Close(done) => {
+                    flush_file(&mut file).await;
+                    let _ = done.send(());
+                    return;
+                }
+            };
+            if let Some(storage) = &storage
+                && let Err(e) = storage.record_audit_event(&event).await
+            {
+                error!("failed to store audit event: {}", e);
+            }
+            if let Some(file) = &mut file
+                && let Err(e) = append_line(file, &event).await
+            {
+                error!("failed to write audit event to file: {}", e);
+            }
+        }
+        flush_file(&mut file).await;
+    }
+}
+
+async fn flush_file(file: &mut Option<tokio::fs::File>) {
+    if let Some(file) = file
+        && let Err(e) = file.flush().await
+    {
+        error!("failed to flush audit file: {}", e);
+    }
+}
+
+async fn append_line(file: &mut tokio::fs::File, event: &AuditEvent) -> Result<()> {
+    let mut line = serde_json::to_vec(event)?;
+    line.push(b'\n');
+    file.write_all(&line).await?;
+    Ok(())
+}
+
+/// Read the events in a JSON lines audit file matching `filter`, oldest
+/// first.
+pub fn read_file(path: &str, filter: &AuditFilter) -> Result<Vec<AuditEvent>> {
+    let contents = std::fs::read_to_string(path)
+        .with_context(|| format!("failed to read audit file {}", path))?;
+    let mut events = Vec::new();
+    for (number, line) in contents.lines().enumerate() {
+        if line.trim().is_empty() {
+            continue;
+        }
+        let event: AuditEvent = serde_json::from_str(line)
+            .with_context(|| format!("{}:{}: invalid audit event", path, number + 1))?;
+        if filter.matches(&event) {
+            events.push(event);
+        }
+    }
+    if let Some(limit) = filter.limit {
+        let skip = events.len().saturating_sub(limit as usize);
+        events.drain(..skip);
+    }
+    Ok(events)
+}
@@ -1,8 +1,15 @@
 use anyhow::Result;
 use base64::{engine::general_purpose, Engine as _};
+use hmac::{Hmac, Mac};
 use rand::rngs::OsRng;
+use sha2::Sha256;
 use x25519_dalek::{PublicKey, StaticSecret};
 
+type HmacSha256 = Hmac<Sha256>;
+
+/// Domain separation prefix mixed into every registration challenge MAC.
+const CHALLENGE_CONTEXT: &[u8] = b"shade-register-v1";
+
 pub fn generate_keys() -> Result<(String, String)> {
     let secret = StaticSecret::random_from_rng(OsRng);
     let priv_b64 = general_purpose::STANDARD.encode(secret.to_bytes());
@@ -12,9 +19,105 @@ pub fn generate_keys() -> Result<(String, String)> {
 }
 
 pub fn generate_public_from_private(priv_b64: &str) -> Result<String> {
-    let priv_bytes = base64::engine::general_purpose::STANDARD.decode(priv_b64.trim())?;
-    let secret = StaticSecret::from(<[u8; 32]>::try_from(priv_bytes.as_slice())?);
+    let secret = decode_private_key(priv_b64)?;
     let public = PublicKey::from(&secret);
     let pub_b64 = general_purpose::STANDARD.encode(public.as_bytes());
     Ok(pub_b64)
 }
+
+pub fn decode_private_key(priv_b64: &str) -> Result<StaticSecret> {
+    let priv_bytes = general_purpose::STANDARD.decode(priv_b64.trim())?;
+    Ok(StaticSecret::from(<[u8; 32]>::try_from(
+        priv_bytes.as_slice(),
+    )?))
+}
+
+pub fn decode_public_key(pub_b64: &str) -> Result<PublicKey> {
+    let pub_bytes = general_purpose::STANDARD.decode(pub_b64.trim())?;
+    Ok(PublicKey::from(<[u8; 32]>::try_from(pub_bytes.as_slice())?))
+}
+
+/// Compute the proof-of-possession MAC for a registration challenge.
+///
+/// Both sides derive the same x25519 shared secret (our secret, their public
+/// key) and use it to key an HMAC-SHA256 over the challenge nonce.
+pub fn challenge_mac(secret: &StaticSecret, peer: &PublicKey, nonce: &[u8]) -> Result<Vec<u8>> {
+    let mac = challenge_hmac(secret, peer, nonce)?;
+    Ok(mac.finalize().into_bytes().to_vec())
+}
+
+/// Verify a challenge MAC in constant time.
+pub fn verify_challenge_mac(
+    secret: &StaticSecret,
+    peer: &PublicKey,
+    nonce: &[u8],
+    mac: &[u8],
+) -> Result<bool> {
+    let expected = challenge_hmac(secret, peer, nonce)?;
+    Ok(expected.verify_slice(mac).is_ok())
+}
+
+fn challenge_hmac(secret: &StaticSecret, peer: &PublicKey, nonce: &[u8]) -> Result<HmacSha256> {
+    let shared = secret.diffie_hellman(peer);
+    if !shared.was_contributory() {
+        anyhow::bail!("non-contributory key exchange");
+    }
+    let mut mac = HmacSha256::new_from_slice(shared.as_bytes())?;
+    mac.update(CHALLENGE_CONTEXT);
+    mac.update(nonce);
+    Ok(mac)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn keypair() -> (StaticSecret, PublicKey) {
+        let secret = StaticSecret::random_from_rng(OsRng);
+        let public = PublicKey::from(&secret);
+        (secret, public)
+    }
+
+    #[test]
+    fn both_sides_derive_the_same_mac() {
+        let (client, client_pub) = keypair();
+        let (server, server_pub) = keypair();
+        let nonce = b"nonce";
+        let mac = challenge_mac(&client, &server_pub, nonce).unwrap();
+        assert_eq!(mac.len(), 32);
+        assert!(verify_challenge_mac(&server, &client_pub, nonce, &mac).unwrap());
+    }
+
+    #[test]
+    fn mac_fails_for_another_nonce_key_or_mac() {
+        let (client, client_pub) = keypair();
+        let (server, server_pub) = keypair();
+        let (_, other_pub) = keypair();
+        let mac = challenge_mac(&client, &server_pub, b"nonce").unwrap();
+
+        assert!(!verify_challenge_mac(&server, &client_pub, b"other", &mac).unwrap());
+        assert!(!verify_challenge_mac(&server, &other_pub, b"nonce", &mac).unwrap());
+        let mut tampered = mac.clone();
+        tampered[0] ^= 1;
+        assert!(!verify_challenge_mac(&server, &client_pub, b"nonce", &tampered).unwrap());
+        assert!(!verify_challenge_mac(&server, &client_pub, b"nonce", &mac[..16]).unwrap());
+    }
+
+    #[test]
+    fn low_order_keys_are_refused() {
+        let (server, _) = keypair();
+        let zero = PublicKey::from([0u8; 32]);
+        assert!(challenge_mac(&server, &zero, b"nonce").is_err());
+        assert!(verify_challenge_mac(&server, &zero, b"nonce", &[0; 32]).is_err());
+    }
+
+    #[test]
+    fn keys_round_trip_through_base64() {
+        let (private, public) = generate_keys().unwrap();
+        assert_eq!(generate_public_from_private(&private).unwrap(), public);
+        let decoded = decode_public_key(&public).unwrap();
+        let secret = decode_private_key(&private).unwrap();
+        assert_eq!(PublicKey::from(&secret), decoded);
+        assert!(decode_public_key("c2hvcnQ=").is_err());
+    }
+}
@@ -0,0 +1,263 @@
+use base64::{engine::general_purpose, Engine as _};
+use rand::rngs::OsRng;
+use rand::RngCore;
+use std::collections::HashMap;
+use std::fmt;
+use std::net::IpAddr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use uuid::Uuid;
+use x25519_dalek::{PublicKey, StaticSecret};
+
+/// How long an issued challenge may be answered before it is discarded.
+pub const CHALLENGE_TTL: Duration = Duration::from_secs(60);
+
+const NONCE_LEN: usize = 32;
+
+/// Outstanding challenges kept at once, across all clients
+const MAX_CHALLENGES: usize = 10_000;
+
+/// Outstanding challenges kept at once for one client IP
+const MAX_CHALLENGES_PER_IP: usize = 16;
+
+/// A single outstanding registration challenge.
+pub struct Challenge {
+    pub secret: StaticSecret,
+    pub public_key: PublicKey,
+    pub nonce: Vec<u8>,
+    issued_at: Instant,
+    ip: IpAddr,
+}
+
+impl Challenge {
+    pub fn public_key_b64(&self) -> String {
+        general_purpose::STANDARD.encode(self.public_key.as_bytes())
+    }
+
+    pub fn nonce_b64(&self) -> String {
+        general_purpose::STANDARD.encode(&self.nonce)
+    }
+}
+
+/// In-memory store of issued challenges. Each challenge is single use: it is
+/// removed as soon as a registration attempt references it. Expired
+/// challenges are removed by `sweep`, and count towards the limits on
+/// outstanding challenges until then.
+pub struct ChallengeStore {
+    ttl: Duration,
+    state: Mutex<State>,
+}
+
+#[derive(Default)]
+struct State {
+    challenges: HashMap<Uuid, Challenge>,
+    per_ip: HashMap<IpAddr, usize>,
+}
+
+impl State {
+    fn remove(&mut self, id: &Uuid) -> Option<Challenge> {
+        let challenge = self.challenges.remove(id)?;
+        if let Some(count) = self.per_ip.get_mut(&challenge.ip) {
+            *count -= 1;
+            if *count == 0 {
+                self.per_ip.remove(&challenge.ip);
+            }
+        }
+        Some(challenge)
+    }
+}
+
+impl fmt::Debug for ChallengeStore {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ChallengeStore")
+            .field("ttl", &self.ttl)
+            .finish_non_exhaustive()
+    }
+}
+
+impl ChallengeStore {
+    pub fn new(ttl: Duration) -> Self {
+        Self {
+            ttl,
+            state: Mutex::new(State::default()),
+        }
+    }
+
+    pub fn ttl(&self) -> Duration {
+        self.ttl
+    }
+
+    /// Issue a fresh server-ephemeral key and nonce to `ip`, unless it or
+    /// the store as a whole already has as many outstanding challenges as
+    /// allowed.
+    pub fn issue(&self, ip: IpAddr) -> Option<(Uuid, String, String)> {
+        let ip = ip.to_canonical();
+        let mut state = self.state.lock().unwrap();
+        if state.challenges.len() >= MAX_CHALLENGES
+            || state.per_ip.get(&ip).copied().unwrap_or(0) >= MAX_CHALLENGES_PER_IP
+        {
+            return None;
+        }
+
+        let secret = StaticSecret::random_from_rng(OsRng);
+        let public_key = PublicKey::from(&secret);
+        let mut nonce = vec![0u8; NONCE_LEN];
+        OsRng.fill_bytes(&mut nonce);
+
+        let challenge = Challenge {
+            secret,
+            public_key,
+            nonce,
+            issued_at: Instant::now(),
+            ip,
+        };
+        let id = Uuid::new_v4();
+        let response = (id, challenge.public_key_b64(), challenge.nonce_b64());
+
+        state.challenges.insert(id, challenge);
+        *state.per_ip.entry(ip).or_insert(0) += 1;
+
+        Some(response)
+    }
+
+    /// Remove a challenge, returning it if it has not expired and was
+    /// issued to `ip`. A challenge answered from another address is used up
+    /// all the same, so a relayed response cannot enroll that address.
+    pub fn take(&self, id: &Uuid, ip: IpAddr) -> Option<Challenge> {
+        let mut state = self.state.lock().unwrap();
+        state
+            .remove(id)
+            .filter(|c| c.issued_at.elapsed() < self.ttl && c.ip == ip.to_canonical())
+    }
+
+    /// Remove expired challenges.
+    pub fn sweep(&self) {
+        let mut state = self.state.lock().unwrap();
+        let expired: Vec<Uuid> = state
+            .challenges
+            .iter()
+            .filter(|(_, c)| c.issued_at.elapsed() >= self.ttl)
+            .map(|(id, _)| *id)
+            .collect();
+        for id in &expired {
+            state.remove(id);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    fn issue(store: &ChallengeStore, ip: IpAddr) -> Uuid {
+        store.issue(ip).expect("challenge was refused").0
+    }
+
+    /// Fill the store with `n` outstanding challenges for `ip`, without
+    /// generating a key for each.
+    fn fill(store: &ChallengeStore, ip: IpAddr, n: usize) {
+        let secret = StaticSecret::from([7u8; 32]);
+        let public_key = PublicKey::from(&secret);
+        let mut state = store.state.lock().unwrap();
+        for _ in 0..n {
+            state.challenges.insert(
+                Uuid::new_v4(),
+                Challenge {
+                    secret: secret.clone(),
+                    public_key,
+                    nonce: vec![0; NONCE_LEN],
+                    issued_at: Instant::now(),
+                    ip,
+                },
+            );
+            *state.per_ip.entry(ip).or_insert(0) += 1;
+        }
+    }
+
+    #[test]
+    fn challenges_are_single_use() {
+        let store = ChallengeStore::new(CHALLENGE_TTL);
+        let id = issue(&store, ip("192.0.2.1"));
+        let challenge = store.take(&id, ip("192.0.2.1")).unwrap();
+        assert_eq!(challenge.nonce.len(), NONCE_LEN);
+        assert!(store.take(&id, ip("192.0.2.1")).is_none());
+        assert!(store.take(&Uuid::new_v4(), ip("192.0.2.1")).is_none());
+    }
+
+    #[test]
+    fn challenges_expire() {
+        let store = ChallengeStore::new(Duration::ZERO);
+        let id = issue(&store, ip("192.0.2.1"));
+        assert!(store.take(&id, ip("192.0.2.1")).is_none());
+    }
+
+    #[test]
+    fn challenges_are_bound_to_the_issuing_address() {
+        let store = ChallengeStore::new(CHALLENGE_TTL);
+        let id = issue(&store, ip("192.0.2.1"));
+        assert!(store.take(&id, ip("192.0.2.2")).is_none());
+        // Used up by the attempt from the wrong address
+        assert!(store.take(&id, ip("192.0.2.1")).is_none());
+
+        let id = issue(&store, ip("192.0.2.1"));
+        assert!(store.take(&id, ip("::ffff:192.0.2.1")).is_some());
+    }
+
+    #[test]
+    fn outstanding_challenges_are_capped_per_ip() {
+        let store = ChallengeStore::new(CHALLENGE_TTL);
+        fill(&store, ip("192.0.2.1"), MAX_CHALLENGES_PER_IP - 1);
+        let id = issue(&store, ip("192.0.2.1"));
+        assert!(store.issue(ip("192.0.2.1")).is_none());
+        assert!(store.issue(ip("::ffff:192.0.2.1")).is_none());
+        assert!(store.issue(ip("192.0.2.2")).is_some());
+
+        // Answering one frees a slot
+        store.take(&id, ip("192.0.2.1")).unwrap();
+        assert!(store.issue(ip("192.0.2.1")).is_some());
+    }
+
+    #[test]
+    fn outstanding_challenges_are_capped_in_total() {
+        let store = ChallengeStore::new(CHALLENGE_TTL);
+        let per_ip = MAX_CHALLENGES_PER_IP - 1;
+        for n in 0..MAX_CHALLENGES / per_ip {
+            fill(&store, IpAddr::V4((n as u32).into()), per_ip);
+        }
+        let remaining = MAX_CHALLENGES % per_ip;
+        fill(&store, ip("198.51.100.1"), remaining);
+        assert!(store.issue(ip("203.0.113.1")).is_none());
+    }
+
+    #[test]
+    fn sweep_removes_expired_challenges() {
+        let store = ChallengeStore::new(Duration::from_millis(50));
+        fill(&store, ip("192.0.2.1"), MAX_CHALLENGES_PER_IP);
+        assert!(store.issue(ip("192.0.2.1")).is_none());
+
+        std::thread::sleep(Duration::from_millis(60));
+        let fresh = issue(&store, ip("192.0.2.2"));
+        // Expired challenges count until swept
+        assert!(store.issue(ip("192.0.2.1")).is_none());
+        store
+            .state
+            .lock()
+            .unwrap()
+            .challenges
+            .get_mut(&fresh)
+            .unwrap()
+            .issued_at = Instant::now();
+        store.sweep();
+
+        let state = store.state.lock().unwrap();
+        assert_eq!(state.challenges.len(), 1);
+        assert!(state.challenges.contains_key(&fresh));
+        assert!(!state.per_ip.contains_key(&ip("192.0.2.1")));
+        drop(state);
+        assert!(store.issue(ip("192.0.2.1")).is_some());
+    }
+}
@@ -1,6 +1,16 @@
+use crate::audit::{AuditAction, AuditEvent, Decision};
+use crate::logger::{get_subscriber, init_subscriber, shutdown_tracing, Output};
 use anyhow::Result;
+use base64::{engine::general_purpose, Engine as _};
 use clap::{Parser, Subcommand};
 use serde::Serialize;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::signal::unix::{signal, SignalKind};
+use tracing::{error, info, warn};
+
+/// The actor recorded for changes made directly against storage
+const ACTOR: &str = "cli";
 
 #[derive(Serialize)]
 struct KeyPair {
@@ -8,6 +18,12 @@ struct KeyPair {
     public: String,
 }
 
+#[derive(Serialize)]
+struct AdminToken {
+    token: String,
+    sha256: String,
+}
+
 #[derive(Parser)]
 #[command(name = "shade")]
 #[command(about = "Simple Host Attestation & Dynamic Enrollment")]
@@ -22,12 +38,36 @@ pub struct Cli {
 #[derive(Subcommand)]
 pub enum Commands {
     GenKeys,
+    /// Generate a bearer token for the admin API and the sha256 to configure
+    GenAdminToken,
     Server,
     RegisterKey {
+        #[arg(
+            long,
+            required_unless_present = "private_key",
+            conflicts_with = "private_key"
+        )]
+        public_key: Option<String>,
+        /// Derive the public key locally; the private key is never sent to the server
         #[arg(short, long)]
-        private_key: String,
+        private_key: Option<String>,
         #[arg(long)]
         expires_at: Option<String>,
+        /// Enroll IPv4 hosts registering with this key as a network of this prefix length
+        #[arg(long)]
+        ipv4_prefix_len: Option<u8>,
+        /// Enroll IPv6 hosts registering with this key as a network of this prefix length
+        #[arg(long)]
+        ipv6_prefix_len: Option<u8>,
+        /// Add the key to a group, granting access to routes allowing that group (repeatable)
+        #[arg(long = "group")]
+        groups: Vec<String>,
+        /// Pin a client certificate (PEM) to the key for routes with `client_auth: key`
+        #[arg(long, conflicts_with = "spki_sha256")]
+        client_cert: Option<String>,
+        /// Pin a client certificate by the base64 SHA-256 of its public key info
+        #[arg(long)]
+        spki_sha256: Option<String>,
     },
     RevokeKey {
         #[arg(short, long)]
@@ -38,15 +78,80 @@ pub enum Commands {
     RegisterHost {
         #[arg(long)]
         url: String,
-        #[arg(long)]
-        public_key: String,
+        #[arg(short, long)]
+        private_key: String,
+        /// Stay running and renew the host lease at this interval, e.g. "30m"
+        #[arg(long, value_parser = humantime::parse_duration)]
+        renew_every: Option<std::time::Duration>,
     },
     ListHosts,
+    AllowCidr {
+        #[arg(long)]
+        cidr: ipnet::IpNet,
+        /// Tie the entry to a key so revoking the key removes it
+        #[arg(long)]
+        key_id: Option<String>,
+        #[arg(long)]
+        expires_at: Option<String>,
+    },
+    /// Ask the running server to re-read its configuration file
+    Reload,
+    /// Inspect the audit log
+    Audit {
+        #[command(subcommand)]
+        command: AuditCommand,
+    },
+}
+
+#[derive(Subcommand)]
+pub enum AuditCommand {
+    /// Show the most recent audit events
+    Tail {
+        #[arg(short = 'n', long, default_value_t = 20)]
+        lines: u32,
+        /// Print events as JSON lines
+        #[arg(long)]
+        json: bool,
+    },
+    /// Search audit events
+    Query {
+        /// Only events at or after this time, as RFC 3339 or a duration ago, e.g. "1h"
+        #[arg(long)]
+        since: Option<String>,
+        /// Only events for this key ID
+        #[arg(long)]
+        key: Option<String>,
+        /// Only events for this client IP
+        #[arg(long)]
+        ip: Option<std::net::IpAddr>,
+        /// Show at most this many of the most recent matching events
+        #[arg(long)]
+        limit: Option<u32>,
+        /// Print events as JSON lines
+        #[arg(long)]
+        json: bool,
+    },
 }
 
 pub fn run_cli() -> Result<()> {
     let cli = Cli::parse();
 
+    // The server logs where configured; other commands log to stderr so
+    // their output can be piped. A config that fails to load is reported by
+    // the command itself.
+    let mut logging = crate::config::Config::load(&cli.config)
+        .map(|config| config.logging)
+        .unwrap_or_default();
+    let output = match cli.command {
+        Some(Commands::Server) => Output::Stdout,
+        _ => {
+            logging.file = None;
+            logging.otlp = None;
+            Output::Stderr
+        }
+    };
+    init_subscriber(get_subscriber("shade".into(), &logging, output)?);
+
     match cli.command {
         Some(Commands::GenKeys) => {
             let (priv_b64, pub_b64) = crate::cert::generate_keys()?;
@@ -59,34 +164,119 @@ pub fn run_cli() -> Result<()> {
             let json_output = serde_json::to_string_pretty(&keys)?;
             println!("{}", json_output);
         }
+        Some(Commands::GenAdminToken) => {
+            let (token, sha256) = crate::admin::generate_token();
+            println!(
+                "{}",
+                serde_json::to_string_pretty(&AdminToken { token, sha256 })?
+            );
+        }
         Some(Commands::Server) => {
             tokio::runtime::Runtime::new()?.block_on(async {
-                let config_for_proxy = cli.config.clone();
-                let config_for_server = cli.config.clone();
+                let config = crate::config::Config::load(&cli.config)?;
+                config.validate()?;
+
+                // The proxy and the HTTP server share one storage instance so
+                // the proxy's allowlist sees every registration as it happens
+                let storage = crate::server::create_storage(&config).await?;
+
+                let shutdown = tokio_util::sync::CancellationToken::new();
+                let signalled = shutdown.clone();
+                tokio::spawn(async move {
+                    match shutdown_signal().await {
+                        Ok(signal) => {
+                            info!(signal, "shutting down");
+                            signalled.cancel();
+                        }
+                        Err(e) => error!("failed to listen for shutdown signals: {}", e),
+                    }
+                });
+
+                // Bind every route before serving anything, so a bad route
+                // fails startup as a whole
+                let audit = crate::audit::Auditor::start(&config.audit, storage.clone()).await?;
+                let proxy = crate::proxy::Proxy::start(
+                    &config,
+                    storage.clone(),
+                    audit.clone(),
+                    shutdown.clone(),
+                )
+                .await?;
+                let reloader =
+                    crate::reload::ConfigReloader::new(&cli.config, config.clone(), proxy.clone());
 
-                let proxy_handle = tokio::spawn(async move {
-                    if let Err(e) = crate::proxy::run_proxy(&config_for_proxy).await {
-                        eprintln!("Proxy error: {}", e);
+                let hangups = reloader.clone();
+                tokio::spawn(async move {
+                    let mut hangup = match signal(SignalKind::hangup()) {
+                        Ok(hangup) => hangup,
+                        Err(e) => {
+                            error!("failed to listen for SIGHUP: {}", e);
+                            return;
+                        }
+                    };
+                    while hangup.recv().await.is_some() {
+                        match hangups.reload().await {
+                            Ok(report) => info!("configuration reloaded: {}", report),
+                            Err(e) => error!("configuration reload failed: {:#}", e),
+                        }
                     }
                 });
 
-                if let Err(e) = crate::server::run_server(&config_for_server).await {
-                    eprintln!("Server error: {}", e);
+                let shutdown_timeout = Duration::from_secs(config.server.shutdown_timeout_secs);
+                if let Err(e) = crate::server::run_server(
+                    config,
+                    storage.clone(),
+                    audit.clone(),
+                    shutdown.clone(),
+                    reloader,
+                )
+                .await
+                {
+                    error!("server error: {}", e);
                 }
 
-                proxy_handle.abort();
+                // Stop the proxy too if the HTTP server exited on its own,
+                // then let it drain and the audit log catch up before
+                // closing the database
+                shutdown.cancel();
+                proxy.drain(shutdown_timeout).await;
+                audit.close().await;
+                storage.close().await;
 
                 Ok::<(), anyhow::Error>(())
             })?;
+            // Outside the runtime, as flushing blocks on the exporter
+            shutdown_tracing();
         }
         Some(Commands::RegisterKey {
+            public_key,
             private_key,
             expires_at,
+            ipv4_prefix_len,
+            ipv6_prefix_len,
+            groups,
+            client_cert,
+            spki_sha256,
         }) => {
+            let spki_sha256 = match client_cert {
+                Some(path) => Some(crate::tls::spki_sha256_from_file(&path)?),
+                None => spki_sha256,
+            };
+            let public_key = match (public_key, private_key) {
+                (Some(public_key), _) => public_key,
+                (None, Some(private_key)) => {
+                    crate::cert::generate_public_from_private(&private_key)?
+                }
+                (None, None) => anyhow::bail!("either --public-key or --private-key is required"),
+            };
             tokio::runtime::Runtime::new()?.block_on(register_key(
                 &cli.config,
-                private_key,
+                public_key,
                 expires_at,
+                ipv4_prefix_len,
+                ipv6_prefix_len,
+                groups,
+                spki_sha256,
             ))?;
         }
         Some(Commands::RevokeKey { id }) => {
@@ -99,24 +289,60 @@ pub fn run_cli() -> Result<()> {
             let config = crate::config::Config::load(&cli.config)?;
             config.validate()?;
         }
-        Some(Commands::RegisterHost { url, public_key }) => {
+        Some(Commands::RegisterHost {
+            url,
+            private_key,
+            renew_every,
+        }) => {
             let config = crate::config::Config::load(&cli.config)?;
             config.validate()?;
-            let client = reqwest::blocking::Client::new();
-            let res = client
-                .post(format!("{}/register", url))
-                .json(&serde_json::json!({ "public_key": public_key }))
-                .send()?;
-            if res.status().is_success() {
-                let body: serde_json::Value = res.json()?;
-                println!("Host registered successfully: {}", body);
-            } else {
-                println!("Failed to register host: {} {}", res.status(), res.text()?);
-            }
+            register_host(&url, &private_key, renew_every)?;
         }
         Some(Commands::ListHosts) => {
             tokio::runtime::Runtime::new()?.block_on(list_hosts(&cli.config))?;
         }
+        Some(Commands::AllowCidr {
+            cidr,
+            key_id,
+            expires_at,
+        }) => {
+            tokio::runtime::Runtime::new()?.block_on(allow_cidr(
+                &cli.config,
+                cidr,
+                key_id,
+                expires_at,
+            ))?;
+        }
+        Some(Commands::Reload) => {
+            tokio::runtime::Runtime::new()?.block_on(reload(&cli.config))?;
+        }
+        Some(Commands::Audit { command }) => {
+            let (filter, json) = match command {
+                AuditCommand::Tail { lines, json } => (
+                    crate::audit::AuditFilter {
+                        limit: Some(lines),
+                        ..Default::default()
+                    },
+                    json,
+                ),
+                AuditCommand::Query {
+                    since,
+                    key,
+                    ip,
+                    limit,
+                    json,
+                } => (
+                    crate::audit::AuditFilter {
+                        since: since.as_deref().map(parse_since).transpose()?,
+                        key_id: key.map(|id| uuid::Uuid::parse_str(&id)).transpose()?,
+                        ip,
+                        limit,
+                    },
+                    json,
+                ),
+            };
+            tokio::runtime::Runtime::new()?.block_on(audit_events(&cli.config, filter, json))?;
+        }
         None => {
             println!("No command provided. Use --help to see available commands.");
         }
@@ -125,37 +351,60 @@ pub fn run_cli() -> Result<()> {
     Ok(())
 }
 
+/// Wait for SIGTERM or SIGINT, returning the name of the signal received.
+async fn shutdown_signal() -> Result<&'static str> {
+    let mut terminate = signal(SignalKind::terminate())?;
+    let mut interrupt = signal(SignalKind::interrupt())?;
+    Ok(tokio::select! {
+        _ = terminate.recv() => "SIGTERM",
+        _ = interrupt.recv() => "SIGINT",
+    })
+}
+
 async fn register_key(
     config_path: &str,
-    private_key: String,
+    public_key: String,
     expires_at: Option<String>,
+    ipv4_prefix_len: Option<u8>,
+    ipv6_prefix_len: Option<u8>,
+    groups: Vec<String>,
+    spki_sha256: Option<String>,
 ) -> Result<()> {
     let config = crate::config::Config::load(config_path)?;
     config.validate()?;
 
-    let expires_at = match expires_at {
-        Some(date_str) => {
-            Some(chrono::DateTime::parse_from_rfc3339(&date_str)?.with_timezone(&chrono::Utc))
-        }
-        None => None,
-    };
+    let expires_at = parse_expires_at(expires_at)?;
     match config.storage.mode {
         crate::config::StorageMode::File => {
             let storage = create_storage(&config).await?;
-            let keypair = crate::storage::KeyPair::new(private_key, expires_at)?;
-            storage.register_key(keypair.clone()).await?;
-            println!("Key registered successfully with ID: {}", keypair.id);
+            let key = crate::storage::KeyRecord::new(public_key, expires_at)?
+                .with_prefix_lens(ipv4_prefix_len, ipv6_prefix_len)?
+                .with_groups(groups)?
+                .with_spki_sha256(spki_sha256)?;
+            let registered = storage.register_key(key.clone()).await;
+            let event = AuditEvent::new(AuditAction::KeyRegistered, ACTOR, Decision::Allow)
+                .with_key(Some(key.id))
+                .with_outcome(&registered);
+            record(&config, &storage, event).await?;
+            registered?;
+            println!("Key registered successfully with ID: {}", key.id);
         }
         crate::config::StorageMode::Socket => {
             let socket_path = config.storage.socket_path.as_ref().unwrap();
             let client = crate::socket::SocketClient::new(socket_path);
-            let keypair = crate::storage::KeyPair::new(private_key, expires_at)?;
             let response = client
-                .send_message(crate::socket::SocketMessage::Register(keypair.clone()))
+                .send_message(crate::socket::SocketMessage::Register {
+                    public_key,
+                    expires_at,
+                    ipv4_prefix_len,
+                    ipv6_prefix_len,
+                    groups,
+                    spki_sha256,
+                })
                 .await?;
             match response {
-                crate::socket::SocketResponse::KeyRegistered(kp) => {
-                    println!("Key registered successfully with ID: {}", kp.id);
+                crate::socket::SocketResponse::KeyRegistered(key) => {
+                    println!("Key registered successfully with ID: {}", key.id);
                 }
                 crate::socket::SocketResponse::Error(e) => {
                     anyhow::bail!("Server error: {}", e);
@@ -169,6 +418,19 @@ async fn register_key(
     Ok(())
 }
 
+fn parse_expires_at(expires_at: Option<String>) -> Result<Option<chrono::DateTime<chrono::Utc>>> {
+    let expires_at = match expires_at {
+        Some(date_str) => {
+            Some(chrono::DateTime::parse_from_rfc3339(&date_str)?.with_timezone(&chrono::Utc))
+        }
+        None => None,
+    };
+    if expires_at.is_some_and(|expires_at| expires_at <= chrono::Utc::now()) {
+        anyhow::bail!("expires_at must be in the future");
+    }
+    Ok(expires_at)
+}
+
 async fn revoke_key(config_path: &str, id: String) -> Result<()> {
     let config = crate::config::Config::load(config_path)?;
     config.validate()?;
@@ -177,8 +439,17 @@ async fn revoke_key(config_path: &str, id: String) -> Result<()> {
         crate::config::StorageMode::File => {
             let storage = create_storage(&config).await?;
             let uuid = uuid::Uuid::parse_str(&id)?;
-            storage.revoke_key(uuid).await?;
-            println!("Key with ID {} revoked successfully", id);
+            let revoked = storage.revoke_key(uuid).await;
+            let event = AuditEvent::new(AuditAction::KeyRevoked, ACTOR, Decision::Allow)
+                .with_key(Some(uuid));
+            record(&config, &storage, event.with_found(&revoked)).await?;
+            let Some(hosts_removed) = revoked? else {
+                anyhow::bail!("Key with ID {} not found", id);
+            };
+            println!(
+                "Key with ID {} revoked successfully, {} host(s) removed",
+                id, hosts_removed
+            );
         }
         crate::config::StorageMode::Socket => {
             let socket_path = config.storage.socket_path.as_ref().unwrap();
@@ -187,8 +458,11 @@ async fn revoke_key(config_path: &str, id: String) -> Result<()> {
                 .send_message(crate::socket::SocketMessage::Revoke { id: id.clone() })
                 .await?;
             match response {
-                crate::socket::SocketResponse::KeyRevoked => {
-                    println!("Key with ID {} revoked successfully", id);
+                crate::socket::SocketResponse::KeyRevoked { hosts_removed } => {
+                    println!(
+                        "Key with ID {} revoked successfully, {} host(s) removed",
+                        id, hosts_removed
+                    );
                 }
                 crate::socket::SocketResponse::Error(e) => {
                     anyhow::bail!("Server error: {}", e);
@@ -212,8 +486,13 @@ async fn list_keys(config_path: &str) -> Result<()> {
             let keys = storage.list_keys().await?;
             for key in keys {
                 println!(
-                    "ID: {}, Created At: {}, Expires At: {:?}",
-                    key.id, key.created_at, key.expires_at
+                    "ID: {}, Status: {}, Groups: {}, Client Cert: {}, Created At: {}, Expires At: {:?}",
+                    key.id,
+                    key.status(),
+                    key.groups.join(","),
+                    key.spki_sha256.as_deref().unwrap_or("-"),
+                    key.created_at,
+                    key.expires_at
                 );
             }
         }
@@ -227,8 +506,14 @@ async fn list_keys(config_path: &str) -> Result<()> {
                 crate::socket::SocketResponse::KeyList(keys) => {
                     for key in keys {
                         println!(
-                            "ID: {}, PubKey: {},  Created At: {}, Expires At: {:?}",
-                            key.id, key.public_key, key.created_at, key.expires_at
+                            "ID: {}, Status: {}, Groups: {}, Client Cert: {}, PubKey: {},  Created At: {}, Expires At: {:?}",
+                            key.id,
+                            key.status(),
+                            key.groups.join(","),
+                            key.spki_sha256.as_deref().unwrap_or("-"),
+                            key.public_key,
+                            key.created_at,
+                            key.expires_at
                         );
                     }
                 }
@@ -251,21 +536,281 @@ async fn list_hosts(config_path: &str) -> Result<()> {
 
     let storage = create_storage(&config).await?;
     let hosts = storage.list_hosts().await?;
-    println!("Debug: found {} hosts", hosts.len());
     for host in hosts {
+        let key_id = host
+            .key_id
+            .map(|id| id.to_string())
+            .unwrap_or_else(|| "-".to_string());
+        let expires_at = host
+            .expires_at
+            .map(|e| e.to_string())
+            .unwrap_or_else(|| "never".to_string());
         println!(
-            "IP Address: {}, Registered At: {}",
-            host.ip, host.created_at
+            "Network: {}, Key ID: {}, Registered At: {}, Lease Expires At: {}",
+            host.network, key_id, host.created_at, expires_at
+        );
+    }
+
+    Ok(())
+}
+
+/// Enroll this host by answering a proof-of-possession challenge for
+/// `private_key`. With `renew_every` set, keep running and renew the lease,
+/// re-registering if the server no longer holds one for this host.
+fn register_host(
+    url: &str,
+    private_key: &str,
+    renew_every: Option<std::time::Duration>,
+) -> Result<()> {
+    let client = reqwest::blocking::Client::new();
+
+    let Some(renew_every) = renew_every else {
+        let res = send_challenge_response(&client, url, "register", private_key)?;
+        if res.status().is_success() {
+            let body: serde_json::Value = res.json()?;
+            println!("Host registered successfully: {}", body);
+        } else {
+            println!("Failed to register host: {} {}", res.status(), res.text()?);
+        }
+        return Ok(());
+    };
+
+    // Errors from here on are logged and retried, so the agent keeps running
+    let mut registered = register_logged(&client, url, private_key);
+    loop {
+        std::thread::sleep(renew_every);
+
+        // Until a registration succeeds there is no lease to renew
+        if !registered {
+            registered = register_logged(&client, url, private_key);
+            continue;
+        }
+
+        let res = match send_challenge_response(&client, url, "renew", private_key) {
+            Ok(res) => res,
+            Err(e) => {
+                warn!("failed to renew lease: {:#}", e);
+                continue;
+            }
+        };
+        let status = res.status();
+        if status.is_success() {
+            println!("Host lease renewed successfully: {}", body_text(res));
+        } else if status == reqwest::StatusCode::NOT_FOUND {
+            // The lease lapsed or was pruned, so enroll again from scratch
+            registered = register_logged(&client, url, private_key);
+        } else {
+            warn!("failed to renew lease: {} {}", status, body_text(res));
+        }
+    }
+}
+
+/// Register the host, logging rather than returning any failure. Returns
+/// whether the host is now registered.
+fn register_logged(client: &reqwest::blocking::Client, url: &str, private_key: &str) -> bool {
+    match send_challenge_response(client, url, "register", private_key) {
+        Ok(res) if res.status().is_success() => {
+            println!("Host registered successfully: {}", body_text(res));
+            true
+        }
+        Ok(res) => {
+            error!(
+                "failed to register host: {} {}",
+                res.status(),
+                body_text(res)
+            );
+            false
+        }
+        Err(e) => {
+            error!("failed to register host: {:#}", e);
+            false
+        }
+    }
+}
+
+/// A response body for output, described instead when it cannot be read.
+fn body_text(res: reqwest::blocking::Response) -> String {
+    res.text()
+        .unwrap_or_else(|e| format!("<unreadable body: {}>", e))
+}
+
+/// Fetch a challenge, answer it with `private_key` and post the response to
+/// `endpoint`.
+fn send_challenge_response(
+    client: &reqwest::blocking::Client,
+    url: &str,
+    endpoint: &str,
+    private_key: &str,
+) -> Result<reqwest::blocking::Response> {
+    let secret = crate::cert::decode_private_key(private_key)?;
+    let public_key = crate::cert::generate_public_from_private(private_key)?;
+
+    let res = client.get(format!("{}/challenge", url)).send()?;
+    if !res.status().is_success() {
+        anyhow::bail!(
+            "Failed to fetch challenge: {} {}",
+            res.status(),
+            res.text()?
         );
     }
+    let challenge: crate::models::ChallengeResponse = res.json()?;
+
+    let server_key = crate::cert::decode_public_key(&challenge.server_public_key)?;
+    let nonce = general_purpose::STANDARD.decode(&challenge.nonce)?;
+    let mac = crate::cert::challenge_mac(&secret, &server_key, &nonce)?;
 
+    let request = crate::models::RegisterRequest {
+        public_key,
+        challenge_id: challenge.challenge_id,
+        mac: general_purpose::STANDARD.encode(mac),
+    };
+    let res = client
+        .post(format!("{}/{}", url, endpoint))
+        .json(&request)
+        .send()?;
+
+    Ok(res)
+}
+
+async fn allow_cidr(
+    config_path: &str,
+    cidr: ipnet::IpNet,
+    key_id: Option<String>,
+    expires_at: Option<String>,
+) -> Result<()> {
+    let config = crate::config::Config::load(config_path)?;
+    config.validate()?;
+
+    let network = crate::storage::normalize_network(cidr);
+    let key_id = key_id.map(|id| uuid::Uuid::parse_str(&id)).transpose()?;
+    let expires_at = parse_expires_at(expires_at)?;
+
+    match config.storage.mode {
+        crate::config::StorageMode::File => {
+            let storage = create_storage(&config).await?;
+            let allowed = storage.allow_network(network, key_id, expires_at).await;
+            let event = AuditEvent::new(AuditAction::CidrAllowed, ACTOR, Decision::Allow)
+                .with_key(key_id)
+                .with_network(Some(network))
+                .with_outcome(&allowed);
+            record(&config, &storage, event).await?;
+            allowed?;
+            println!("Network {} allowed successfully", network);
+        }
+        crate::config::StorageMode::Socket => {
+            let socket_path = config.storage.socket_path.as_ref().unwrap();
+            let client = crate::socket::SocketClient::new(socket_path);
+            let response = client
+                .send_message(crate::socket::SocketMessage::AllowCidr {
+                    network,
+                    key_id,
+                    expires_at,
+                })
+                .await?;
+            match response {
+                crate::socket::SocketResponse::CidrAllowed => {
+                    println!("Network {} allowed successfully", network);
+                }
+                crate::socket::SocketResponse::Error(e) => {
+                    anyhow::bail!("Server error: {}", e);
+                }
+                _ => {
+                    anyhow::bail!("Unexpected response from server");
+                }
+            }
+        }
+    }
+
+    Ok(())
+}
+
+async fn reload(config_path: &str) -> Result<()> {
+    let config = crate::config::Config::load(config_path)?;
+    config.validate()?;
+
+    match config.storage.mode {
+        crate::config::StorageMode::File => {
+            anyhow::bail!("reload needs socket mode; send SIGHUP to the server instead");
+        }
+        crate::config::StorageMode::Socket => {
+            let socket_path = config.storage.socket_path.as_ref().unwrap();
+            let client = crate::socket::SocketClient::new(socket_path);
+            let response = client
+                .send_message(crate::socket::SocketMessage::Reload)
+                .await?;
+            match response {
+                crate::socket::SocketResponse::Reloaded(report) => {
+                    println!("Configuration reloaded: {}", report);
+                }
+                crate::socket::SocketResponse::Error(e) => {
+                    anyhow::bail!("Server error: {}", e);
+                }
+                _ => {
+                    anyhow::bail!("Unexpected response from server");
+                }
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Print the audit events matching `filter`, read from storage or, when
+/// events are only written to a file, from the audit file.
+async fn audit_events(
+    config_path: &str,
+    filter: crate::audit::AuditFilter,
+    json: bool,
+) -> Result<()> {
+    let config = crate::config::Config::load(config_path)?;
+    config.validate()?;
+
+    let events = if config.audit.storage {
+        let storage = create_storage(&config).await?;
+        storage.query_audit_events(&filter).await?
+    } else if let Some(path) = &config.audit.file {
+        crate::audit::read_file(path, &filter)?
+    } else {
+        anyhow::bail!("audit logging is disabled; set audit.storage or audit.file");
+    };
+    for event in events {
+        if json {
+            println!("{}", serde_json::to_string(&event)?);
+        } else {
+            println!("{}", event);
+        }
+    }
+
+    Ok(())
+}
+
+/// Parse `--since` as an RFC 3339 time or a duration before now.
+fn parse_since(since: &str) -> Result<chrono::DateTime<chrono::Utc>> {
+    if let Ok(time) = chrono::DateTime::parse_from_rfc3339(since) {
+        return Ok(time.with_timezone(&chrono::Utc));
+    }
+    let ago = humantime::parse_duration(since).map_err(|_| {
+        anyhow::anyhow!("--since must be an RFC 3339 time or a duration such as \"1h\"")
+    })?;
+    Ok(chrono::Utc::now() - chrono::Duration::from_std(ago)?)
+}
+
+/// Record a change made directly against storage in the audit log.
+async fn record(
+    config: &crate::config::Config,
+    storage: &Arc<dyn crate::storage::StorageBackend>,
+    event: AuditEvent,
+) -> Result<()> {
+    let audit = crate::audit::Auditor::start(&config.audit, storage.clone()).await?;
+    audit.record(event);
+    audit.close().await;
     Ok(())
 }
 
 async fn create_storage(
     config: &crate::config::Config,
-) -> Result<Box<dyn crate::storage::StorageBackend>> {
+) -> Result<Arc<dyn crate::storage::StorageBackend>> {
     let database_url = config.storage.database_url.as_ref().unwrap();
     let storage = crate::storage::SqliteStorage::new(database_url).await?;
-    Ok(Box::new(storage))
+    Ok(Arc::new(storage))
 }
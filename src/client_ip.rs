@@ -0,0 +1,269 @@
+use actix_web::http::header::HeaderMap;
+use ipnet::IpNet;
+use std::net::IpAddr;
+
+/// Determine the real client address for a request arriving from `peer`.
+///
+/// Forwarding headers are only honoured when `peer` is one of the
+/// `trusted_proxies`. The hop chain is then walked right-to-left, skipping
+/// trusted hops, and the first untrusted address is taken as the client.
+/// `Forwarded` (RFC 7239) takes precedence over `X-Forwarded-For`.
+///
+/// Returns `None` when a trusted proxy sent a chain that does not name a
+/// client, so a proxy's own address is never mistaken for one.
+pub fn resolve(
+    peer: IpAddr,
+    headers: &HeaderMap,
+    trusted_proxies: &[IpNet],
+) -> Option<(&'static str, IpAddr)> {
+    let peer = peer.to_canonical();
+    if !is_trusted(peer, trusted_proxies) {
+        return Some(("peer_addr", peer));
+    }
+
+    if let Some(hops) = header_values(headers, "forwarded").map(|v| parse_forwarded(&v)) {
+        return walk_hops(hops, trusted_proxies).map(|ip| ("forwarded", ip));
+    }
+
+    if let Some(hops) = header_values(headers, "x-forwarded-for").map(|v| parse_xff(&v)) {
+        return walk_hops(hops, trusted_proxies).map(|ip| ("x-forwarded-for", ip));
+    }
+
+    Some(("peer_addr", peer))
+}
+
+fn is_trusted(ip: IpAddr, trusted_proxies: &[IpNet]) -> bool {
+    trusted_proxies.iter().any(|net| net.contains(&ip))
+}
+
+/// Walk `hops` (client first, nearest proxy last) from the right to the
+/// first untrusted hop, or the leftmost hop if all are trusted. A hop that
+/// could not be parsed before that, or an empty chain, yields `None`:
+/// nothing to its left can be trusted, and every hop to its right is a proxy.
+fn walk_hops(hops: Vec<Option<IpAddr>>, trusted_proxies: &[IpNet]) -> Option<IpAddr> {
+    let mut client = None;
+    for hop in hops.into_iter().rev() {
+        let ip = hop?;
+        client = Some(ip);
+        if !is_trusted(ip, trusted_proxies) {
+            break;
+        }
+    }
+    client
+}
+
+/// Join every instance of a header into one comma separated list.
+fn header_values(headers: &HeaderMap, name: &str) -> Option<String> {
+    let values: Vec<&str> = headers
+        .get_all(name)
+        .filter_map(|v| v.to_str().ok())
+        .collect();
+    if values.is_empty() {
+        None
+    } else {
+        Some(values.join(","))
+    }
+}
+
+fn parse_xff(value: &str) -> Vec<Option<IpAddr>> {
+    value
+        .split(',')
+        .map(str::trim)
+        .filter(|v| !v.is_empty())
+        .map(parse_node)
+        .collect()
+}
+
+/// Extract the `for=` node of every element in a `Forwarded` header.
+/// Elements without a `for` parameter contribute an unknown hop.
+fn parse_forwarded(value: &str) -> Vec<Option<IpAddr>> {
+    split_unquoted(value, ',')
+        .into_iter()
+        .filter(|element| !element.trim().is_empty())
+        .map(|element| {
+            split_unquoted(element, ';')
+                .into_iter()
+                .filter_map(|pair| pair.split_once('='))
+                .find(|(name, _)| name.trim().eq_ignore_ascii_case("for"))
+                .and_then(|(_, node)| parse_node(&unquote(node.trim())))
+        })
+        .collect()
+}
+
+/// Parse a node as used by `Forwarded` and `X-Forwarded-For`: a bare IP, an
+/// IPv4 address with a port, or a bracketed IPv6 address with optional port.
+/// Obfuscated identifiers and `unknown` yield `None`.
+fn parse_node(node: &str) -> Option<IpAddr> {
+    let node = node.trim();
+    if let Ok(ip) = node.parse::<IpAddr>() {
+        return Some(ip.to_canonical());
+    }
+    if let Some(rest) = node.strip_prefix('[') {
+        let (ip, _port) = rest.split_once(']')?;
+        return ip.parse::<IpAddr>().ok().map(|ip| ip.to_canonical());
+    }
+    let (ip, _port) = node.rsplit_once(':')?;
+    ip.parse::<std::net::Ipv4Addr>().ok().map(IpAddr::V4)
+}
+
+/// Split on `sep`, ignoring separators inside quoted strings.
+fn split_unquoted(value: &str, sep: char) -> Vec<&str> {
+    let mut parts = Vec::new();
+    let mut in_quotes = false;
+    let mut escaped = false;
+    let mut start = 0;
+    for (i, c) in value.char_indices() {
+        match c {
+            _ if escaped => escaped = false,
+            '\\' if in_quotes => escaped = true,
+            '"' => in_quotes = !in_quotes,
+            c if c == sep && !in_quotes => {
+                parts.push(&value[start..i]);
+                start = i + c.len_utf8();
+            }
+            _ => {}
+        }
+    }
+    parts.push(&value[start..]);
+    parts
+}
+
+fn unquote(value: &str) -> String {
+    match value.strip_prefix('"').and_then(|v| v.strip_suffix('"')) {
+        Some(inner) => {
+            let mut out = String::with_capacity(inner.len());
+            let mut chars = inner.chars();
+            while let Some(c) = chars.next() {
+                if c == '\\' {
+                    if let Some(next) = chars.next() {
+                        out.push(next);
+                    }
+                } else {
+                    out.push(c);
+                }
+            }
+            out
+        }
+        None => value.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::http::header::{HeaderName, HeaderValue};
+
+    fn headers(pairs: &[(&'static str, &'static str)]) -> HeaderMap {
+        let mut map = HeaderMap::new();
+        for (name, value) in pairs {
+            map.append(
+                HeaderName::from_static(name),
+                HeaderValue::from_static(value),
+            );
+        }
+        map
+    }
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    fn trusted() -> Vec<IpNet> {
+        vec!["10.0.0.0/8".parse().unwrap()]
+    }
+
+    #[test]
+    fn untrusted_peer_headers_are_ignored() {
+        let h = headers(&[("x-forwarded-for", "1.2.3.4")]);
+        assert_eq!(
+            resolve(ip("192.0.2.1"), &h, &trusted()),
+            Some(("peer_addr", ip("192.0.2.1")))
+        );
+    }
+
+    #[test]
+    fn trusted_peer_without_headers_is_the_client() {
+        assert_eq!(
+            resolve(ip("10.0.0.1"), &HeaderMap::new(), &trusted()),
+            Some(("peer_addr", ip("10.0.0.1")))
+        );
+    }
+
+    #[test]
+    fn xff_skips_trusted_hops_from_the_right() {
+        let h = headers(&[("x-forwarded-for", "6.6.6.6, 1.2.3.4, 10.0.0.2")]);
+        assert_eq!(
+            resolve(ip("10.0.0.1"), &h, &trusted()),
+            Some(("x-forwarded-for", ip("1.2.3.4")))
+        );
+    }
+
+    #[test]
+    fn all_trusted_hops_yield_the_leftmost() {
+        let h = headers(&[("x-forwarded-for", "10.0.0.3, 10.0.0.2")]);
+        assert_eq!(
+            resolve(ip("10.0.0.1"), &h, &trusted()),
+            Some(("x-forwarded-for", ip("10.0.0.3")))
+        );
+    }
+
+    #[test]
+    fn repeated_xff_headers_are_joined() {
+        let h = headers(&[
+            ("x-forwarded-for", "1.2.3.4"),
+            ("x-forwarded-for", "10.0.0.2"),
+        ]);
+        assert_eq!(
+            resolve(ip("10.0.0.1"), &h, &trusted()),
+            Some(("x-forwarded-for", ip("1.2.3.4")))
+        );
+    }
+
+    #[test]
+    fn forwarded_takes_precedence() {
+        let h = headers(&[
+            ("x-forwarded-for", "5.5.5.5"),
+            (
+                "forwarded",
+                "for=\"[2001:db8::1]:4711\";proto=https, for=10.0.0.2",
+            ),
+        ]);
+        assert_eq!(
+            resolve(ip("10.0.0.1"), &h, &trusted()),
+            Some(("forwarded", ip("2001:db8::1")))
+        );
+    }
+
+    #[test]
+    fn ipv4_with_port_and_mapped_addresses_are_canonical() {
+        let h = headers(&[("x-forwarded-for", "1.2.3.4:5678")]);
+        assert_eq!(
+            resolve(ip("::ffff:10.0.0.1"), &h, &trusted()),
+            Some(("x-forwarded-for", ip("1.2.3.4")))
+        );
+    }
+
+    #[test]
+    fn malformed_chain_never_yields_the_proxy() {
+        let trusted = trusted();
+        for (name, value) in [
+            ("x-forwarded-for", "1.2.3.4, garbage"),
+            ("x-forwarded-for", "garbage, 10.0.0.2"),
+            ("x-forwarded-for", ""),
+            ("forwarded", "for=unknown"),
+            ("forwarded", "proto=https"),
+        ] {
+            let h = headers(&[(name, value)]);
+            assert_eq!(resolve(ip("10.0.0.1"), &h, &trusted), None, "{value}");
+        }
+    }
+
+    #[test]
+    fn unparseable_hops_left_of_the_client_are_ignored() {
+        let h = headers(&[("x-forwarded-for", "garbage, 1.2.3.4, 10.0.0.2")]);
+        assert_eq!(
+            resolve(ip("10.0.0.1"), &h, &trusted()),
+            Some(("x-forwarded-for", ip("1.2.3.4")))
+        );
+    }
+}
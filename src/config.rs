@@ -1,29 +1,470 @@
+use crate::storage::AllowEntry;
 use anyhow::Result;
+use base64::{engine::general_purpose, Engine as _};
+use chrono::{DateTime, Utc};
+use ipnet::IpNet;
 use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
 use std::fs;
+use std::net::{IpAddr, SocketAddr};
 use std::path::Path;
+use uuid::Uuid;
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct Config {
     pub storage: StorageConfig,
     pub server: ServerConfig,
     pub proxy: ProxyConfig,
+    #[serde(default)]
+    pub logging: LoggingConfig,
+    #[serde(default)]
+    pub audit: AuditConfig,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct LoggingConfig {
+    #[serde(default = "default_log_level")]
+    pub level: String, // tracing filter directive, e.g. "info" or "shade_proxy=debug,info"
+    #[serde(default)]
+    pub format: LogFormat,
+    /// Append logs to this file instead of stdout
+    #[serde(default)]
+    pub file: Option<String>,
+    /// Export spans to an OpenTelemetry collector
+    #[serde(default)]
+    pub otlp: Option<OtlpConfig>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct OtlpConfig {
+    /// OTLP/HTTP traces URL, e.g. http://localhost:4318/v1/traces. Falls
+    /// back to the standard OTEL_EXPORTER_OTLP_* environment variables.
+    #[serde(default)]
+    pub endpoint: Option<String>,
+    #[serde(default = "default_service_name")]
+    pub service_name: String,
+}
+
+impl Default for LoggingConfig {
+    fn default() -> Self {
+        Self {
+            level: default_log_level(),
+            format: LogFormat::default(),
+            file: None,
+            otlp: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum LogFormat {
+    /// Bunyan JSON records
+    #[default]
+    Bunyan,
+    /// tracing's own JSON records
+    Json,
+    /// Multi-line human readable output
+    Pretty,
+}
+
+/// Where security-relevant events are recorded
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct AuditConfig {
+    /// Append events to the `audit_events` table
+    #[serde(default = "default_audit_storage")]
+    pub storage: bool,
+    /// Also append events to this file, one JSON object per line
+    #[serde(default)]
+    pub file: Option<String>,
+}
+
+impl Default for AuditConfig {
+    fn default() -> Self {
+        Self {
+            storage: default_audit_storage(),
+            file: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct StorageConfig {
     pub mode: StorageMode,
     pub database_url: Option<String>,
     pub socket_path: Option<String>,
+    #[serde(default)]
+    pub socket: SocketConfig,
+}
+
+/// Permissions of the admin socket file, and which local users may use it
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct SocketConfig {
+    /// Octal file mode, e.g. "0660"
+    #[serde(default)]
+    pub mode: Option<String>,
+    #[serde(default)]
+    pub owner: Option<String>, // user name or uid
+    #[serde(default)]
+    pub group: Option<String>, // group name or gid
+    /// Peers allowed to register, revoke and allow networks, and reload.
+    /// Root and the user running the server always are.
+    #[serde(default)]
+    pub admins: SocketPeers,
+    /// Peers allowed only to list keys
+    #[serde(default)]
+    pub readers: SocketPeers,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+impl SocketConfig {
+    pub fn file_mode(&self) -> Result<Option<u32>> {
+        let Some(mode) = &self.mode else {
+            return Ok(None);
+        };
+        match u32::from_str_radix(mode.trim_start_matches("0o"), 8) {
+            Ok(bits) if bits <= 0o7777 => Ok(Some(bits)),
+            _ => anyhow::bail!("socket mode {:?} is not an octal file mode", mode),
+        }
+    }
+}
+
+/// Local users, by name or uid, and groups, by name or gid. A peer is in a
+/// group when it is its primary group or the group lists the peer's user.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct SocketPeers {
+    #[serde(default)]
+    pub users: Vec<String>,
+    #[serde(default)]
+    pub groups: Vec<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct ProxyConfig {
-    pub listen_addr: String,   // e.g., "127.0.0.1:4000"
-    pub upstream_addr: String, // e.g., "127.0.0.1:3000"
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub listen_addr: Option<String>, // single unnamed route, kept for older configs
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub upstream_addr: Option<String>,
+    #[serde(default)]
+    pub routes: Vec<RouteConfig>,
+    #[serde(default)]
+    pub allowlist: AllowlistConfig,
+}
+
+impl ProxyConfig {
+    /// Every configured route, with a top level `listen_addr`/`upstream_addr`
+    /// pair served as a route named `default`.
+    pub fn routes(&self) -> Vec<RouteConfig> {
+        let legacy = match (&self.listen_addr, &self.upstream_addr) {
+            (Some(listen_addr), Some(upstream_addr)) => {
+                Some(RouteConfig::new("default", listen_addr, upstream_addr))
+            }
+            _ => None,
+        };
+        legacy
+            .into_iter()
+            .chain(self.routes.iter().cloned())
+            .collect()
+    }
+
+    /// Routes grouped by the socket they listen on, in configuration order.
+    /// Several TLS routes may share a TCP listener, selected by SNI.
+    pub fn listeners(&self) -> Result<Vec<(RouteProtocol, SocketAddr, Vec<RouteConfig>)>> {
+        let mut listeners: Vec<(RouteProtocol, SocketAddr, Vec<RouteConfig>)> = Vec::new();
+        for route in self.routes() {
+            let listen_addr: SocketAddr = route.listen_addr.parse().map_err(|e| {
+                anyhow::anyhow!("route {:?} has an invalid listen_addr: {}", route.name, e)
+            })?;
+            // TCP and UDP routes may share a port, e.g. for DNS
+            match listeners
+                .iter_mut()
+                .find(|(protocol, addr, _)| *protocol == route.protocol && *addr == listen_addr)
+            {
+                Some((_, _, routes)) => routes.push(route),
+                None => listeners.push((route.protocol, listen_addr, vec![route])),
+            }
+        }
+        Ok(listeners)
+    }
+}
+
+/// A proxied service: connections to `listen_addr` from allowed hosts are
+/// forwarded to one of the route's upstreams.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct RouteConfig {
+    pub name: String,
+    #[serde(default)]
+    pub protocol: RouteProtocol,
+    pub listen_addr: String, // e.g., "127.0.0.1:4000"
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub upstream_addr: Option<String>, // e.g., "127.0.0.1:3000"
+    #[serde(default)]
+    pub upstreams: Vec<String>, // pool of backends, in addition to upstream_addr
+    #[serde(default)]
+    pub balance: BalanceStrategy,
+    #[serde(default)]
+    pub health_check: HealthCheckConfig,
+    #[serde(default)]
+    pub limits: LimitsConfig,
+    #[serde(default)]
+    pub dial_attempts: Option<u32>, // backends tried per connection; None tries each once
+    #[serde(default)]
+    pub proxy_protocol: ProxyProtocolConfig,
+    #[serde(default = "default_session_idle_secs")]
+    pub session_idle_secs: u64, // UDP: forget a client mapping after this long without traffic
+    #[serde(default)]
+    pub tls: Option<TlsConfig>, // terminate TLS from clients
+    #[serde(default)]
+    pub upstream_tls: Option<UpstreamTlsConfig>, // speak TLS to the upstreams
+    /// Restrict the route to hosts enrolled by these keys...
+    #[serde(default)]
+    pub allowed_keys: Vec<Uuid>,
+    /// ...or by keys in these groups. Both empty leaves the route open to
+    /// every allowed host.
+    #[serde(default)]
+    pub allowed_groups: Vec<String>,
+}
+
+impl RouteConfig {
+    pub fn new(name: &str, listen_addr: &str, upstream_addr: &str) -> Self {
+        Self {
+            name: name.to_string(),
+            protocol: RouteProtocol::default(),
+            listen_addr: listen_addr.to_string(),
+            upstream_addr: Some(upstream_addr.to_string()),
+            upstreams: Vec::new(),
+            balance: BalanceStrategy::default(),
+            health_check: HealthCheckConfig::default(),
+            limits: LimitsConfig::default(),
+            dial_attempts: None,
+            proxy_protocol: ProxyProtocolConfig::default(),
+            session_idle_secs: default_session_idle_secs(),
+            tls: None,
+            upstream_tls: None,
+            allowed_keys: Vec::new(),
+            allowed_groups: Vec::new(),
+        }
+    }
+
+    /// Every backend address of the route, `upstream_addr` first.
+    pub fn upstream_addrs(&self) -> Vec<&str> {
+        self.upstream_addr
+            .iter()
+            .chain(self.upstreams.iter())
+            .map(String::as_str)
+            .collect()
+    }
+
+    /// Whether a host entry grants access to this route. Unrestricted routes
+    /// accept any entry; restricted routes only those owned by a listed key
+    /// or a key in a listed group.
+    pub fn permits(&self, entry: &AllowEntry) -> bool {
+        self.permits_key(entry.key_id, &entry.groups)
+    }
+
+    /// Whether a key with this id and these groups may use this route.
+    pub fn permits_key(&self, key_id: Option<Uuid>, groups: &[String]) -> bool {
+        if self.allowed_keys.is_empty() && self.allowed_groups.is_empty() {
+            return true;
+        }
+        key_id.is_some_and(|key_id| self.allowed_keys.contains(&key_id))
+            || groups
+                .iter()
+                .any(|group| self.allowed_groups.contains(group))
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum RouteProtocol {
+    #[default]
+    #[serde(rename = "tcp")]
+    Tcp,
+    /// Datagrams are relayed per client session; health checks, dial
+    /// retries and the PROXY protocol do not apply
+    #[serde(rename = "udp")]
+    Udp,
+}
+
+/// TLS presented to clients of a route
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct TlsConfig {
+    pub cert_file: String, // PEM certificate chain
+    pub key_file: String,  // PEM private key
+    /// SNI names selecting this route when several TLS routes share a
+    /// listener; a route without names catches everything else
+    #[serde(default)]
+    pub server_names: Vec<String>,
+    #[serde(default)]
+    pub client_auth: ClientAuth,
+}
+
+/// How a TLS route decides which clients to let through
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ClientAuth {
+    /// No client certificate; the client IP must be on the allowlist
+    #[default]
+    #[serde(rename = "none")]
+    None,
+    /// The client must present a certificate pinned to a registered,
+    /// unexpired key. The client IP is not checked.
+    #[serde(rename = "key")]
+    Key,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+/// TLS used when connecting to a route's upstreams
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct UpstreamTlsConfig {
+    #[serde(default)]
+    pub server_name: Option<String>, // name to send and verify; defaults to the backend IP
+    #[serde(default)]
+    pub ca_file: Option<String>, // PEM roots to trust instead of the web PKI
+}
+
+/// PROXY protocol handling on either side of a route
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct ProxyProtocolConfig {
+    #[serde(default)]
+    pub trusted_sources: Vec<IpNet>, // peers that must prefix connections with a PROXY header
+    #[serde(default)]
+    pub send: Option<crate::proxy_protocol::Version>, // announce the client to upstreams
+}
+
+impl ProxyProtocolConfig {
+    pub fn trusts(&self, peer: IpAddr) -> bool {
+        let peer = peer.to_canonical();
+        self.trusted_sources.iter().any(|net| net.contains(&peer))
+    }
+}
+
+/// How a route picks the backend for a new connection
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum BalanceStrategy {
+    #[default]
+    #[serde(rename = "round_robin")]
+    RoundRobin,
+    /// Fewest connections currently open through this proxy
+    #[serde(rename = "least_conn")]
+    LeastConn,
+    /// The same client IP keeps landing on the same backend while it is healthy
+    #[serde(rename = "consistent_hash")]
+    ConsistentHash,
+}
+
+/// Active TCP health checks; a backend is ejected after
+/// `unhealthy_threshold` failed connects in a row and brought back after
+/// `healthy_threshold` successful ones.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct HealthCheckConfig {
+    #[serde(default = "default_health_check_enabled")]
+    pub enabled: bool,
+    #[serde(default = "default_health_check_interval_secs")]
+    pub interval_secs: u64,
+    #[serde(default = "default_health_check_timeout_ms")]
+    pub timeout_ms: u64,
+    #[serde(default = "default_unhealthy_threshold")]
+    pub unhealthy_threshold: u32,
+    #[serde(default = "default_healthy_threshold")]
+    pub healthy_threshold: u32,
+}
+
+impl Default for HealthCheckConfig {
+    fn default() -> Self {
+        Self {
+            enabled: default_health_check_enabled(),
+            interval_secs: default_health_check_interval_secs(),
+            timeout_ms: default_health_check_timeout_ms(),
+            unhealthy_threshold: default_unhealthy_threshold(),
+            healthy_threshold: default_healthy_threshold(),
+        }
+    }
+}
+
+/// Connection limits and timeouts of a route. UDP routes support only
+/// `max_connections` and `max_connections_per_ip`, counting sessions. Unset
+/// limits are not enforced.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct LimitsConfig {
+    #[serde(default)]
+    pub max_connections: Option<u32>, // open connections on the route
+    #[serde(default)]
+    pub max_connections_per_ip: Option<u32>, // open connections from one client IP
+    #[serde(default)]
+    pub connections_per_minute_per_ip: Option<u32>, // new connections from one client IP, allowing bursts of this size
+    #[serde(default = "default_connect_timeout_ms")]
+    pub connect_timeout_ms: u64, // per upstream dial, including the upstream TLS handshake
+    #[serde(default = "default_handshake_timeout_ms")]
+    pub handshake_timeout_ms: u64, // client TLS handshake, from accepting the connection
+    #[serde(default)]
+    pub idle_timeout_secs: Option<u64>, // close after this long without data in either direction
+    #[serde(default)]
+    pub max_lifetime_secs: Option<u64>, // close after this long regardless of activity
+}
+
+impl Default for LimitsConfig {
+    fn default() -> Self {
+        Self {
+            max_connections: None,
+            max_connections_per_ip: None,
+            connections_per_minute_per_ip: None,
+            connect_timeout_ms: default_connect_timeout_ms(),
+            handshake_timeout_ms: default_handshake_timeout_ms(),
+            idle_timeout_secs: None,
+            max_lifetime_secs: None,
+        }
+    }
+}
+
+impl LimitsConfig {
+    /// The limits checked as a connection is admitted
+    fn admission(&self) -> (Option<u32>, Option<u32>, Option<u32>, u64) {
+        (
+            self.max_connections,
+            self.max_connections_per_ip,
+            self.connections_per_minute_per_ip,
+            self.handshake_timeout_ms,
+        )
+    }
+}
+
+/// In-memory copy of the host allowlist consulted on every proxied connection
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct AllowlistConfig {
+    #[serde(default = "default_allowlist_refresh_secs")]
+    pub refresh_secs: u64, // full reload from storage, picking up changes made by other processes
+    #[serde(default = "default_allowlist_poll_ms")]
+    pub poll_ms: u64, // check storage for changes made by other processes, reloading if there are any
+    #[serde(default = "default_allowlist_max_stale_secs")]
+    pub max_stale_secs: u64, // cache is stale if it has not been reloaded for this long
+    #[serde(default)]
+    pub stale_fallback: StaleFallback,
+}
+
+/// How connections are validated while the allowlist cache is stale
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum StaleFallback {
+    /// Query storage for each connection
+    #[default]
+    #[serde(rename = "storage")]
+    Storage,
+    /// Keep answering from the stale cache
+    #[serde(rename = "cache")]
+    Cache,
+    /// Reject every connection
+    #[serde(rename = "deny")]
+    Deny,
+}
+
+impl Default for AllowlistConfig {
+    fn default() -> Self {
+        Self {
+            refresh_secs: default_allowlist_refresh_secs(),
+            poll_ms: default_allowlist_poll_ms(),
+            max_stale_secs: default_allowlist_max_stale_secs(),
+            stale_fallback: StaleFallback::default(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub enum StorageMode {
     #[serde(rename = "file")]
     File,
@@ -31,10 +472,139 @@ pub enum StorageMode {
     Socket,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct ServerConfig {
     pub host: String,
     pub port: u16,
+    #[serde(default = "default_reap_interval_secs")]
+    pub reap_interval_secs: u64, // how often expired keys and host leases are purged
+    #[serde(default = "default_expired_key_retention_secs")]
+    pub expired_key_retention_secs: u64, // how long expired keys are kept for listing
+    #[serde(default)]
+    pub host_lease_secs: Option<u64>, // None leaves enrolled hosts trusted indefinitely
+    #[serde(default)]
+    pub max_ips_per_key: Option<u32>, // registering past this replaces the key's oldest IPs
+    #[serde(default)]
+    pub trusted_proxies: Vec<IpNet>, // peers allowed to set X-Forwarded-For / Forwarded
+    #[serde(default = "default_shutdown_timeout_secs")]
+    pub shutdown_timeout_secs: u64, // how long open connections may drain on SIGTERM/SIGINT
+    #[serde(default = "default_metrics_listen_addr")]
+    pub metrics_listen_addr: Option<String>, // serves GET /metrics; null turns it off
+    /// Serve the HTTP API over TLS
+    #[serde(default)]
+    pub tls: Option<ApiTlsConfig>,
+    /// Credentials accepted by the `/admin` API
+    #[serde(default)]
+    pub admin: AdminConfig,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ApiTlsConfig {
+    pub cert_file: String, // PEM certificate chain
+    pub key_file: String,  // PEM private key
+}
+
+/// The `/admin` API is closed until at least one credential is configured
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct AdminConfig {
+    #[serde(default)]
+    pub tokens: Vec<AdminToken>,
+    /// Client certificates accepted on the TLS listener, pinned like keys
+    /// for `client_auth: key` routes
+    #[serde(default)]
+    pub client_certs: Vec<AdminClientCert>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct AdminToken {
+    pub name: String,   // recorded as the actor in the audit log
+    pub sha256: String, // hex SHA-256 of the bearer token
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct AdminClientCert {
+    pub name: String,
+    pub spki_sha256: String, // base64 SHA-256 of the certificate's SPKI
+}
+
+impl ServerConfig {
+    /// Expiry for a host lease granted or renewed now.
+    pub fn lease_expiry(&self) -> Option<DateTime<Utc>> {
+        self.host_lease_secs
+            .map(|secs| Utc::now() + chrono::Duration::seconds(secs as i64))
+    }
+}
+
+fn default_connect_timeout_ms() -> u64 {
+    5000
+}
+
+fn default_handshake_timeout_ms() -> u64 {
+    10000
+}
+
+fn default_session_idle_secs() -> u64 {
+    60
+}
+
+fn default_health_check_enabled() -> bool {
+    true
+}
+
+fn default_health_check_interval_secs() -> u64 {
+    10
+}
+
+fn default_health_check_timeout_ms() -> u64 {
+    1000
+}
+
+fn default_unhealthy_threshold() -> u32 {
+    3
+}
+
+fn default_healthy_threshold() -> u32 {
+    2
+}
+
+fn default_allowlist_refresh_secs() -> u64 {
+    60
+}
+
+fn default_allowlist_poll_ms() -> u64 {
+    1000
+}
+
+fn default_allowlist_max_stale_secs() -> u64 {
+    5 * 60
+}
+
+fn default_reap_interval_secs() -> u64 {
+    60
+}
+
+fn default_expired_key_retention_secs() -> u64 {
+    7 * 24 * 60 * 60
+}
+
+fn default_log_level() -> String {
+    "info".to_string()
+}
+
+fn default_service_name() -> String {
+    "shade".to_string()
+}
+
+fn default_audit_storage() -> bool {
+    true
+}
+
+fn default_shutdown_timeout_secs() -> u64 {
+    30
+}
+
+fn default_metrics_listen_addr() -> Option<String> {
+    Some("127.0.0.1:9464".to_string())
 }
 
 impl Default for Config {
@@ -44,15 +614,33 @@ impl Default for Config {
                 mode: StorageMode::Socket,
                 database_url: Some("sqlite::memory:".to_string()),
                 socket_path: Some("/tmp/shade.sock".to_string()),
+                socket: SocketConfig::default(),
             },
             server: ServerConfig {
                 host: "127.0.0.1".to_string(),
                 port: 3000,
+                reap_interval_secs: default_reap_interval_secs(),
+                expired_key_retention_secs: default_expired_key_retention_secs(),
+                host_lease_secs: None,
+                max_ips_per_key: None,
+                trusted_proxies: Vec::new(),
+                shutdown_timeout_secs: default_shutdown_timeout_secs(),
+                metrics_listen_addr: default_metrics_listen_addr(),
+                tls: None,
+                admin: AdminConfig::default(),
             },
             proxy: ProxyConfig {
-                listen_addr: "127.0.0.1:3001".to_string(),
-                upstream_addr: "127.0.0.1:3002".to_string(),
+                listen_addr: None,
+                upstream_addr: None,
+                routes: vec![RouteConfig::new(
+                    "default",
+                    "127.0.0.1:3001",
+                    "127.0.0.1:3002",
+                )],
+                allowlist: AllowlistConfig::default(),
             },
+            logging: LoggingConfig::default(),
+            audit: AuditConfig::default(),
         }
     }
 }
@@ -86,9 +674,260 @@ impl Config {
                 if self.storage.socket_path.is_none() {
                     anyhow::bail!("socket_path is required for socket mode");
                 }
+                self.storage.socket.file_mode()?;
             }
         }
 
+        if self.server.reap_interval_secs == 0 {
+            anyhow::bail!("reap_interval_secs must be greater than zero");
+        }
+        if self.server.max_ips_per_key == Some(0) {
+            anyhow::bail!("max_ips_per_key must be greater than zero");
+        }
+        if self.server.host_lease_secs == Some(0) {
+            anyhow::bail!("host_lease_secs must be greater than zero");
+        }
+        if self.proxy.listen_addr.is_some() != self.proxy.upstream_addr.is_some() {
+            anyhow::bail!("proxy listen_addr and upstream_addr must be set together");
+        }
+        let routes = self.proxy.routes();
+        if routes.is_empty() {
+            anyhow::bail!("at least one proxy route is required");
+        }
+        let mut names = HashSet::new();
+        for route in &routes {
+            if route.name.is_empty() {
+                anyhow::bail!("proxy route names must not be empty");
+            }
+            if !names.insert(route.name.as_str()) {
+                anyhow::bail!("duplicate proxy route name {:?}", route.name);
+            }
+            let upstreams = route.upstream_addrs();
+            if upstreams.is_empty() {
+                anyhow::bail!("route {:?} needs at least one upstream", route.name);
+            }
+            for upstream in upstreams {
+                upstream.parse::<SocketAddr>().map_err(|e| {
+                    anyhow::anyhow!(
+                        "route {:?} has an invalid upstream {:?}: {}",
+                        route.name,
+                        upstream,
+                        e
+                    )
+                })?;
+            }
+            if route.protocol == RouteProtocol::Udp {
+                if !route.proxy_protocol.trusted_sources.is_empty()
+                    || route.proxy_protocol.send.is_some()
+                {
+                    anyhow::bail!(
+                        "route {:?} is udp and cannot use the PROXY protocol",
+                        route.name
+                    );
+                }
+                if route.tls.is_some() || route.upstream_tls.is_some() {
+                    anyhow::bail!("route {:?} is udp and cannot use TLS", route.name);
+                }
+                if route.session_idle_secs == 0 {
+                    anyhow::bail!(
+                        "route {:?} session_idle_secs must be greater than zero",
+                        route.name
+                    );
+                }
+                let session_limits = LimitsConfig {
+                    max_connections: route.limits.max_connections,
+                    max_connections_per_ip: route.limits.max_connections_per_ip,
+                    ..LimitsConfig::default()
+                };
+                if route.limits != session_limits {
+                    anyhow::bail!(
+                        "route {:?} is udp and only supports the max_connections and max_connections_per_ip limits",
+                        route.name
+                    );
+                }
+            }
+            let limits = &route.limits;
+            if limits.max_connections == Some(0)
+                || limits.max_connections_per_ip == Some(0)
+                || limits.connections_per_minute_per_ip == Some(0)
+                || limits.connect_timeout_ms == 0
+                || limits.handshake_timeout_ms == 0
+                || limits.idle_timeout_secs == Some(0)
+                || limits.max_lifetime_secs == Some(0)
+            {
+                anyhow::bail!(
+                    "route {:?} limits and timeouts must be greater than zero",
+                    route.name
+                );
+            }
+            if route.dial_attempts == Some(0) {
+                anyhow::bail!(
+                    "route {:?} dial_attempts must be greater than zero",
+                    route.name
+                );
+            }
+            let health_check = &route.health_check;
+            if health_check.interval_secs == 0
+                || health_check.timeout_ms == 0
+                || health_check.unhealthy_threshold == 0
+                || health_check.healthy_threshold == 0
+            {
+                anyhow::bail!(
+                    "route {:?} health_check interval, timeout and thresholds must be greater than zero",
+                    route.name
+                );
+            }
+        }
+        for (_, listen_addr, routes) in self.proxy.listeners()? {
+            validate_shared_listener(listen_addr, &routes)?;
+        }
+        if self.proxy.allowlist.refresh_secs == 0 {
+            anyhow::bail!("allowlist refresh_secs must be greater than zero");
+        }
+        if self.proxy.allowlist.poll_ms == 0 {
+            anyhow::bail!("allowlist poll_ms must be greater than zero");
+        }
+        if self.proxy.allowlist.max_stale_secs < self.proxy.allowlist.refresh_secs {
+            anyhow::bail!("allowlist max_stale_secs must be at least refresh_secs");
+        }
+        self.validate_admin()?;
+        if let Err(e) = tracing_subscriber::EnvFilter::try_new(&self.logging.level) {
+            anyhow::bail!("invalid logging level {:?}: {}", self.logging.level, e);
+        }
+
+        Ok(())
+    }
+
+    fn validate_admin(&self) -> Result<()> {
+        let admin = &self.server.admin;
+        let mut names = HashSet::new();
+        let credentials = admin
+            .tokens
+            .iter()
+            .map(|token| token.name.as_str())
+            .chain(admin.client_certs.iter().map(|cert| cert.name.as_str()));
+        for name in credentials {
+            if name.is_empty() {
+                anyhow::bail!("admin credential names must not be empty");
+            }
+            if !names.insert(name) {
+                anyhow::bail!("duplicate admin credential name {:?}", name);
+            }
+        }
+        for token in &admin.tokens {
+            if token.sha256.len() != 64 || !token.sha256.chars().all(|c| c.is_ascii_hexdigit()) {
+                anyhow::bail!(
+                    "admin token {:?} sha256 must be a hex encoded SHA-256 digest",
+                    token.name
+                );
+            }
+        }
+        if !admin.client_certs.is_empty() && self.server.tls.is_none() {
+            anyhow::bail!("admin client_certs require server.tls");
+        }
+        // Bearer tokens must not cross the network in cleartext
+        let loopback = self.server.host == "localhost"
+            || self
+                .server
+                .host
+                .parse::<IpAddr>()
+                .is_ok_and(|ip| ip.is_loopback());
+        if !admin.tokens.is_empty() && self.server.tls.is_none() && !loopback {
+            anyhow::bail!(
+                "admin tokens require server.tls unless server.host is a loopback address"
+            );
+        }
+        for cert in &admin.client_certs {
+            let digest = general_purpose::STANDARD
+                .decode(cert.spki_sha256.trim())
+                .ok()
+                .filter(|digest| digest.len() == 32);
+            if digest.is_none() {
+                anyhow::bail!(
+                    "admin client cert {:?} spki_sha256 must be a base64 encoded SHA-256 digest",
+                    cert.name
+                );
+            }
+        }
         Ok(())
     }
 }
+
+/// Routes may only share a listener when all of them terminate TLS, so the
+/// route can be picked by SNI before anything else happens.
+fn validate_shared_listener(listen_addr: SocketAddr, routes: &[RouteConfig]) -> Result<()> {
+    if routes.len() < 2 {
+        return Ok(());
+    }
+    let mut defaults = 0;
+    let mut server_names = HashSet::new();
+    for route in routes {
+        let Some(tls) = &route.tls else {
+            anyhow::bail!(
+                "route {:?} shares listen_addr {} but does not terminate TLS",
+                route.name,
+                listen_addr
+            );
+        };
+        if tls.server_names.is_empty() {
+            defaults += 1;
+        }
+        for name in &tls.server_names {
+            if !server_names.insert(name.to_ascii_lowercase()) {
+                anyhow::bail!(
+                    "server name {:?} is used by more than one route on {}",
+                    name,
+                    listen_addr
+                );
+            }
+        }
+        // The PROXY header comes before the TLS handshake, so it cannot
+        // depend on which route SNI selects
+        if route.proxy_protocol.trusted_sources != routes[0].proxy_protocol.trusted_sources {
+            anyhow::bail!(
+                "routes sharing listen_addr {} must have the same proxy_protocol.trusted_sources",
+                listen_addr
+            );
+        }
+        // Connections are admitted before SNI selects a route, too
+        if route.limits.admission() != routes[0].limits.admission() {
+            anyhow::bail!(
+                "routes sharing listen_addr {} must have the same max_connections, max_connections_per_ip, connections_per_minute_per_ip and handshake_timeout_ms",
+                listen_addr
+            );
+        }
+    }
+    if defaults > 1 {
+        anyhow::bail!(
+            "only one route on {} may omit tls.server_names",
+            listen_addr
+        );
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn with_admin_token(host: &str) -> Config {
+        let mut config = Config::default();
+        config.server.host = host.to_string();
+        config.server.admin.tokens.push(AdminToken {
+            name: "ops".to_string(),
+            sha256: "00".repeat(32),
+        });
+        config
+    }
+
+    #[test]
+    fn admin_tokens_need_tls_off_loopback() {
+        for host in ["127.0.0.1", "::1", "localhost"] {
+            with_admin_token(host).validate().unwrap();
+        }
+        for host in ["0.0.0.0", "192.0.2.1", "api.example.com"] {
+            let err = with_admin_token(host).validate().unwrap_err();
+            assert!(err.to_string().contains("server.tls"), "{host}: {err}");
+        }
+    }
+}
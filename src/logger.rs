@@ -1,26 +1,136 @@
+use crate::config::{LogFormat, LoggingConfig, OtlpConfig};
+use anyhow::{Context, Result};
+use opentelemetry::trace::TracerProvider;
+use opentelemetry_otlp::{SpanExporter, WithExportConfig};
+use opentelemetry_sdk::trace::{SdkTracer, SdkTracerProvider};
+use opentelemetry_sdk::Resource;
+use std::sync::{Mutex, OnceLock};
 use tracing::subscriber::{set_global_default, Subscriber};
 use tracing_bunyan_formatter::{BunyanFormattingLayer, JsonStorageLayer};
 use tracing_log::LogTracer;
 use tracing_subscriber::fmt::MakeWriter;
-use tracing_subscriber::{layer::SubscriberExt, EnvFilter, Registry};
-pub fn get_subscriber<Sink>(
+use tracing_subscriber::layer::Layered;
+use tracing_subscriber::{layer::SubscriberExt, reload, EnvFilter, Layer, Registry};
+
+// Lets the configured level replace the filter after the subscriber is set
+static FILTER: OnceLock<reload::Handle<EnvFilter, Registry>> = OnceLock::new();
+
+// Kept so buffered spans can be flushed on shutdown
+static TRACER_PROVIDER: OnceLock<SdkTracerProvider> = OnceLock::new();
+
+type Filtered = Layered<reload::Layer<EnvFilter, Registry>, Registry>;
+
+/// Where log lines are written
+pub enum Output {
+    Stdout,
+    Stderr,
+}
+
+/// Build the subscriber described by `config`. Logs go to `config.file`
+/// when set, otherwise to `output`, and spans are exported when
+/// `config.otlp` is set.
+pub fn get_subscriber(
     name: String,
-    env_filter: String,
+    config: &LoggingConfig,
+    output: Output,
+) -> Result<impl Subscriber + Send + Sync> {
+    let layer = match &config.file {
+        Some(path) => {
+            let file = std::fs::OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(path)
+                .with_context(|| format!("failed to open log file {}", path))?;
+            formatting_layer(name, config.format, Mutex::new(file))
+        }
+        None => match output {
+            Output::Stdout => formatting_layer(name, config.format, std::io::stdout),
+            Output::Stderr => formatting_layer(name, config.format, std::io::stderr),
+        },
+    };
+
+    let env_filter =
+        EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new(&config.level));
+    let (env_filter, handle) = reload::Layer::new(env_filter);
+    let _ = FILTER.set(handle);
+
+    let otel = match &config.otlp {
+        Some(otlp) => Some(tracing_opentelemetry::layer().with_tracer(tracer(otlp)?)),
+        None => None,
+    };
+    Ok(Registry::default().with(env_filter).with(layer).with(otel))
+}
+
+/// Batch spans to an OTLP/HTTP collector, and continue W3C trace contexts
+/// received from callers.
+fn tracer(config: &OtlpConfig) -> Result<SdkTracer> {
+    let mut exporter = SpanExporter::builder().with_http();
+    if let Some(endpoint) = &config.endpoint {
+        exporter = exporter.with_endpoint(endpoint);
+    }
+    let provider = SdkTracerProvider::builder()
+        .with_batch_exporter(exporter.build().context("failed to build OTLP exporter")?)
+        .with_resource(
+            Resource::builder()
+                .with_service_name(config.service_name.clone())
+                .build(),
+        )
+        .build();
+    let tracer = provider.tracer(env!("CARGO_PKG_NAME"));
+    let _ = TRACER_PROVIDER.set(provider);
+    Ok(tracer)
+}
+
+fn formatting_layer<Sink>(
+    name: String,
+    format: LogFormat,
     sink: Sink,
-) -> impl Subscriber + Send + Sync
+) -> Box<dyn Layer<Filtered> + Send + Sync>
 where
     Sink: for<'a> MakeWriter<'a> + Send + Sync + 'static,
 {
-    let env_filter =
-        EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new(env_filter));
-    let formatting_layer = BunyanFormattingLayer::new(name, sink);
-    Registry::default()
-        .with(env_filter)
-        .with(JsonStorageLayer)
-        .with(formatting_layer)
+    match format {
+        LogFormat::Bunyan => JsonStorageLayer
+            .and_then(BunyanFormattingLayer::new(name, sink))
+            .boxed(),
+        LogFormat::Json => tracing_subscriber::fmt::layer()
+            .json()
+            .with_current_span(true)
+            .with_span_list(false)
+            .with_writer(sink)
+            .boxed(),
+        LogFormat::Pretty => tracing_subscriber::fmt::layer()
+            .pretty()
+            .with_writer(sink)
+            .boxed(),
+    }
 }
 
 pub fn init_subscriber(subscriber: impl Subscriber + Send + Sync) {
     LogTracer::init().expect("Failed to set logger!");
     set_global_default(subscriber).expect("Failed to set subscriber");
 }
+
+/// Export any spans still buffered. Call once, before exiting.
+pub fn shutdown_tracing() {
+    if let Some(provider) = TRACER_PROVIDER.get()
+        && let Err(e) = provider.shutdown()
+    {
+        tracing::warn!("failed to flush spans: {}", e);
+    }
+}
+
+/// Replace the log filter with `level`, unless `RUST_LOG` is set, which
+/// always takes precedence. Returns whether the filter was replaced.
+pub fn set_level(level: &str) -> anyhow::Result<bool> {
+    if std::env::var_os(EnvFilter::DEFAULT_ENV).is_some() {
+        return Ok(false);
+    }
+    match FILTER.get() {
+        Some(handle) => {
+            handle.reload(EnvFilter::try_new(level)?)?;
+            Ok(true)
+        }
+        None => Ok(false),
+    }
+}
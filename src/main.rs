@@ -1,17 +1,24 @@
-use crate::logger::{get_subscriber, init_subscriber};
+mod admin;
+mod allowlist;
+mod audit;
 mod cert;
+mod challenge;
 mod cli;
+mod client_ip;
 mod config;
 mod logger;
+mod metrics;
 mod models;
 mod proxy;
+mod proxy_protocol;
+mod reload;
 mod server;
 mod socket;
 mod storage;
+mod tls;
+mod upstream;
 
 fn main() -> anyhow::Result<()> {
-    let subscriber = get_subscriber("shade".into(), "info".into(), std::io::stdout);
-    init_subscriber(subscriber);
     cli::run_cli()?;
     Ok(())
 }
@@ -0,0 +1,171 @@
+use anyhow::Result;
+use prometheus::{
+    Encoder, HistogramOpts, HistogramTimer, HistogramVec, IntCounterVec, IntGauge, IntGaugeVec,
+    Opts, Registry, TextEncoder,
+};
+use std::sync::LazyLock;
+
+static METRICS: LazyLock<Metrics> =
+    LazyLock::new(|| Metrics::new().expect("metric definitions are valid"));
+
+/// Prometheus metrics recorded across the server and proxy, served on
+/// `GET /metrics` at `server.metrics_listen_addr`.
+pub struct Metrics {
+    registry: Registry,
+    /// Host registrations and renewals by `endpoint`, `result` and `reason`
+    pub registrations: IntCounterVec,
+    /// Proxy connections (UDP sessions) by `route`, `result` and `reason`
+    pub proxy_connections: IntCounterVec,
+    /// Open proxy connections and UDP sessions by `route`
+    pub active_connections: IntGaugeVec,
+    /// Bytes relayed by `route` and `direction` (`in` from clients, `out` to them)
+    pub bytes: IntCounterVec,
+    /// Failed upstream dials by `route` and `upstream`
+    pub dial_failures: IntCounterVec,
+    /// Time taken by successful upstream dials by `route`
+    pub dial_seconds: HistogramVec,
+    /// Time taken by storage queries by `operation`
+    pub storage_seconds: HistogramVec,
+    /// Audit events dropped because the writer fell behind, by `action`
+    pub audit_dropped: IntCounterVec,
+    /// Audit events folded into a sampled event, by `action`
+    pub audit_suppressed: IntCounterVec,
+    keys: IntGaugeVec,
+    hosts: IntGauge,
+}
+
+impl Metrics {
+    fn new() -> Result<Self> {
+        let registry = Registry::new_custom(Some("shade".to_string()), None)?;
+        let metrics = Self {
+            registrations: IntCounterVec::new(
+                Opts::new("registrations_total", "Host registrations and renewals"),
+                &["endpoint", "result", "reason"],
+            )?,
+            proxy_connections: IntCounterVec::new(
+                Opts::new(
+                    "proxy_connections_total",
+                    "Proxy connections and UDP sessions allowed or rejected",
+                ),
+                &["route", "result", "reason"],
+            )?,
+            active_connections: IntGaugeVec::new(
+                Opts::new(
+                    "proxy_active_connections",
+                    "Open proxy connections and UDP sessions",
+                ),
+                &["route"],
+            )?,
+            bytes: IntCounterVec::new(
+                Opts::new("proxy_bytes_total", "Bytes relayed by the proxy"),
+                &["route", "direction"],
+            )?,
+            dial_failures: IntCounterVec::new(
+                Opts::new("upstream_dial_failures_total", "Failed upstream dials"),
+                &["route", "upstream"],
+            )?,
+            dial_seconds: HistogramVec::new(
+                HistogramOpts::new(
+                    "upstream_dial_duration_seconds",
+                    "Time taken to connect to an upstream",
+                ),
+                &["route"],
+            )?,
+            storage_seconds: HistogramVec::new(
+                HistogramOpts::new(
+                    "storage_query_duration_seconds",
+                    "Time taken by storage queries",
+                )
+                .buckets(vec![
+                    0.0005, 0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0,
+                ]),
+                &["operation"],
+            )?,
+            audit_dropped: IntCounterVec::new(
+                Opts::new(
+                    "audit_events_dropped_total",
+                    "Audit events dropped because the writer fell behind",
+                ),
+                &["action"],
+            )?,
+            audit_suppressed: IntCounterVec::new(
+                Opts::new(
+                    "audit_events_suppressed_total",
+                    "Repeated audit events folded into a sampled event",
+                ),
+                &["action"],
+            )?,
+            keys: IntGaugeVec::new(Opts::new("keys", "Registered keys by status"), &["status"])?,
+            hosts: IntGauge::new("hosts", "Enrolled host entries")?,
+            registry,
+        };
+
+        let registry = &metrics.registry;
+        registry.register(Box::new(metrics.registrations.clone()))?;
+        registry.register(Box::new(metrics.proxy_connections.clone()))?;
+        registry.register(Box::new(metrics.active_connections.clone()))?;
+        registry.register(Box::new(metrics.bytes.clone()))?;
+        registry.register(Box::new(metrics.dial_failures.clone()))?;
+        registry.register(Box::new(metrics.dial_seconds.clone()))?;
+        registry.register(Box::new(metrics.storage_seconds.clone()))?;
+        registry.register(Box::new(metrics.audit_dropped.clone()))?;
+        registry.register(Box::new(metrics.audit_suppressed.clone()))?;
+        registry.register(Box::new(metrics.keys.clone()))?;
+        registry.register(Box::new(metrics.hosts.clone()))?;
+        Ok(metrics)
+    }
+
+    /// Time a storage query, observed when the returned timer is dropped.
+    pub fn storage_timer(&self, operation: &str) -> HistogramTimer {
+        self.storage_seconds
+            .with_label_values(&[operation])
+            .start_timer()
+    }
+
+    pub fn connection_allowed(&self, route: &str) {
+        self.proxy_connections
+            .with_label_values(&[route, "allowed", ""])
+            .inc();
+    }
+
+    pub fn connection_rejected(&self, route: &str, reason: &str) {
+        self.proxy_connections
+            .with_label_values(&[route, "rejected", reason])
+            .inc();
+    }
+
+    /// Count an open connection on `route` until the guard is dropped.
+    pub fn connection_opened(&self, route: &str) -> ActiveGuard {
+        let gauge = self.active_connections.with_label_values(&[route]);
+        gauge.inc();
+        ActiveGuard(gauge)
+    }
+
+    /// Refresh the key and host counts from `storage`, then encode every
+    /// metric in the Prometheus text format.
+    pub async fn render(&self, storage: &dyn crate::storage::StorageBackend) -> Result<String> {
+        let (active, expired) = storage.count_keys().await?;
+        self.keys.with_label_values(&["active"]).set(active as i64);
+        self.keys
+            .with_label_values(&["expired"])
+            .set(expired as i64);
+        self.hosts.set(storage.count_hosts().await? as i64);
+
+        let mut buf = Vec::new();
+        TextEncoder::new().encode(&self.registry.gather(), &mut buf)?;
+        Ok(String::from_utf8(buf)?)
+    }
+}
+
+/// Decrements a route's active connection gauge when dropped
+pub struct ActiveGuard(IntGauge);
+
+impl Drop for ActiveGuard {
+    fn drop(&mut self) {
+        self.0.dec();
+    }
+}
+
+pub fn metrics() -> &'static Metrics {
+    &METRICS
+}
@@ -0,0 +1,2 @@
+-- The server only ever needs the public half of a client key
+ALTER TABLE keys DROP COLUMN private_key;
@@ -0,0 +1,4 @@
+-- Record which key enrolled each host so revocation can cascade
+ALTER TABLE client_ips ADD COLUMN key_id TEXT REFERENCES keys(id) ON DELETE CASCADE;
+
+CREATE INDEX IF NOT EXISTS idx_client_ips_key_id ON client_ips (key_id);
@@ -0,0 +1,2 @@
+-- Host enrollments are leases; NULL keeps pre-lease hosts trusted indefinitely
+ALTER TABLE client_ips ADD COLUMN expires_at DATETIME;
@@ -0,0 +1,2 @@
+-- Track when a host last registered or renewed, used to pick which IPs a key replaces
+ALTER TABLE client_ips ADD COLUMN updated_at DATETIME;
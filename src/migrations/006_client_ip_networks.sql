@@ -0,0 +1,11 @@
+-- Host entries are CIDR prefixes. The bounds are 16 byte big-endian
+-- addresses with IPv4 mapped into IPv6 so one range query covers both
+-- families; existing rows are backfilled on startup.
+ALTER TABLE client_ips ADD COLUMN net_start BLOB;
+ALTER TABLE client_ips ADD COLUMN net_end BLOB;
+
+CREATE INDEX IF NOT EXISTS idx_client_ips_range ON client_ips (net_start, net_end);
+
+-- Optional per-key widening of registered hosts
+ALTER TABLE keys ADD COLUMN ipv4_prefix_len INTEGER;
+ALTER TABLE keys ADD COLUMN ipv6_prefix_len INTEGER;
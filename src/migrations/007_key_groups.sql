@@ -0,0 +1,2 @@
+-- Comma separated group names a key belongs to, used to restrict proxy routes
+ALTER TABLE keys ADD COLUMN groups TEXT NOT NULL DEFAULT '';
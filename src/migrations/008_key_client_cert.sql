@@ -0,0 +1,4 @@
+-- Base64 SHA-256 of the SubjectPublicKeyInfo of a client certificate pinned
+-- to the key, used by routes attesting clients with mutual TLS
+ALTER TABLE keys ADD COLUMN spki_sha256 TEXT;
+CREATE INDEX IF NOT EXISTS idx_keys_spki_sha256 ON keys (spki_sha256);
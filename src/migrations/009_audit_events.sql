@@ -0,0 +1,25 @@
+-- Security-relevant events, appended by the audit subsystem and never changed
+CREATE TABLE IF NOT EXISTS audit_events (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    timestamp DATETIME NOT NULL,
+    action TEXT NOT NULL,
+    actor TEXT NOT NULL,
+    key_id TEXT,
+    ip TEXT,
+    network TEXT,
+    route TEXT,
+    decision TEXT NOT NULL,
+    reason TEXT
+);
+CREATE INDEX IF NOT EXISTS idx_audit_events_timestamp ON audit_events (timestamp);
+CREATE INDEX IF NOT EXISTS idx_audit_events_key_id ON audit_events (key_id);
+CREATE INDEX IF NOT EXISTS idx_audit_events_ip ON audit_events (ip);
+
+CREATE TRIGGER IF NOT EXISTS audit_events_no_update BEFORE UPDATE ON audit_events
+BEGIN
+    SELECT RAISE(ABORT, 'audit_events is append-only');
+END;
+CREATE TRIGGER IF NOT EXISTS audit_events_no_delete BEFORE DELETE ON audit_events
+BEGIN
+    SELECT RAISE(ABORT, 'audit_events is append-only');
+END;
@@ -0,0 +1,2 @@
+-- Identical denials folded into one event by sampling
+ALTER TABLE audit_events ADD COLUMN repeats INTEGER NOT NULL DEFAULT 0;
@@ -0,0 +1,18 @@
+-- Bumped by every change to hosts or keys, so a running proxy notices
+-- changes made by other processes without reloading its allowlist
+CREATE TABLE allowlist_version (
+    id INTEGER PRIMARY KEY CHECK (id = 0),
+    version INTEGER NOT NULL
+);
+INSERT INTO allowlist_version (id, version) VALUES (0, 0);
+
+CREATE TRIGGER client_ips_inserted AFTER INSERT ON client_ips
+BEGIN UPDATE allowlist_version SET version = version + 1; END;
+CREATE TRIGGER client_ips_updated AFTER UPDATE ON client_ips
+BEGIN UPDATE allowlist_version SET version = version + 1; END;
+CREATE TRIGGER client_ips_deleted AFTER DELETE ON client_ips
+BEGIN UPDATE allowlist_version SET version = version + 1; END;
+CREATE TRIGGER keys_updated AFTER UPDATE ON keys
+BEGIN UPDATE allowlist_version SET version = version + 1; END;
+CREATE TRIGGER keys_deleted AFTER DELETE ON keys
+BEGIN UPDATE allowlist_version SET version = version + 1; END;
@@ -1,17 +1,142 @@
+use crate::storage::{HostPair, KeyRecord};
+use chrono::{DateTime, Utc};
+use ipnet::IpNet;
 use serde::{Deserialize, Serialize};
 use utoipa::ToSchema;
+use uuid::Uuid;
 
 #[derive(Serialize, ToSchema)]
 pub struct HealthResponse {
     pub status: String,
 }
 
-#[derive(Debug, Deserialize, ToSchema)]
+#[derive(Serialize, Deserialize, ToSchema)]
+pub struct ChallengeResponse {
+    pub challenge_id: String,
+    pub server_public_key: String,
+    pub nonce: String,
+    pub expires_in: u64,
+}
+
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
 pub struct RegisterRequest {
     pub public_key: String,
+    pub challenge_id: String,
+    pub mac: String,
 }
 
-#[derive(Serialize, ToSchema)]
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum RegistrationStatus {
+    New,
+    Refreshed,
+    Moved,
+}
+
+#[derive(Serialize, Deserialize, ToSchema)]
 pub struct RegisterResponse {
     pub message: String,
+    pub status: RegistrationStatus,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub replaced_ips: Vec<String>,
+    pub expires_at: Option<DateTime<Utc>>,
+}
+
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum KeyStatus {
+    Active,
+    Expired,
+}
+
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
+pub struct KeyResponse {
+    pub id: Uuid,
+    pub public_key: String,
+    pub status: KeyStatus,
+    pub created_at: DateTime<Utc>,
+    pub expires_at: Option<DateTime<Utc>>,
+    pub ipv4_prefix_len: Option<u8>,
+    pub ipv6_prefix_len: Option<u8>,
+    pub groups: Vec<String>,
+    pub spki_sha256: Option<String>,
+}
+
+impl From<KeyRecord> for KeyResponse {
+    fn from(key: KeyRecord) -> Self {
+        let status = match key.status() {
+            crate::storage::KeyStatus::Active => KeyStatus::Active,
+            crate::storage::KeyStatus::Expired => KeyStatus::Expired,
+        };
+        Self {
+            id: key.id,
+            public_key: key.public_key,
+            status,
+            created_at: key.created_at,
+            expires_at: key.expires_at,
+            ipv4_prefix_len: key.ipv4_prefix_len,
+            ipv6_prefix_len: key.ipv6_prefix_len,
+            groups: key.groups,
+            spki_sha256: key.spki_sha256,
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
+pub struct CreateKeyRequest {
+    pub public_key: String,
+    pub expires_at: Option<DateTime<Utc>>,
+    #[serde(default)]
+    pub ipv4_prefix_len: Option<u8>,
+    #[serde(default)]
+    pub ipv6_prefix_len: Option<u8>,
+    #[serde(default)]
+    pub groups: Vec<String>,
+    /// Base64 SHA-256 of the SPKI of a client certificate to pin to the key
+    #[serde(default)]
+    pub spki_sha256: Option<String>,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize, ToSchema)]
+pub struct ExpireKeyRequest {
+    /// When the key expires; now if omitted
+    #[serde(default)]
+    pub expires_at: Option<DateTime<Utc>>,
+}
+
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
+pub struct RevokeKeyResponse {
+    pub hosts_removed: u64,
+}
+
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
+pub struct HostResponse {
+    #[schema(value_type = String, example = "203.0.113.7/32")]
+    pub network: IpNet,
+    /// The key that enrolled or owns the entry
+    pub key_id: Option<Uuid>,
+    pub created_at: DateTime<Utc>,
+    pub expires_at: Option<DateTime<Utc>>,
+}
+
+impl From<HostPair> for HostResponse {
+    fn from(host: HostPair) -> Self {
+        Self {
+            network: host.network,
+            key_id: host.key_id,
+            created_at: host.created_at,
+            expires_at: host.expires_at,
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
+pub struct CreateHostRequest {
+    #[schema(value_type = String, example = "203.0.113.0/24")]
+    pub network: IpNet,
+    /// Owning key; revoking it removes the entry
+    #[serde(default)]
+    pub key_id: Option<Uuid>,
+    #[serde(default)]
+    pub expires_at: Option<DateTime<Utc>>,
 }
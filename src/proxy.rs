@@ -1,67 +1,710 @@
-use crate::config::Config;
-use anyhow::Result;
-use std::net::SocketAddr;
+use crate::allowlist::Allowlist;
+use crate::audit::{AuditAction, AuditEvent, Auditor, Decision};
+use crate::config::{ClientAuth, Config, ProxyConfig, RouteConfig, RouteProtocol};
+use crate::metrics::metrics;
+use crate::proxy_protocol;
+use crate::storage::{AllowEntry, StorageBackend};
+use crate::upstream::UpstreamPool;
+use anyhow::{Context, Result};
+use std::collections::{BTreeMap, HashMap};
+use std::net::{IpAddr, SocketAddr};
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::Arc;
-use tokio::net::{TcpListener, TcpStream};
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream, UdpSocket};
+use tokio::sync::{watch, Mutex};
+use tokio::task::JoinHandle;
+use tokio_rustls::rustls::pki_types::CertificateDer;
+use tokio_rustls::rustls::server::Acceptor;
+use tokio_rustls::rustls::ServerConfig;
+use tokio_rustls::{LazyConfigAcceptor, TlsConnector};
+use tokio_util::sync::CancellationToken;
+use tokio_util::task::TaskTracker;
+use tracing::{error, field, info, info_span, warn, Instrument, Span};
+use uuid::Uuid;
 
-/// Run a TCP proxy that validates connecting IPs and forwards traffic to upstream
-pub async fn run_proxy(config_path: &str) -> Result<()> {
-    let config = Config::load(config_path)?;
-    config.validate()?;
+// Numbers connections in log spans
+static NEXT_CONNECTION_ID: AtomicU64 = AtomicU64::new(1);
 
-    let listener_addr: SocketAddr = config.proxy.listen_addr.parse()?;
-    let upstream_addr: SocketAddr = config.proxy.upstream_addr.parse()?;
+mod limits;
+mod relay;
+mod udp;
 
-    let listener = TcpListener::bind(listener_addr).await?;
-    println!(
-        "TCP Proxy listening on {}, forwarding to {}",
-        listener_addr, upstream_addr
-    );
+/// A plain or TLS wrapped connection on either side of the proxy
+trait Stream: AsyncRead + AsyncWrite + Unpin + Send {}
 
-    let storage = crate::server::create_storage(&config).await?;
-    let storage = Arc::clone(&storage);
+impl<T: AsyncRead + AsyncWrite + Unpin + Send> Stream for T {}
 
-    loop {
-        let (mut inbound, addr) = listener.accept().await?;
-        let storage = Arc::clone(&storage);
+/// A TCP route ready to serve connections
+struct TcpRoute {
+    config: Arc<RouteConfig>,
+    upstreams: Arc<UpstreamPool>,
+    tls: Option<Arc<ServerConfig>>,
+    upstream_tls: Option<TlsConnector>,
+    limits: Arc<limits::RouteLimits>,
+}
+
+impl TcpRoute {
+    fn new(config: RouteConfig) -> Result<Self> {
+        let context = || format!("route {:?}", config.name);
+        let tls = config
+            .tls
+            .as_ref()
+            .map(crate::tls::server_config)
+            .transpose()
+            .with_context(context)?;
+        let upstream_tls = config
+            .upstream_tls
+            .as_ref()
+            .map(crate::tls::connector)
+            .transpose()
+            .with_context(context)?;
+        Ok(Self {
+            upstreams: Arc::new(UpstreamPool::new(&config)?),
+            limits: Arc::new(limits::RouteLimits::new(config.limits.clone())),
+            config: Arc::new(config),
+            tls,
+            upstream_tls,
+        })
+    }
+}
+
+type ListenerKey = (RouteProtocol, SocketAddr);
+
+/// The routes a listener currently serves. Replacing them only affects
+/// connections accepted afterwards.
+enum ListenerRoutes {
+    Tcp(watch::Sender<Arc<Vec<TcpRoute>>>),
+    Udp(watch::Sender<Arc<udp::UdpRoute>>),
+}
+
+/// A bound listener and the routes it was last configured with.
+struct Listener {
+    configs: Vec<RouteConfig>,
+    routes: ListenerRoutes,
+    // Cancelled when the routes are replaced, stopping their health checks
+    generation: CancellationToken,
+    stop: CancellationToken,
+    task: JoinHandle<()>,
+}
+
+/// A listener's routes, built and bound but not yet serving.
+enum Prepared {
+    Tcp(Option<TcpListener>, Vec<TcpRoute>),
+    Udp(Option<UdpSocket>, udp::UdpRoute),
+}
 
-        tokio::spawn(async move {
-            let client_ip = addr.ip().to_string();
+/// The running proxy: one listener per distinct route listen address,
+/// validating connecting IPs and forwarding traffic to the route's
+/// upstreams. Routes can be replaced while running with `apply`.
+pub struct Proxy {
+    storage: Arc<dyn StorageBackend>,
+    audit: Arc<Auditor>,
+    allowlist: Arc<Allowlist>,
+    shutdown: CancellationToken,
+    connections: TaskTracker,
+    listeners: Mutex<HashMap<ListenerKey, Listener>>,
+}
 
-            // Validate connecting IP
-            match storage.validate_host_ip(&client_ip).await {
-                Ok(true) => {
-                    println!("Allowed connection from {}", client_ip);
+impl Proxy {
+    /// Start serving every configured route. Listeners stop accepting once
+    /// `shutdown` is cancelled; call `drain` to wait for open connections.
+    pub async fn start(
+        config: &Config,
+        storage: Arc<dyn StorageBackend>,
+        audit: Arc<Auditor>,
+        shutdown: CancellationToken,
+    ) -> Result<Arc<Self>> {
+        let allowlist =
+            Allowlist::start(Arc::clone(&storage), config.proxy.allowlist.clone()).await?;
+        let proxy = Arc::new(Self {
+            storage,
+            audit,
+            allowlist,
+            shutdown,
+            connections: TaskTracker::new(),
+            listeners: Mutex::new(HashMap::new()),
+        });
+        proxy.apply(&config.proxy).await?;
+        Ok(proxy)
+    }
+
+    /// Bring the listeners in line with `config`, returning a description of
+    /// each route added, updated or removed. New routes are built and new
+    /// listeners bound before anything is swapped, so a bad route leaves the
+    /// running ones untouched. Open connections keep the route they were
+    /// accepted on.
+    pub async fn apply(&self, config: &ProxyConfig) -> Result<Vec<String>> {
+        let mut listeners = self.listeners.lock().await;
+        let wanted = config.listeners()?;
+
+        let mut prepared = Vec::new();
+        for (protocol, addr, configs) in &wanted {
+            let key = (*protocol, *addr);
+            let existing = listeners.get(&key);
+            if existing.is_some_and(|listener| listener.configs == *configs) {
+                continue;
+            }
+            let names: Vec<&str> = configs.iter().map(|route| route.name.as_str()).collect();
+            let context = || format!("failed to bind route {:?} on {}", names, addr);
+            let built = match protocol {
+                RouteProtocol::Tcp => {
+                    let socket = match existing {
+                        Some(_) => None,
+                        None => Some(TcpListener::bind(addr).await.with_context(context)?),
+                    };
+                    let routes = configs
+                        .iter()
+                        .cloned()
+                        .map(TcpRoute::new)
+                        .collect::<Result<Vec<_>>>()?;
+                    Prepared::Tcp(socket, routes)
                 }
-                Ok(false) => {
-                    println!("Rejected connection from {}", client_ip);
-                    return; // Drop the connection immediately
+                RouteProtocol::Udp => {
+                    let socket = match existing {
+                        Some(_) => None,
+                        None => Some(UdpSocket::bind(addr).await.with_context(context)?),
+                    };
+                    // Only TLS routes may share a listener, so a UDP listener has one route
+                    let route = configs.first().context("listener without routes")?;
+                    Prepared::Udp(socket, udp::UdpRoute::new(route.clone())?)
                 }
-                Err(e) => {
-                    eprintln!("Validation error for {}: {}", client_ip, e);
-                    return;
-                }
-            }
+            };
+            prepared.push((key, configs.clone(), built));
+        }
 
-            // Connect to upstream server
-            match TcpStream::connect(upstream_addr).await {
-                Ok(mut outbound) => {
-                    let (mut ri, mut wi) = inbound.split();
-                    let (mut ro, mut wo) = outbound.split();
+        let changes = describe_changes(&listeners, &wanted);
 
-                    // Forward inbound -> outbound
-                    let client_to_upstream = tokio::io::copy(&mut ri, &mut wo);
-                    // Forward outbound -> inbound
-                    let upstream_to_client = tokio::io::copy(&mut ro, &mut wi);
+        // Listeners no longer configured stop accepting; their connections
+        // are left to finish
+        listeners.retain(|key, listener| {
+            let keep = wanted
+                .iter()
+                .any(|(protocol, addr, _)| (*protocol, *addr) == *key);
+            if !keep {
+                listener.stop.cancel();
+                listener.generation.cancel();
+                info!(listen_addr = %key.1, "proxy listener closed");
+            }
+            keep
+        });
 
-                    if let Err(e) = tokio::try_join!(client_to_upstream, upstream_to_client) {
-                        eprintln!("Proxy connection error: {}", e);
+        for (key, configs, built) in prepared {
+            let generation = self.shutdown.child_token();
+            match built {
+                Prepared::Tcp(socket, routes) => {
+                    // Routes sharing a listener use the limits of the first
+                    if let Some(route) = routes.first() {
+                        route.limits.spawn_sweeper(generation.clone());
+                    }
+                    for route in &routes {
+                        log_route(key.1, route);
+                        route.upstreams.spawn_health_checks(
+                            route.config.health_check.clone(),
+                            generation.clone(),
+                        );
+                    }
+                    let routes = Arc::new(routes);
+                    match (socket, listeners.get_mut(&key)) {
+                        (None, Some(listener)) => {
+                            if let ListenerRoutes::Tcp(sender) = &listener.routes {
+                                sender.send_replace(routes);
+                            }
+                            listener.generation.cancel();
+                            listener.generation = generation;
+                            listener.configs = configs;
+                        }
+                        (Some(socket), _) => {
+                            let (sender, receiver) = watch::channel(routes);
+                            let stop = self.shutdown.child_token();
+                            let task = tokio::spawn(run_tcp_listener(
+                                socket,
+                                receiver,
+                                Arc::clone(&self.allowlist),
+                                Arc::clone(&self.storage),
+                                Arc::clone(&self.audit),
+                                stop.clone(),
+                                self.connections.clone(),
+                            ));
+                            listeners.insert(
+                                key,
+                                Listener {
+                                    configs,
+                                    routes: ListenerRoutes::Tcp(sender),
+                                    generation,
+                                    stop,
+                                    task,
+                                },
+                            );
+                        }
+                        (None, None) => unreachable!("new listeners are bound above"),
                     }
                 }
+                // Health checks dial TCP, which says nothing about a UDP service
+                Prepared::Udp(socket, route) => {
+                    info!(
+                        route = %route.config.name,
+                        listen_addr = %key.1,
+                        upstreams = %route.config.upstream_addrs().join(", "),
+                        "UDP proxy route listening"
+                    );
+                    let route = Arc::new(route);
+                    match (socket, listeners.get_mut(&key)) {
+                        (None, Some(listener)) => {
+                            if let ListenerRoutes::Udp(sender) = &listener.routes {
+                                sender.send_replace(route);
+                            }
+                            listener.generation.cancel();
+                            listener.generation = generation;
+                            listener.configs = configs;
+                        }
+                        (Some(socket), _) => {
+                            let (sender, receiver) = watch::channel(route);
+                            let stop = self.shutdown.child_token();
+                            let task = tokio::spawn(udp::run_route(
+                                socket,
+                                receiver,
+                                Arc::clone(&self.allowlist),
+                                Arc::clone(&self.audit),
+                                stop.clone(),
+                            ));
+                            listeners.insert(
+                                key,
+                                Listener {
+                                    configs,
+                                    routes: ListenerRoutes::Udp(sender),
+                                    generation,
+                                    stop,
+                                    task,
+                                },
+                            );
+                        }
+                        (None, None) => unreachable!("new listeners are bound above"),
+                    }
+                }
+            }
+        }
+
+        Ok(changes)
+    }
+
+    /// Wait for the listeners to stop after shutdown, then give open
+    /// connections up to `deadline` to finish.
+    pub async fn drain(&self, deadline: Duration) {
+        let listeners: Vec<Listener> = self
+            .listeners
+            .lock()
+            .await
+            .drain()
+            .map(|(_, l)| l)
+            .collect();
+        for listener in listeners {
+            listener.stop.cancel();
+            let _ = listener.task.await;
+        }
+
+        self.connections.close();
+        if !self.connections.is_empty() {
+            info!("draining {} proxy connections", self.connections.len());
+        }
+        if tokio::time::timeout(deadline, self.connections.wait())
+            .await
+            .is_err()
+        {
+            warn!(
+                "shutdown deadline passed, closing {} proxy connections",
+                self.connections.len()
+            );
+        }
+    }
+}
+
+fn log_route(listener_addr: SocketAddr, route: &TcpRoute) {
+    info!(
+        route = %route.config.name,
+        listen_addr = %listener_addr,
+        tls = route.tls.is_some(),
+        upstreams = %route.config.upstream_addrs().join(", "),
+        upstream_tls = route.upstream_tls.is_some(),
+        "TCP proxy route listening"
+    );
+}
+
+/// Describe, by route name, how the configured routes differ from those
+/// the listeners are serving.
+fn describe_changes(
+    listeners: &HashMap<ListenerKey, Listener>,
+    wanted: &[(RouteProtocol, SocketAddr, Vec<RouteConfig>)],
+) -> Vec<String> {
+    let current: BTreeMap<&str, &RouteConfig> = listeners
+        .values()
+        .flat_map(|listener| &listener.configs)
+        .map(|route| (route.name.as_str(), route))
+        .collect();
+    let wanted: BTreeMap<&str, &RouteConfig> = wanted
+        .iter()
+        .flat_map(|(_, _, configs)| configs)
+        .map(|route| (route.name.as_str(), route))
+        .collect();
+
+    let mut changes = Vec::new();
+    for (name, route) in &wanted {
+        match current.get(name) {
+            None => changes.push(format!("route {} added", name)),
+            Some(current) if current != route => changes.push(format!("route {} updated", name)),
+            Some(_) => {}
+        }
+    }
+    for name in current.keys() {
+        if !wanted.contains_key(name) {
+            changes.push(format!("route {} removed", name));
+        }
+    }
+    changes
+}
+
+async fn run_tcp_listener(
+    listener: TcpListener,
+    routes: watch::Receiver<Arc<Vec<TcpRoute>>>,
+    allowlist: Arc<Allowlist>,
+    storage: Arc<dyn StorageBackend>,
+    audit: Arc<Auditor>,
+    stop: CancellationToken,
+    connections: TaskTracker,
+) {
+    loop {
+        let (inbound, peer) = tokio::select! {
+            accepted = listener.accept() => match accepted {
+                Ok(accepted) => accepted,
                 Err(e) => {
-                    eprintln!("Failed to connect to upstream {}: {}", upstream_addr, e);
+                    error!("proxy listener error: {}", e);
+                    return;
+                }
+            },
+            _ = stop.cancelled() => return,
+        };
+        let allowlist = Arc::clone(&allowlist);
+        let storage = Arc::clone(&storage);
+        let audit = Arc::clone(&audit);
+        let routes = Arc::clone(&routes.borrow());
+
+        // The client, route and key are filled in as they become known. The key
+        // is the one the client's certificate is pinned to on attested routes,
+        // otherwise the one that enrolled the matching allowlist entry.
+        let span = info_span!(
+            "connection",
+            id = NEXT_CONNECTION_ID.fetch_add(1, Ordering::Relaxed),
+            client = %peer,
+            route = field::Empty,
+            key_id = field::Empty,
+        );
+        connections.spawn(
+            async move {
+                let connection =
+                    handle_connection(inbound, peer, &routes, allowlist, storage, &audit);
+                if let Err(e) = connection.await {
+                    warn!("dropping connection: {:#}", e);
                 }
             }
-        });
+            .instrument(span),
+        );
+    }
+}
+
+async fn handle_connection(
+    mut inbound: TcpStream,
+    peer: SocketAddr,
+    routes: &[TcpRoute],
+    allowlist: Arc<Allowlist>,
+    storage: Arc<dyn StorageBackend>,
+    audit: &Auditor,
+) -> Result<()> {
+    let accepted = Instant::now();
+    // Behind a trusted load balancer the real client is in the PROXY header.
+    // Routes sharing a listener agree on trusted sources.
+    let (client_addr, local_addr) = client_addrs(&mut inbound, peer, &routes[0].config).await?;
+    let client_ip = client_addr.ip();
+    let span = Span::current();
+    if client_addr != peer {
+        span.record("client", field::display(client_addr));
+    }
+
+    // Counted before the handshake, so unauthenticated peers cannot hold
+    // more sockets than the limits allow. Routes sharing a listener agree
+    // on their limits, and share those of the first route.
+    let listener = &routes[0];
+    let _limit = match listener.limits.admit(client_ip) {
+        Ok(guard) => guard,
+        Err(rejection) => {
+            span.record("route", listener.config.name.as_str());
+            reject_over_limit(audit, listener, client_ip, None, rejection);
+            return Ok(());
+        }
+    };
+
+    // TLS listeners pick the route from the ClientHello, before any
+    // certificate is presented to a client that may not be allowed
+    let mut key_id = None;
+    let (route, inbound): (&TcpRoute, Box<dyn Stream>) = if listener.tls.is_some() {
+        let deadline = tokio::time::Instant::now()
+            + Duration::from_millis(listener.config.limits.handshake_timeout_ms);
+        let start = tokio::time::timeout_at(
+            deadline,
+            LazyConfigAcceptor::new(Acceptor::default(), inbound),
+        )
+        .await
+        .context("timed out waiting for the TLS handshake")?
+        .context("TLS handshake failed")?;
+        let sni = start.client_hello().server_name().map(str::to_string);
+        let route = select_route(routes, sni.as_deref())
+            .with_context(|| format!("no route for server name {:?}", sni))?;
+        span.record("route", route.config.name.as_str());
+        // Attested routes identify the client by its certificate instead
+        let attested = route
+            .config
+            .tls
+            .as_ref()
+            .is_some_and(|tls| tls.client_auth == ClientAuth::Key);
+        if !attested {
+            match allowed_entry(&allowlist, audit, client_ip, route).await? {
+                Some(entry) => key_id = entry.key_id,
+                None => return Ok(()), // Drop the connection before the handshake completes
+            }
+        }
+        let tls = route.tls.clone().context("route does not terminate TLS")?;
+        let stream = tokio::time::timeout_at(deadline, start.into_stream(tls))
+            .await
+            .context("timed out waiting for the TLS handshake")?
+            .context("TLS handshake failed")?;
+        if attested {
+            let cert = stream
+                .get_ref()
+                .1
+                .peer_certificates()
+                .and_then(<[_]>::first);
+            key_id = is_attested(storage.as_ref(), audit, client_ip, route, cert).await?;
+            if key_id.is_none() {
+                return Ok(());
+            }
+        }
+        (route, Box::new(stream))
+    } else {
+        let route = listener;
+        span.record("route", route.config.name.as_str());
+        match allowed_entry(&allowlist, audit, client_ip, route).await? {
+            Some(entry) => key_id = entry.key_id,
+            None => return Ok(()), // Drop the connection immediately
+        }
+        (route, Box::new(inbound))
+    };
+    if let Some(key_id) = key_id {
+        span.record("key_id", field::display(key_id));
+    }
+
+    // Rates are only tracked for allowed clients, so a flood of addresses
+    // that are turned away cannot grow the buckets
+    if let Err(rejection) = listener.limits.check_rate(client_ip) {
+        reject_over_limit(audit, route, client_ip, key_id, rejection);
+        return Ok(());
+    }
+
+    metrics().connection_allowed(&route.config.name);
+    audit_decision(audit, route, client_ip, key_id, None);
+    let _active = metrics().connection_opened(&route.config.name);
+
+    // Connect to an upstream, trying further backends if the dial fails
+    let (mut outbound, backend) = route
+        .upstreams
+        .dial(client_ip)
+        .await
+        .with_context(|| format!("route {}", route.config.name))?;
+    info!(upstream = %backend.addr(), "forwarding connection");
+    if let Some(version) = route.config.proxy_protocol.send {
+        let header = proxy_protocol::encode_header(version, client_addr, local_addr);
+        outbound
+            .write_all(&header)
+            .await
+            .with_context(|| format!("failed to send PROXY header to {}", backend.addr()))?;
     }
+    let outbound: Box<dyn Stream> = match (&route.upstream_tls, &route.config.upstream_tls) {
+        (Some(connector), Some(config)) => {
+            let server_name = crate::tls::upstream_server_name(config, backend.addr())?;
+            let handshake = tokio::time::timeout(
+                route.upstreams.connect_timeout(),
+                connector.connect(server_name, outbound),
+            );
+            Box::new(
+                handshake
+                    .await
+                    .with_context(|| format!("TLS handshake with {} timed out", backend.addr()))?
+                    .with_context(|| format!("TLS handshake with {} failed", backend.addr()))?,
+            )
+        }
+        _ => Box::new(outbound),
+    };
+
+    let relayed = relay::relay(inbound, outbound, &route.config.limits).await;
+    let bytes = &metrics().bytes;
+    bytes
+        .with_label_values(&[route.config.name.as_str(), "in"])
+        .inc_by(relayed.bytes_in);
+    bytes
+        .with_label_values(&[route.config.name.as_str(), "out"])
+        .inc_by(relayed.bytes_out);
+    info!(
+        upstream = %backend.addr(),
+        bytes_in = relayed.bytes_in,
+        bytes_out = relayed.bytes_out,
+        duration_ms = accepted.elapsed().as_millis() as u64,
+        reason = %relayed.reason,
+        "connection closed"
+    );
+    Ok(())
+}
+
+/// The route whose `tls.server_names` match `sni`, falling back to the
+/// route without names.
+fn select_route<'a>(routes: &'a [TcpRoute], sni: Option<&str>) -> Option<&'a TcpRoute> {
+    fn names(route: &TcpRoute) -> &[String] {
+        route
+            .config
+            .tls
+            .as_ref()
+            .map(|tls| tls.server_names.as_slice())
+            .unwrap_or_default()
+    }
+    sni.and_then(|sni| {
+        routes
+            .iter()
+            .find(|route| crate::tls::sni_matches(names(route), sni))
+    })
+    .or_else(|| routes.iter().find(|route| names(route).is_empty()))
+}
+
+/// The host entry allowing `client_ip` on `route`, if there is one.
+async fn allowed_entry(
+    allowlist: &Allowlist,
+    audit: &Auditor,
+    client_ip: IpAddr,
+    route: &TcpRoute,
+) -> Result<Option<AllowEntry>> {
+    // Validate connecting IP
+    let entry = allowlist
+        .lookup(client_ip, &route.config)
+        .await
+        .with_context(|| format!("validation error for {}", client_ip))?;
+    match &entry {
+        Some(entry) => info!(network = %entry.network, "connection allowed"),
+        None => {
+            info!(reason = "not_allowed", "connection rejected");
+            metrics().connection_rejected(&route.config.name, "not_allowed");
+            audit_decision(audit, route, client_ip, None, Some("not_allowed"));
+        }
+    }
+    Ok(entry)
+}
+
+/// The key `cert` is pinned to, if it is one `route` permits.
+async fn is_attested(
+    storage: &dyn StorageBackend,
+    audit: &Auditor,
+    client_ip: IpAddr,
+    route: &TcpRoute,
+    cert: Option<&CertificateDer<'_>>,
+) -> Result<Option<Uuid>> {
+    let Some(cert) = cert else {
+        info!(
+            reason = "no_client_certificate",
+            "connection rejected: no client certificate"
+        );
+        metrics().connection_rejected(&route.config.name, "no_client_certificate");
+        audit_decision(audit, route, client_ip, None, Some("no_client_certificate"));
+        return Ok(None);
+    };
+    let pin = crate::tls::spki_sha256(cert)?;
+    let key = storage
+        .find_key_by_spki(&pin)
+        .await
+        .with_context(|| format!("attestation error for {}", client_ip))?;
+    match key {
+        Some(key) if !key.is_expired() && route.config.permits_key(Some(key.id), &key.groups) => {
+            info!(key_id = %key.id, "connection allowed by client certificate");
+            Ok(Some(key.id))
+        }
+        key => {
+            info!(
+                reason = "not_attested",
+                spki_sha256 = %pin,
+                "connection rejected: certificate is not pinned to a permitted key"
+            );
+            metrics().connection_rejected(&route.config.name, "not_attested");
+            let key_id = key.map(|key| key.id);
+            audit_decision(audit, route, client_ip, key_id, Some("not_attested"));
+            Ok(None)
+        }
+    }
+}
+
+fn reject_over_limit(
+    audit: &Auditor,
+    route: &TcpRoute,
+    client_ip: IpAddr,
+    key_id: Option<Uuid>,
+    rejection: limits::Rejection,
+) {
+    info!(
+        reason = rejection.label(),
+        "connection rejected: {}", rejection
+    );
+    metrics().connection_rejected(&route.config.name, rejection.label());
+    audit_decision(audit, route, client_ip, key_id, Some(rejection.label()));
+}
+
+/// Record the proxy's decision on a connection, denied when there is a
+/// `denied` reason. Denials are sampled, as any peer can cause them.
+fn audit_decision(
+    audit: &Auditor,
+    route: &TcpRoute,
+    client_ip: IpAddr,
+    key_id: Option<Uuid>,
+    denied: Option<&str>,
+) {
+    let decision = match denied {
+        Some(_) => Decision::Deny,
+        None => Decision::Allow,
+    };
+    let event = AuditEvent::new(AuditAction::ProxyConnection, "proxy", decision)
+        .with_key(key_id)
+        .with_ip(Some(client_ip))
+        .with_route(&route.config.name);
+    match denied {
+        Some(reason) => audit.record_sampled(event.with_reason(reason)),
+        None => audit.record(event),
+    }
+}
+
+/// The client's address and the address it connected to. Peers listed in
+/// the route's `proxy_protocol.trusted_sources` must send a PROXY header,
+/// whose addresses are used unless it is a `LOCAL`/`UNKNOWN` header.
+async fn client_addrs(
+    inbound: &mut TcpStream,
+    peer: SocketAddr,
+    route: &RouteConfig,
+) -> Result<(SocketAddr, SocketAddr)> {
+    let local_addr = inbound.local_addr()?;
+    if !route.proxy_protocol.trusts(peer.ip()) {
+        return Ok((peer, local_addr));
+    }
+
+    let header = tokio::time::timeout(
+        proxy_protocol::HEADER_TIMEOUT,
+        proxy_protocol::read_header(inbound),
+    )
+    .await
+    .context("timed out waiting for PROXY header")??;
+    Ok((
+        header.source.unwrap_or(peer),
+        header.destination.unwrap_or(local_addr),
+    ))
 }
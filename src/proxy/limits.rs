@@ -0,0 +1,346 @@
+use crate::config::LimitsConfig;
+use std::collections::hash_map::Entry;
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio_util::sync::CancellationToken;
+
+// Clients tracked for `connections_per_minute_per_ip`. A client new to a
+// full map is rate limited until the sweep frees room.
+const MAX_BUCKETS: usize = 16_384;
+
+// A bucket refills completely within a minute, after which it is the same
+// as no bucket and can be dropped
+const SWEEP_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Connection counts and rates of one route. Counts are checked as TCP
+/// connections or UDP sessions are admitted, rates once a connection has
+/// been allowed.
+#[derive(Debug)]
+pub struct RouteLimits {
+    config: LimitsConfig,
+    state: Mutex<State>,
+}
+
+#[derive(Debug, Default)]
+struct State {
+    active: usize,
+    per_ip: HashMap<IpAddr, usize>,
+    rates: HashMap<IpAddr, Bucket>,
+}
+
+/// Token bucket refilling `connections_per_minute_per_ip` tokens a minute.
+/// IPv6 clients share a bucket per /64, which is usually one host.
+#[derive(Debug)]
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+/// Why a connection was turned away.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Rejection {
+    RouteFull,
+    TooManyFromIp,
+    RateLimited,
+}
+
+impl Rejection {
+    /// The limit exceeded, as a metric label
+    pub fn label(&self) -> &'static str {
+        match self {
+            Rejection::RouteFull => "max_connections",
+            Rejection::TooManyFromIp => "max_connections_per_ip",
+            Rejection::RateLimited => "connections_per_minute_per_ip",
+        }
+    }
+}
+
+impl std::fmt::Display for Rejection {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Rejection::RouteFull => write!(f, "route is at max_connections"),
+            Rejection::TooManyFromIp => write!(f, "client is at max_connections_per_ip"),
+            Rejection::RateLimited => write!(f, "client exceeded connections_per_minute_per_ip"),
+        }
+    }
+}
+
+/// Counts a connection against its route and client IP until dropped.
+#[derive(Debug)]
+pub struct LimitGuard {
+    limits: Arc<RouteLimits>,
+    ip: IpAddr,
+}
+
+impl Drop for LimitGuard {
+    fn drop(&mut self) {
+        let mut state = self.limits.lock();
+        state.active = state.active.saturating_sub(1);
+        if let Some(count) = state.per_ip.get_mut(&self.ip) {
+            *count -= 1;
+            if *count == 0 {
+                state.per_ip.remove(&self.ip);
+            }
+        }
+    }
+}
+
+impl RouteLimits {
+    pub fn new(config: LimitsConfig) -> Self {
+        Self {
+            config,
+            state: Mutex::new(State::default()),
+        }
+    }
+
+    /// Admit a connection from `ip` against the route and per-IP counts, or
+    /// say which it would exceed. A rejected connection does not count.
+    pub fn admit(self: &Arc<Self>, ip: IpAddr) -> Result<LimitGuard, Rejection> {
+        let ip = ip.to_canonical();
+        let mut state = self.lock();
+
+        if self
+            .config
+            .max_connections
+            .is_some_and(|max| state.active >= max as usize)
+        {
+            return Err(Rejection::RouteFull);
+        }
+        let from_ip = state.per_ip.get(&ip).copied().unwrap_or(0);
+        if self
+            .config
+            .max_connections_per_ip
+            .is_some_and(|max| from_ip >= max as usize)
+        {
+            return Err(Rejection::TooManyFromIp);
+        }
+
+        state.active += 1;
+        *state.per_ip.entry(ip).or_insert(0) += 1;
+        Ok(LimitGuard {
+            limits: Arc::clone(self),
+            ip,
+        })
+    }
+
+    /// Take one of `ip`'s `connections_per_minute_per_ip`. Only checked for
+    /// allowed clients, so peers that are turned away cannot fill the map.
+    pub fn check_rate(&self, ip: IpAddr) -> Result<(), Rejection> {
+        let Some(per_minute) = self.config.connections_per_minute_per_ip else {
+            return Ok(());
+        };
+        let capacity = per_minute as f64;
+        let now = Instant::now();
+        let mut state = self.lock();
+        let full = state.rates.len() >= MAX_BUCKETS;
+        let bucket = match state.rates.entry(rate_key(ip)) {
+            Entry::Occupied(entry) => entry.into_mut(),
+            Entry::Vacant(_) if full => return Err(Rejection::RateLimited),
+            Entry::Vacant(entry) => entry.insert(Bucket {
+                tokens: capacity,
+                updated: now,
+            }),
+        };
+        bucket.tokens = (bucket.tokens + refill(bucket, now, capacity)).min(capacity);
+        bucket.updated = now;
+        if bucket.tokens < 1.0 {
+            return Err(Rejection::RateLimited);
+        }
+        bucket.tokens -= 1.0;
+        Ok(())
+    }
+
+    /// Drop rate buckets every `SWEEP_INTERVAL` until `stop` is cancelled.
+    pub fn spawn_sweeper(self: &Arc<Self>, stop: CancellationToken) {
+        if self.config.connections_per_minute_per_ip.is_none() {
+            return;
+        }
+        let limits = Arc::clone(self);
+        tokio::spawn(async move {
+            let mut ticker = tokio::time::interval(SWEEP_INTERVAL);
+            loop {
+                tokio::select! {
+                    _ = ticker.tick() => limits.sweep(Instant::now()),
+                    _ = stop.cancelled() => return,
+                }
+            }
+        });
+    }
+
+    /// Forget buckets that have refilled by `now`.
+    fn sweep(&self, now: Instant) {
+        let Some(per_minute) = self.config.connections_per_minute_per_ip else {
+            return;
+        };
+        let capacity = per_minute as f64;
+        self.lock()
+            .rates
+            .retain(|_, bucket| bucket.tokens + refill(bucket, now, capacity) < capacity);
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, State> {
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+/// The bucket `ip` is counted in: its /64 for IPv6, itself for IPv4.
+fn rate_key(ip: IpAddr) -> IpAddr {
+    match ip.to_canonical() {
+        IpAddr::V6(v6) => IpAddr::V6((u128::from(v6) & !(u64::MAX as u128)).into()),
+        v4 => v4,
+    }
+}
+
+/// Tokens earned by `bucket` since it was last updated.
+fn refill(bucket: &Bucket, now: Instant, per_minute: f64) -> f64 {
+    now.duration_since(bucket.updated).as_secs_f64() * per_minute / 60.0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn limits(config: LimitsConfig) -> Arc<RouteLimits> {
+        Arc::new(RouteLimits::new(config))
+    }
+
+    fn rate_limited(per_minute: u32) -> Arc<RouteLimits> {
+        limits(LimitsConfig {
+            connections_per_minute_per_ip: Some(per_minute),
+            ..LimitsConfig::default()
+        })
+    }
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn route_max_counts_open_connections() {
+        let limits = limits(LimitsConfig {
+            max_connections: Some(2),
+            ..LimitsConfig::default()
+        });
+        let first = limits.admit(ip("192.0.2.1")).unwrap();
+        let _second = limits.admit(ip("192.0.2.2")).unwrap();
+        assert_eq!(
+            limits.admit(ip("192.0.2.3")).unwrap_err(),
+            Rejection::RouteFull
+        );
+        drop(first);
+        assert!(limits.admit(ip("192.0.2.3")).is_ok());
+    }
+
+    #[test]
+    fn per_ip_max_counts_each_client() {
+        let limits = limits(LimitsConfig {
+            max_connections_per_ip: Some(1),
+            ..LimitsConfig::default()
+        });
+        let first = limits.admit(ip("192.0.2.1")).unwrap();
+        assert_eq!(
+            limits.admit(ip("::ffff:192.0.2.1")).unwrap_err(),
+            Rejection::TooManyFromIp
+        );
+        assert!(limits.admit(ip("192.0.2.2")).is_ok());
+        drop(first);
+        assert!(limits.admit(ip("192.0.2.1")).is_ok());
+        assert!(!limits.lock().per_ip.contains_key(&ip("192.0.2.1")));
+    }
+
+    #[test]
+    fn rate_allows_a_burst_then_limits() {
+        let limits = rate_limited(3);
+        for _ in 0..3 {
+            limits.check_rate(ip("192.0.2.1")).unwrap();
+        }
+        assert_eq!(
+            limits.check_rate(ip("192.0.2.1")).unwrap_err(),
+            Rejection::RateLimited
+        );
+        assert!(limits.check_rate(ip("192.0.2.2")).is_ok());
+        // Admission does not touch the rate
+        assert!(limits.admit(ip("192.0.2.1")).is_ok());
+    }
+
+    #[test]
+    fn rate_is_shared_by_an_ipv6_64() {
+        let limits = rate_limited(1);
+        limits.check_rate(ip("2001:db8:0:1::1")).unwrap();
+        assert!(limits.check_rate(ip("2001:db8:0:1::ffff")).is_err());
+        assert!(limits.check_rate(ip("2001:db8:0:2::1")).is_ok());
+        assert_eq!(limits.lock().rates.len(), 2);
+    }
+
+    #[test]
+    fn rate_refills_over_time() {
+        let limits = rate_limited(60);
+        limits.lock().rates.insert(
+            ip("192.0.2.1"),
+            Bucket {
+                tokens: 0.0,
+                updated: Instant::now() - Duration::from_millis(1500),
+            },
+        );
+        // 60 a minute is one a second, so 1.5s earns one token
+        assert!(limits.check_rate(ip("192.0.2.1")).is_ok());
+        assert!(limits.check_rate(ip("192.0.2.1")).is_err());
+    }
+
+    #[test]
+    fn refill_is_capped_at_capacity() {
+        let limits = rate_limited(2);
+        limits.lock().rates.insert(
+            ip("192.0.2.1"),
+            Bucket {
+                tokens: 0.0,
+                updated: Instant::now() - Duration::from_secs(3600),
+            },
+        );
+        assert!(limits.check_rate(ip("192.0.2.1")).is_ok());
+        assert!(limits.check_rate(ip("192.0.2.1")).is_ok());
+        assert!(limits.check_rate(ip("192.0.2.1")).is_err());
+    }
+
+    #[test]
+    fn new_clients_are_limited_once_the_map_is_full() {
+        let limits = rate_limited(10);
+        {
+            let mut state = limits.lock();
+            for n in 0..MAX_BUCKETS as u32 {
+                state.rates.insert(
+                    IpAddr::V4(n.into()),
+                    Bucket {
+                        tokens: 5.0,
+                        updated: Instant::now(),
+                    },
+                );
+            }
+        }
+        assert!(limits.check_rate(IpAddr::V4(0.into())).is_ok());
+        assert_eq!(
+            limits.check_rate(ip("203.0.113.1")).unwrap_err(),
+            Rejection::RateLimited
+        );
+    }
+
+    #[test]
+    fn sweep_drops_refilled_buckets() {
+        let limits = rate_limited(60);
+        limits.check_rate(ip("192.0.2.1")).unwrap();
+        limits.check_rate(ip("192.0.2.2")).unwrap();
+        let now = Instant::now();
+        limits
+            .lock()
+            .rates
+            .get_mut(&ip("192.0.2.2"))
+            .unwrap()
+            .updated = now - Duration::from_secs(120);
+        limits.sweep(now);
+        let state = limits.lock();
+        assert!(state.rates.contains_key(&ip("192.0.2.1")));
+        assert!(!state.rates.contains_key(&ip("192.0.2.2")));
+    }
+}
@@ -0,0 +1,364 @@
+use crate::config::LimitsConfig;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::task::{Context as TaskContext, Poll};
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadBuf};
+
+const BUFFER_SIZE: usize = 16 * 1024;
+
+/// Which end of a proxied connection something happened on
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Side {
+    Client,
+    Upstream,
+}
+
+impl std::fmt::Display for Side {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Side::Client => write!(f, "client"),
+            Side::Upstream => write!(f, "upstream"),
+        }
+    }
+}
+
+/// Why a relayed connection ended
+#[derive(Debug)]
+pub enum CloseReason {
+    /// Both sides finished sending; `by` was the first to close.
+    Closed {
+        by: Side,
+    },
+    Error {
+        side: Side,
+        error: std::io::Error,
+    },
+    IdleTimeout,
+    MaxLifetime,
+}
+
+impl std::fmt::Display for CloseReason {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            CloseReason::Closed { by } => write!(f, "{} closed", by),
+            CloseReason::Error { side, error } => write!(f, "{} error: {}", side, error),
+            CloseReason::IdleTimeout => write!(f, "idle timeout"),
+            CloseReason::MaxLifetime => write!(f, "max lifetime reached"),
+        }
+    }
+}
+
+/// What passed through a relayed connection
+#[derive(Debug)]
+pub struct Relayed {
+    /// Bytes received from the client and sent upstream
+    pub bytes_in: u64,
+    /// Bytes received from the upstream and sent to the client
+    pub bytes_out: u64,
+    pub reason: CloseReason,
+}
+
+/// When data last arrived from either side of a connection
+struct IdleClock {
+    start: Instant,
+    last_ms: AtomicU64, // since `start`
+}
+
+impl IdleClock {
+    fn new() -> Self {
+        Self {
+            start: Instant::now(),
+            last_ms: AtomicU64::new(0),
+        }
+    }
+
+    fn touch(&self) {
+        let now = self.start.elapsed().as_millis() as u64;
+        self.last_ms.store(now, Ordering::Relaxed);
+    }
+
+    fn idle_for(&self) -> Duration {
+        let last = Duration::from_millis(self.last_ms.load(Ordering::Relaxed));
+        self.start.elapsed().saturating_sub(last)
+    }
+}
+
+/// A stream that touches an `IdleClock` whenever it reads data
+struct Activity<S> {
+    inner: S,
+    clock: Arc<IdleClock>,
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for Activity<S> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut TaskContext<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        let before = buf.filled().len();
+        let result = Pin::new(&mut self.inner).poll_read(cx, buf);
+        if matches!(result, Poll::Ready(Ok(()))) && buf.filled().len() > before {
+            self.clock.touch();
+        }
+        result
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for Activity<S> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut TaskContext<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        Pin::new(&mut self.inner).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut TaskContext<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(
+        mut self: Pin<&mut Self>,
+        cx: &mut TaskContext<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+/// Copy data both ways, closing each direction's write side once its read
+/// side reaches EOF, until both directions are done. An error on either
+/// side, or the route's idle timeout or max lifetime, ends the connection.
+pub async fn relay<C, U>(client: C, upstream: U, limits: &LimitsConfig) -> Relayed
+where
+    C: AsyncRead + AsyncWrite + Unpin,
+    U: AsyncRead + AsyncWrite + Unpin,
+{
+    let clock = Arc::new(IdleClock::new());
+    let client = Activity {
+        inner: client,
+        clock: Arc::clone(&clock),
+    };
+    let upstream = Activity {
+        inner: upstream,
+        clock: Arc::clone(&clock),
+    };
+    let (mut client_read, mut client_write) = tokio::io::split(client);
+    let (mut upstream_read, mut upstream_write) = tokio::io::split(upstream);
+
+    let mut bytes_in = 0;
+    let mut bytes_out = 0;
+    let idle = limits.idle_timeout_secs.map(Duration::from_secs);
+    let lifetime = limits.max_lifetime_secs.map(Duration::from_secs);
+    let reason = {
+        let client_to_upstream = pipe(
+            &mut client_read,
+            &mut upstream_write,
+            Side::Client,
+            &mut bytes_in,
+        );
+        let upstream_to_client = pipe(
+            &mut upstream_read,
+            &mut client_write,
+            Side::Upstream,
+            &mut bytes_out,
+        );
+        tokio::select! {
+            result = async { tokio::try_join!(client_to_upstream, upstream_to_client) } => {
+                match result {
+                    Ok((client_done, upstream_done)) => CloseReason::Closed {
+                        by: if client_done <= upstream_done {
+                            Side::Client
+                        } else {
+                            Side::Upstream
+                        },
+                    },
+                    Err(reason) => reason,
+                }
+            }
+            _ = idle_expiry(&clock, idle) => CloseReason::IdleTimeout,
+            _ = expiry(lifetime) => CloseReason::MaxLifetime,
+        }
+    };
+
+    Relayed {
+        bytes_in,
+        bytes_out,
+        reason,
+    }
+}
+
+/// Copy `reader` into `writer` until EOF, then shut `writer` down so the
+/// other end sees the close. Returns when `from` finished sending.
+async fn pipe<R, W>(
+    reader: &mut R,
+    writer: &mut W,
+    from: Side,
+    bytes: &mut u64,
+) -> Result<Instant, CloseReason>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let to = match from {
+        Side::Client => Side::Upstream,
+        Side::Upstream => Side::Client,
+    };
+    let failed = |side, error| CloseReason::Error { side, error };
+    let mut buf = vec![0; BUFFER_SIZE];
+    loop {
+        let n = reader.read(&mut buf).await.map_err(|e| failed(from, e))?;
+        if n == 0 {
+            let closed = Instant::now();
+            match writer.shutdown().await {
+                // The other end may already be gone, which is no matter
+                // once there is nothing left to send it
+                Err(e) if e.kind() != std::io::ErrorKind::NotConnected => {
+                    return Err(failed(to, e));
+                }
+                _ => return Ok(closed),
+            }
+        }
+        writer
+            .write_all(&buf[..n])
+            .await
+            .map_err(|e| failed(to, e))?;
+        writer.flush().await.map_err(|e| failed(to, e))?;
+        *bytes += n as u64;
+    }
+}
+
+/// Resolve once `clock` has seen no data for `idle`, or never if unset.
+async fn idle_expiry(clock: &IdleClock, idle: Option<Duration>) {
+    let Some(idle) = idle else {
+        return std::future::pending().await;
+    };
+    loop {
+        let idle_for = clock.idle_for();
+        if idle_for >= idle {
+            return;
+        }
+        tokio::time::sleep(idle - idle_for).await;
+    }
+}
+
+/// Resolve after `after`, or never if unset.
+async fn expiry(after: Option<Duration>) {
+    match after {
+        Some(after) => tokio::time::sleep(after).await,
+        None => std::future::pending().await,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{duplex, DuplexStream};
+    use tokio::task::JoinHandle;
+
+    /// Relay between two in-memory streams, returning the client's and the
+    /// upstream's ends.
+    fn start(limits: LimitsConfig) -> (DuplexStream, DuplexStream, JoinHandle<Relayed>) {
+        let (client, client_end) = duplex(1024);
+        let (upstream, upstream_end) = duplex(1024);
+        let task = tokio::spawn(async move { relay(client, upstream, &limits).await });
+        (client_end, upstream_end, task)
+    }
+
+    async fn read_to_end(stream: &mut DuplexStream) -> Vec<u8> {
+        let mut received = Vec::new();
+        tokio::time::timeout(Duration::from_secs(2), stream.read_to_end(&mut received))
+            .await
+            .expect("stream was not closed")
+            .unwrap();
+        received
+    }
+
+    #[tokio::test]
+    async fn half_closes_are_passed_on() {
+        let (mut client, mut upstream, task) = start(LimitsConfig::default());
+
+        client.write_all(b"request").await.unwrap();
+        client.shutdown().await.unwrap();
+        assert_eq!(read_to_end(&mut upstream).await, b"request");
+
+        // The other direction stays open after the client's half-close
+        upstream.write_all(b"a response").await.unwrap();
+        upstream.shutdown().await.unwrap();
+        assert_eq!(read_to_end(&mut client).await, b"a response");
+
+        let relayed = task.await.unwrap();
+        assert!(matches!(
+            relayed.reason,
+            CloseReason::Closed { by: Side::Client }
+        ));
+        assert_eq!(relayed.bytes_in, 7);
+        assert_eq!(relayed.bytes_out, 10);
+    }
+
+    #[tokio::test]
+    async fn the_first_side_to_close_is_reported() {
+        let (mut client, mut upstream, task) = start(LimitsConfig::default());
+
+        upstream.write_all(b"banner").await.unwrap();
+        upstream.shutdown().await.unwrap();
+        assert_eq!(read_to_end(&mut client).await, b"banner");
+        client.shutdown().await.unwrap();
+
+        let relayed = task.await.unwrap();
+        assert!(matches!(
+            relayed.reason,
+            CloseReason::Closed { by: Side::Upstream }
+        ));
+        assert_eq!(relayed.bytes_in, 0);
+        assert_eq!(relayed.bytes_out, 6);
+    }
+
+    #[tokio::test]
+    async fn idle_connections_are_closed_both_ways() {
+        let limits = LimitsConfig {
+            idle_timeout_secs: Some(1),
+            ..LimitsConfig::default()
+        };
+        let (mut client, mut upstream, task) = start(limits);
+
+        // Traffic in either direction keeps the connection open
+        tokio::time::sleep(Duration::from_millis(600)).await;
+        upstream.write_all(b"ping").await.unwrap();
+        tokio::time::sleep(Duration::from_millis(600)).await;
+        assert!(!task.is_finished());
+
+        let relayed = task.await.unwrap();
+        assert!(matches!(relayed.reason, CloseReason::IdleTimeout));
+        assert_eq!(relayed.bytes_out, 4);
+        assert_eq!(read_to_end(&mut client).await, b"ping");
+        assert!(read_to_end(&mut upstream).await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn connections_are_closed_at_their_max_lifetime() {
+        let limits = LimitsConfig {
+            max_lifetime_secs: Some(1),
+            ..LimitsConfig::default()
+        };
+        let (mut client, mut upstream, task) = start(limits);
+
+        // Keep it busy until the relay gives up on it
+        let started = Instant::now();
+        let mut sent = 0;
+        let mut buf = [0u8; 4];
+        while client.write_all(b"busy").await.is_ok() && upstream.read_exact(&mut buf).await.is_ok()
+        {
+            assert!(started.elapsed() < Duration::from_secs(2));
+            sent += buf.len() as u64;
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        }
+
+        let relayed = task.await.unwrap();
+        assert!(matches!(relayed.reason, CloseReason::MaxLifetime));
+        assert_eq!(relayed.bytes_in, sent);
+        assert!(read_to_end(&mut client).await.is_empty());
+        assert!(read_to_end(&mut upstream).await.is_empty());
+    }
+}
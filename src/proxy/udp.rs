@@ -0,0 +1,483 @@
+use super::limits::{LimitGuard, RouteLimits};
+use crate::allowlist::Allowlist;
+use crate::audit::{AuditAction, AuditEvent, Auditor, Decision};
+use crate::config::RouteConfig;
+use crate::metrics::{metrics, ActiveGuard};
+use crate::upstream::{BackendGuard, UpstreamPool};
+use anyhow::Result;
+use chrono::{DateTime, Utc};
+use prometheus::IntCounter;
+use std::collections::HashMap;
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::sync::atomic::Ordering;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::net::UdpSocket;
+use tokio::sync::watch;
+use tokio::task::JoinSet;
+use tokio_util::sync::CancellationToken;
+use tracing::{error, field, info, info_span, warn, Instrument, Span};
+use uuid::Uuid;
+
+// Largest possible UDP payload
+const MAX_DATAGRAM: usize = 65535;
+
+type Sessions = Arc<Mutex<HashMap<SocketAddr, Arc<Session>>>>;
+
+/// A client's mapping to an upstream. Each session has its own upstream
+/// socket so replies can be told apart and sent back to the right client.
+struct Session {
+    upstream: UdpSocket,
+    allowed: Mutex<Allowed>,
+    last_active: Mutex<Instant>,
+    _backend: BackendGuard,
+    _limit: LimitGuard,
+    bytes_in: IntCounter,
+    bytes_out: IntCounter,
+    _active: ActiveGuard,
+    span: Span,
+}
+
+/// The allowlist decision a session relays on. It is reused until the
+/// allowlist or the route changes, or the entry it was granted by expires.
+struct Allowed {
+    generation: u64,
+    route: Arc<RouteConfig>,
+    expires_at: Option<DateTime<Utc>>,
+}
+
+impl Session {
+    fn allowed_by(&self, generation: u64, route: &Arc<RouteConfig>) -> bool {
+        let allowed = self.allowed.lock().unwrap_or_else(|e| e.into_inner());
+        allowed.generation == generation
+            && Arc::ptr_eq(&allowed.route, route)
+            && allowed
+                .expires_at
+                .is_none_or(|expires_at| expires_at > Utc::now())
+    }
+
+    fn allow(&self, allowed: Allowed) {
+        *self.allowed.lock().unwrap_or_else(|e| e.into_inner()) = allowed;
+    }
+
+    fn touch(&self) {
+        *self.last_active.lock().unwrap_or_else(|e| e.into_inner()) = Instant::now();
+    }
+
+    /// Send a client's datagram on to the upstream.
+    async fn relay(&self, datagram: &[u8]) {
+        self.touch();
+        self.bytes_in.inc_by(datagram.len() as u64);
+        if let Err(e) = self.upstream.send(datagram).await {
+            self.span
+                .in_scope(|| warn!("failed to relay datagram upstream: {}", e));
+        }
+    }
+
+    fn idle_for(&self) -> Duration {
+        self.last_active
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .elapsed()
+    }
+}
+
+/// A UDP route ready to relay datagrams
+pub struct UdpRoute {
+    pub config: Arc<RouteConfig>,
+    upstreams: Arc<UpstreamPool>,
+    limits: Arc<RouteLimits>,
+}
+
+impl UdpRoute {
+    pub fn new(config: RouteConfig) -> Result<Self> {
+        Ok(Self {
+            upstreams: Arc::new(UpstreamPool::new(&config)?),
+            limits: Arc::new(RouteLimits::new(config.limits.clone())),
+            config: Arc::new(config),
+        })
+    }
+}
+
+/// Relay datagrams from allowed clients to the route's upstreams until
+/// `stop` is cancelled. A session's allowlist decision is checked again
+/// once the allowlist or route changes, so a revoked client is cut off
+/// mid-session. When the route is replaced, existing sessions keep their
+/// upstream and new ones use the new route.
+pub async fn run_route(
+    socket: UdpSocket,
+    routes: watch::Receiver<Arc<UdpRoute>>,
+    allowlist: Arc<Allowlist>,
+    audit: Arc<Auditor>,
+    stop: CancellationToken,
+) {
+    let socket = Arc::new(socket);
+    let sessions: Sessions = Arc::default();
+    // Reply relays, aborted when the route stops
+    let mut replies = JoinSet::new();
+    let mut buf = vec![0u8; MAX_DATAGRAM];
+
+    loop {
+        // Sessions have no connection to drain; dropping them ends relaying
+        let (len, client) = tokio::select! {
+            received = socket.recv_from(&mut buf) => match received {
+                Ok(received) => received,
+                Err(e) => {
+                    error!("UDP proxy listener error: {}", e);
+                    return;
+                }
+            },
+            Some(_) = replies.join_next() => continue,
+            _ = stop.cancelled() => {
+                replies.shutdown().await;
+                lock(&sessions).clear();
+                return;
+            }
+        };
+        let client_ip = client.ip();
+        let current = Arc::clone(&routes.borrow());
+        let route = &current.config;
+
+        // Read before the lookup, so a change made during it is noticed
+        let generation = allowlist.generation();
+        let existing = lock(&sessions).get(&client).cloned();
+        if let Some(session) = &existing
+            && session.allowed_by(generation, route)
+        {
+            session.relay(&buf[..len]).await;
+            continue;
+        }
+
+        let entry = match allowlist.lookup(client_ip, route).await {
+            Ok(Some(entry)) => entry,
+            Ok(None) => {
+                let ended = lock(&sessions).remove(&client).is_some();
+                let reason = if ended {
+                    info!(client = %client, route = %route.name, "UDP session ended: no longer allowed");
+                    "no_longer_allowed"
+                } else {
+                    info!(client = %client, route = %route.name, reason = "not_allowed", "datagram rejected");
+                    metrics().connection_rejected(&route.name, "not_allowed");
+                    "not_allowed"
+                };
+                let event = AuditEvent::new(AuditAction::ProxyConnection, "proxy", Decision::Deny)
+                    .with_ip(Some(client_ip))
+                    .with_route(&route.name)
+                    .with_reason(reason);
+                audit.record_sampled(event);
+                continue;
+            }
+            Err(e) => {
+                error!(client = %client, route = %route.name, "validation error: {}", e);
+                continue;
+            }
+        };
+        let key_id = entry.key_id;
+        let allowed = Allowed {
+            generation,
+            route: Arc::clone(route),
+            expires_at: entry.expires_at,
+        };
+
+        let session = match existing {
+            Some(session) => {
+                session.allow(allowed);
+                session
+            }
+            None => {
+                let limit = match current.limits.admit(client_ip) {
+                    Ok(guard) => guard,
+                    Err(rejection) => {
+                        info!(client = %client, route = %route.name, reason = rejection.label(), "datagram rejected: {}", rejection);
+                        metrics().connection_rejected(&route.name, rejection.label());
+                        let event =
+                            AuditEvent::new(AuditAction::ProxyConnection, "proxy", Decision::Deny)
+                                .with_ip(Some(client_ip))
+                                .with_route(&route.name)
+                                .with_reason(rejection.label());
+                        audit.record_sampled(event);
+                        continue;
+                    }
+                };
+                let opened = open_session(
+                    &current.upstreams,
+                    &route.name,
+                    client,
+                    key_id,
+                    allowed,
+                    limit,
+                );
+                match opened.await {
+                    Ok(session) => {
+                        metrics().connection_allowed(&route.name);
+                        let event =
+                            AuditEvent::new(AuditAction::ProxyConnection, "proxy", Decision::Allow)
+                                .with_key(key_id)
+                                .with_ip(Some(client_ip))
+                                .with_route(&route.name);
+                        audit.record(event);
+                        session.span.in_scope(|| info!("UDP session started"));
+                        let session = Arc::new(session);
+                        lock(&sessions).insert(client, Arc::clone(&session));
+                        let span = session.span.clone();
+                        replies.spawn(
+                            relay_replies(
+                                Arc::clone(&socket),
+                                Arc::clone(&sessions),
+                                Arc::clone(&session),
+                                client,
+                                Duration::from_secs(route.session_idle_secs),
+                            )
+                            .instrument(span),
+                        );
+                        session
+                    }
+                    Err(e) => {
+                        warn!(client = %client, route = %route.name, "dropping datagram: {:#}", e);
+                        continue;
+                    }
+                }
+            }
+        };
+
+        session.relay(&buf[..len]).await;
+    }
+}
+
+async fn open_session(
+    upstreams: &UpstreamPool,
+    route: &str,
+    client: SocketAddr,
+    key_id: Option<Uuid>,
+    allowed: Allowed,
+    limit: LimitGuard,
+) -> Result<Session> {
+    let backend = upstreams
+        .pick(client.ip())
+        .ok_or_else(|| anyhow::anyhow!("no upstreams configured"))?;
+    let bind_addr = match backend.addr() {
+        SocketAddr::V4(_) => SocketAddr::new(Ipv4Addr::UNSPECIFIED.into(), 0),
+        SocketAddr::V6(_) => SocketAddr::new(Ipv6Addr::UNSPECIFIED.into(), 0),
+    };
+    let upstream = UdpSocket::bind(bind_addr).await?;
+    upstream.connect(backend.addr()).await?;
+    let span = info_span!(
+        "udp_session",
+        id = super::NEXT_CONNECTION_ID.fetch_add(1, Ordering::Relaxed),
+        client = %client,
+        route,
+        upstream = %backend.addr(),
+        key_id = field::Empty,
+    );
+    if let Some(key_id) = key_id {
+        span.record("key_id", field::display(key_id));
+    }
+    Ok(Session {
+        upstream,
+        allowed: Mutex::new(allowed),
+        last_active: Mutex::new(Instant::now()),
+        _backend: backend,
+        _limit: limit,
+        bytes_in: metrics().bytes.with_label_values(&[route, "in"]),
+        bytes_out: metrics().bytes.with_label_values(&[route, "out"]),
+        _active: metrics().connection_opened(route),
+        span,
+    })
+}
+
+/// Send upstream replies back to `client` until the session has seen no
+/// traffic in either direction for `idle`, or has been ended elsewhere.
+async fn relay_replies(
+    socket: Arc<UdpSocket>,
+    sessions: Sessions,
+    session: Arc<Session>,
+    client: SocketAddr,
+    idle: Duration,
+) {
+    let mut buf = vec![0u8; MAX_DATAGRAM];
+    loop {
+        let remaining = idle.saturating_sub(session.idle_for());
+        match tokio::time::timeout(remaining, session.upstream.recv(&mut buf)).await {
+            Ok(Ok(len)) => {
+                // A session removed from the map has been ended; stop relaying
+                if !is_current(&sessions, client, &session) {
+                    return;
+                }
+                session.touch();
+                session.bytes_out.inc_by(len as u64);
+                if let Err(e) = socket.send_to(&buf[..len], client).await {
+                    warn!("failed to relay datagram to client: {}", e);
+                }
+            }
+            // e.g. ICMP port unreachable from the upstream; keep the session
+            Ok(Err(e)) => {
+                warn!("upstream error: {}", e);
+                if !is_current(&sessions, client, &session) {
+                    return;
+                }
+                tokio::time::sleep(Duration::from_millis(100)).await;
+            }
+            Err(_) if session.idle_for() >= idle || !is_current(&sessions, client, &session) => {
+                let mut sessions = lock(&sessions);
+                if sessions
+                    .get(&client)
+                    .is_some_and(|current| Arc::ptr_eq(current, &session))
+                {
+                    sessions.remove(&client);
+                    info!("UDP session expired");
+                }
+                return;
+            }
+            // The client sent something while we waited; wait out the rest
+            Err(_) => {}
+        }
+    }
+}
+
+fn is_current(sessions: &Sessions, client: SocketAddr, session: &Arc<Session>) -> bool {
+    lock(sessions)
+        .get(&client)
+        .is_some_and(|current| Arc::ptr_eq(current, session))
+}
+
+fn lock(sessions: &Sessions) -> std::sync::MutexGuard<'_, HashMap<SocketAddr, Arc<Session>>> {
+    sessions.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{AllowlistConfig, AuditConfig, RouteProtocol};
+    use crate::storage::{SqliteStorage, StorageBackend};
+    use ipnet::IpNet;
+
+    struct Harness {
+        storage: Arc<dyn StorageBackend>,
+        upstream: UdpSocket,
+        client: UdpSocket,
+        stop: CancellationToken,
+        task: tokio::task::JoinHandle<()>,
+        _routes: watch::Sender<Arc<UdpRoute>>,
+    }
+
+    fn loopback() -> IpNet {
+        "127.0.0.1/32".parse().unwrap()
+    }
+
+    /// A UDP route from a client socket to an upstream socket, both on
+    /// loopback, with `allowed` in the allowlist.
+    async fn start(allowed: Option<IpNet>, session_idle_secs: u64) -> Harness {
+        let storage: Arc<dyn StorageBackend> = Arc::new(SqliteStorage::in_memory().await.unwrap());
+        if let Some(network) = allowed {
+            storage.allow_network(network, None, None).await.unwrap();
+        }
+        let allowlist = Allowlist::start(storage.clone(), AllowlistConfig::default())
+            .await
+            .unwrap();
+        let audit = AuditConfig {
+            storage: false,
+            file: None,
+        };
+        let audit = Auditor::start(&audit, storage.clone()).await.unwrap();
+
+        let upstream = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let upstream_addr = upstream.local_addr().unwrap().to_string();
+        let mut route = RouteConfig::new("udp", "127.0.0.1:0", &upstream_addr);
+        route.protocol = RouteProtocol::Udp;
+        route.session_idle_secs = session_idle_secs;
+        let (routes, receiver) = watch::channel(Arc::new(UdpRoute::new(route).unwrap()));
+
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        client.connect(socket.local_addr().unwrap()).await.unwrap();
+        let stop = CancellationToken::new();
+        let task = tokio::spawn(run_route(socket, receiver, allowlist, audit, stop.clone()));
+        Harness {
+            storage,
+            upstream,
+            client,
+            stop,
+            task,
+            _routes: routes,
+        }
+    }
+
+    /// The next datagram on `socket` and where it came from, if one arrives
+    /// within `wait`.
+    async fn receive(socket: &UdpSocket, wait: Duration) -> Option<(Vec<u8>, SocketAddr)> {
+        let mut buf = vec![0u8; MAX_DATAGRAM];
+        let (len, from) = tokio::time::timeout(wait, socket.recv_from(&mut buf))
+            .await
+            .ok()?
+            .unwrap();
+        buf.truncate(len);
+        Some((buf, from))
+    }
+
+    const ARRIVES: Duration = Duration::from_secs(2);
+    const DROPPED: Duration = Duration::from_millis(300);
+
+    #[tokio::test]
+    async fn sessions_relay_both_ways_until_idle() {
+        let harness = start(Some(loopback()), 1).await;
+
+        harness.client.send(b"ping").await.unwrap();
+        let (datagram, session) = receive(&harness.upstream, ARRIVES).await.unwrap();
+        assert_eq!(datagram, b"ping");
+        harness.upstream.send_to(b"pong", session).await.unwrap();
+        let (datagram, _) = receive(&harness.client, ARRIVES).await.unwrap();
+        assert_eq!(datagram, b"pong");
+
+        // Traffic within the idle timeout stays on the same session
+        harness.client.send(b"again").await.unwrap();
+        let (_, from) = receive(&harness.upstream, ARRIVES).await.unwrap();
+        assert_eq!(from, session);
+
+        // Once idle it expires, and replies to it are no longer relayed
+        tokio::time::sleep(Duration::from_millis(1500)).await;
+        harness.upstream.send_to(b"late", session).await.unwrap();
+        assert!(receive(&harness.client, DROPPED).await.is_none());
+        harness.client.send(b"back").await.unwrap();
+        let (_, from) = receive(&harness.upstream, ARRIVES).await.unwrap();
+        assert_ne!(from, session);
+    }
+
+    #[tokio::test]
+    async fn unlisted_clients_get_no_session() {
+        let harness = start(None, 60).await;
+
+        harness.client.send(b"ping").await.unwrap();
+        assert!(receive(&harness.upstream, DROPPED).await.is_none());
+    }
+
+    #[tokio::test]
+    async fn removed_clients_are_cut_off_mid_session() {
+        let harness = start(Some(loopback()), 60).await;
+        harness.client.send(b"ping").await.unwrap();
+        let (_, session) = receive(&harness.upstream, ARRIVES).await.unwrap();
+
+        assert!(harness.storage.delete_host(loopback()).await.unwrap());
+        // Let the allowlist apply the change notification
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        harness.client.send(b"after").await.unwrap();
+        assert!(receive(&harness.upstream, DROPPED).await.is_none());
+        harness.upstream.send_to(b"pong", session).await.unwrap();
+        assert!(receive(&harness.client, DROPPED).await.is_none());
+    }
+
+    #[tokio::test]
+    async fn stopping_the_route_ends_its_sessions() {
+        let harness = start(Some(loopback()), 60).await;
+        harness.client.send(b"ping").await.unwrap();
+        let (_, session) = receive(&harness.upstream, ARRIVES).await.unwrap();
+
+        harness.stop.cancel();
+        tokio::time::timeout(ARRIVES, harness.task)
+            .await
+            .unwrap()
+            .unwrap();
+        // With its reply relay aborted, the session's upstream socket is
+        // closed and its port free again
+        std::net::UdpSocket::bind((Ipv4Addr::UNSPECIFIED, session.port())).unwrap();
+    }
+}
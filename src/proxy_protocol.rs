@@ -0,0 +1,302 @@
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+/// How long a trusted peer has to send its PROXY header
+pub const HEADER_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(5);
+
+const V2_SIGNATURE: [u8; 12] = *b"\r\n\r\n\0\r\nQUIT\n";
+// "PROXY UNKNOWN ffff:...:ffff ffff:...:ffff 65535 65535\r\n"
+const V1_MAX_LEN: usize = 107;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Version {
+    #[serde(rename = "v1")]
+    V1,
+    #[serde(rename = "v2")]
+    V2,
+}
+
+/// Addresses carried by a PROXY header. Both are `None` for `LOCAL` (v2) or
+/// `UNKNOWN` (v1) headers, e.g. a load balancer's own health checks.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Header {
+    pub source: Option<SocketAddr>,
+    pub destination: Option<SocketAddr>,
+}
+
+/// Read a v1 or v2 PROXY header from the start of `stream`, consuming
+/// exactly the header bytes.
+pub async fn read_header<R: AsyncRead + Unpin>(stream: &mut R) -> Result<Header> {
+    let mut prefix = [0u8; 5];
+    stream.read_exact(&mut prefix).await?;
+    if &prefix == b"PROXY" {
+        read_v1(stream).await
+    } else if prefix == V2_SIGNATURE[..5] {
+        read_v2(stream, prefix).await
+    } else {
+        anyhow::bail!("connection did not start with a PROXY header")
+    }
+}
+
+async fn read_v1<R: AsyncRead + Unpin>(stream: &mut R) -> Result<Header> {
+    let mut line = b"PROXY".to_vec();
+    while !line.ends_with(b"\r\n") {
+        if line.len() >= V1_MAX_LEN {
+            anyhow::bail!("PROXY v1 header is too long");
+        }
+        line.push(stream.read_u8().await?);
+    }
+    let line =
+        std::str::from_utf8(&line[..line.len() - 2]).context("PROXY v1 header is not ASCII")?;
+
+    let parts: Vec<&str> = line.split(' ').collect();
+    match parts.as_slice() {
+        ["PROXY", "UNKNOWN", ..] => Ok(Header {
+            source: None,
+            destination: None,
+        }),
+        ["PROXY", proto @ ("TCP4" | "TCP6"), src, dst, sport, dport] => {
+            let src: IpAddr = src.parse().context("invalid PROXY v1 source address")?;
+            let dst: IpAddr = dst
+                .parse()
+                .context("invalid PROXY v1 destination address")?;
+            if src.is_ipv4() != (*proto == "TCP4") || dst.is_ipv4() != (*proto == "TCP4") {
+                anyhow::bail!("PROXY v1 addresses do not match {}", proto);
+            }
+            Ok(Header {
+                source: Some(SocketAddr::new(src, sport.parse()?)),
+                destination: Some(SocketAddr::new(dst, dport.parse()?)),
+            })
+        }
+        _ => anyhow::bail!("malformed PROXY v1 header"),
+    }
+}
+
+async fn read_v2<R: AsyncRead + Unpin>(stream: &mut R, prefix: [u8; 5]) -> Result<Header> {
+    let mut fixed = [0u8; 16];
+    fixed[..5].copy_from_slice(&prefix);
+    stream.read_exact(&mut fixed[5..]).await?;
+    if fixed[..12] != V2_SIGNATURE {
+        anyhow::bail!("invalid PROXY v2 signature");
+    }
+    let version = fixed[12] >> 4;
+    let command = fixed[12] & 0x0f;
+    let family = fixed[13];
+    let len = u16::from_be_bytes([fixed[14], fixed[15]]) as usize;
+    if version != 2 {
+        anyhow::bail!("unsupported PROXY protocol version {}", version);
+    }
+
+    let mut payload = vec![0u8; len];
+    stream.read_exact(&mut payload).await?;
+
+    let unspecified = Header {
+        source: None,
+        destination: None,
+    };
+    match command {
+        0x0 => return Ok(unspecified), // LOCAL
+        0x1 => {}                      // PROXY
+        _ => anyhow::bail!("unsupported PROXY v2 command {:#x}", command),
+    }
+
+    // Only the address family matters here; TCP and UDP are laid out alike
+    match family >> 4 {
+        0x1 => {
+            let addrs = payload
+                .get(..12)
+                .context("PROXY v2 IPv4 address block is truncated")?;
+            let src = Ipv4Addr::new(addrs[0], addrs[1], addrs[2], addrs[3]);
+            let dst = Ipv4Addr::new(addrs[4], addrs[5], addrs[6], addrs[7]);
+            Ok(Header {
+                source: Some(SocketAddr::new(
+                    src.into(),
+                    u16::from_be_bytes([addrs[8], addrs[9]]),
+                )),
+                destination: Some(SocketAddr::new(
+                    dst.into(),
+                    u16::from_be_bytes([addrs[10], addrs[11]]),
+                )),
+            })
+        }
+        0x2 => {
+            let addrs = payload
+                .get(..36)
+                .context("PROXY v2 IPv6 address block is truncated")?;
+            let src: [u8; 16] = addrs[..16].try_into()?;
+            let dst: [u8; 16] = addrs[16..32].try_into()?;
+            Ok(Header {
+                source: Some(SocketAddr::new(
+                    Ipv6Addr::from(src).into(),
+                    u16::from_be_bytes([addrs[32], addrs[33]]),
+                )),
+                destination: Some(SocketAddr::new(
+                    Ipv6Addr::from(dst).into(),
+                    u16::from_be_bytes([addrs[34], addrs[35]]),
+                )),
+            })
+        }
+        // UNSPEC and unix sockets carry no address we can use
+        _ => Ok(unspecified),
+    }
+}
+
+/// Encode a PROXY header announcing a TCP connection from `source` to
+/// `destination`. Mixed address families are sent as IPv6, with the IPv4
+/// side mapped into `::ffff:0:0/96`.
+pub fn encode_header(version: Version, source: SocketAddr, destination: SocketAddr) -> Vec<u8> {
+    let (source, destination) = match (source.ip(), destination.ip()) {
+        (IpAddr::V4(_), IpAddr::V4(_)) | (IpAddr::V6(_), IpAddr::V6(_)) => (source, destination),
+        _ => (to_ipv6(source), to_ipv6(destination)),
+    };
+
+    match version {
+        Version::V1 => {
+            let proto = if source.is_ipv4() { "TCP4" } else { "TCP6" };
+            format!(
+                "PROXY {} {} {} {} {}\r\n",
+                proto,
+                source.ip(),
+                destination.ip(),
+                source.port(),
+                destination.port()
+            )
+            .into_bytes()
+        }
+        Version::V2 => {
+            let mut header = V2_SIGNATURE.to_vec();
+            header.push(0x21); // version 2, PROXY command
+            let addrs = match (source.ip(), destination.ip()) {
+                (IpAddr::V4(src), IpAddr::V4(dst)) => {
+                    header.push(0x11); // TCP over IPv4
+                    [src.octets().as_slice(), dst.octets().as_slice()].concat()
+                }
+                (src, dst) => {
+                    header.push(0x21); // TCP over IPv6
+                    [ipv6_octets(src).as_slice(), ipv6_octets(dst).as_slice()].concat()
+                }
+            };
+            header.extend_from_slice(&((addrs.len() + 4) as u16).to_be_bytes());
+            header.extend_from_slice(&addrs);
+            header.extend_from_slice(&source.port().to_be_bytes());
+            header.extend_from_slice(&destination.port().to_be_bytes());
+            header
+        }
+    }
+}
+
+fn to_ipv6(addr: SocketAddr) -> SocketAddr {
+    SocketAddr::new(
+        IpAddr::V6(Ipv6Addr::from(ipv6_octets(addr.ip()))),
+        addr.port(),
+    )
+}
+
+fn ipv6_octets(ip: IpAddr) -> [u8; 16] {
+    match ip {
+        IpAddr::V4(v4) => v4.to_ipv6_mapped().octets(),
+        IpAddr::V6(v6) => v6.octets(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn addr(s: &str) -> SocketAddr {
+        s.parse().unwrap()
+    }
+
+    async fn parse(bytes: &[u8]) -> Result<Header> {
+        let mut stream = bytes;
+        read_header(&mut stream).await
+    }
+
+    #[tokio::test]
+    async fn v1_round_trip() {
+        let (src, dst) = (addr("192.0.2.1:4000"), addr("198.51.100.2:443"));
+        let header = encode_header(Version::V1, src, dst);
+        assert_eq!(header, b"PROXY TCP4 192.0.2.1 198.51.100.2 4000 443\r\n");
+        let parsed = parse(&header).await.unwrap();
+        assert_eq!(parsed.source, Some(src));
+        assert_eq!(parsed.destination, Some(dst));
+    }
+
+    #[tokio::test]
+    async fn v1_tcp6_and_unknown() {
+        let parsed = parse(b"PROXY TCP6 2001:db8::1 2001:db8::2 1 2\r\n")
+            .await
+            .unwrap();
+        assert_eq!(parsed.source, Some(addr("[2001:db8::1]:1")));
+
+        let parsed = parse(b"PROXY UNKNOWN\r\n").await.unwrap();
+        assert_eq!(parsed.source, None);
+        assert_eq!(parsed.destination, None);
+    }
+
+    #[tokio::test]
+    async fn v1_consumes_only_the_header() {
+        let mut stream: &[u8] = b"PROXY TCP4 192.0.2.1 192.0.2.2 1 2\r\nhello";
+        read_header(&mut stream).await.unwrap();
+        assert_eq!(stream, b"hello");
+    }
+
+    #[tokio::test]
+    async fn v1_rejects_malformed_headers() {
+        for header in [
+            &b"PROXY TCP4 192.0.2.1 2001:db8::2 1 2\r\n"[..],
+            b"PROXY TCP4 192.0.2.1 192.0.2.2 1\r\n",
+            b"PROXY TCP4 nonsense 192.0.2.2 1 2\r\n",
+            b"PROXY TCP4 192.0.2.1 192.0.2.2 1 99999\r\n",
+            b"GET / HTTP/1.1\r\n",
+        ] {
+            assert!(parse(header).await.is_err());
+        }
+        let long = [b"PROXY ".as_slice(), &[b'A'; 200]].concat();
+        assert!(parse(&long).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn v2_round_trip() {
+        for (src, dst) in [
+            (addr("192.0.2.1:4000"), addr("198.51.100.2:443")),
+            (addr("[2001:db8::1]:4000"), addr("[2001:db8::2]:443")),
+        ] {
+            let header = encode_header(Version::V2, src, dst);
+            let stream = [header.as_slice(), b"data"].concat();
+            let mut rest = stream.as_slice();
+            let parsed = read_header(&mut rest).await.unwrap();
+            assert_eq!(parsed.source, Some(src));
+            assert_eq!(parsed.destination, Some(dst));
+            assert_eq!(rest, b"data");
+        }
+    }
+
+    #[tokio::test]
+    async fn v2_mixed_families_are_mapped() {
+        let header = encode_header(
+            Version::V2,
+            addr("192.0.2.1:4000"),
+            addr("[2001:db8::2]:443"),
+        );
+        let parsed = parse(&header).await.unwrap();
+        assert_eq!(parsed.source, Some(addr("[::ffff:192.0.2.1]:4000")));
+    }
+
+    #[tokio::test]
+    async fn v2_local_and_truncated() {
+        let mut local = V2_SIGNATURE.to_vec();
+        local.extend_from_slice(&[0x20, 0x00, 0x00, 0x00]);
+        assert_eq!(parse(&local).await.unwrap().source, None);
+
+        let mut short = V2_SIGNATURE.to_vec();
+        short.extend_from_slice(&[0x21, 0x11, 0x00, 0x04, 1, 2, 3, 4]);
+        assert!(parse(&short).await.is_err());
+
+        let mut bad_version = V2_SIGNATURE.to_vec();
+        bad_version.extend_from_slice(&[0x11, 0x11, 0x00, 0x00]);
+        assert!(parse(&bad_version).await.is_err());
+    }
+}
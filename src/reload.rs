@@ -0,0 +1,323 @@
+use crate::config::Config;
+use crate::proxy::Proxy;
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+use tokio::sync::{watch, Mutex};
+
+/// The configuration currently in effect, as seen by request handlers.
+pub type LiveConfig = watch::Receiver<Arc<Config>>;
+
+/// What a reload changed, and which changes were left for a restart.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct ReloadReport {
+    pub applied: Vec<String>,
+    pub restart_required: Vec<String>,
+    /// Settings that changed but are masked by the environment, e.g.
+    /// `logging.level` while `RUST_LOG` is set
+    #[serde(default)]
+    pub overridden: Vec<String>,
+}
+
+/// Re-reads the configuration file of a running server and applies what
+/// can change without a restart: proxy routes and their upstreams, the
+/// server's trusted proxies, host leases, IP limits and admin credentials,
+/// and the log level.
+/// Everything else keeps its running value until restart.
+pub struct ConfigReloader {
+    path: String,
+    proxy: Arc<Proxy>,
+    current: watch::Sender<Arc<Config>>,
+    // Reloads from SIGHUP and the socket must not interleave
+    lock: Mutex<()>,
+}
+
+impl ConfigReloader {
+    pub fn new(path: &str, config: Config, proxy: Arc<Proxy>) -> Arc<Self> {
+        Arc::new(Self {
+            path: path.to_string(),
+            proxy,
+            current: watch::Sender::new(Arc::new(config)),
+            lock: Mutex::new(()),
+        })
+    }
+
+    pub fn subscribe(&self) -> LiveConfig {
+        self.current.subscribe()
+    }
+
+    /// Load and validate the configuration file, then apply it. Nothing is
+    /// applied if the file is invalid or a new route cannot be started.
+    pub async fn reload(&self) -> Result<ReloadReport> {
+        let _guard = self.lock.lock().await;
+        // Unlike startup, a missing file is an error rather than the defaults
+        let new = Config::load_from_path(&self.path)
+            .with_context(|| format!("failed to read configuration file {}", self.path))?;
+        new.validate()?;
+        let old = Arc::clone(&self.current.borrow());
+
+        let mut report = ReloadReport {
+            restart_required: restart_required(&old, &new),
+            ..ReloadReport::default()
+        };
+        report.applied = self.proxy.apply(&new.proxy).await?;
+        let effective = apply_live(&old, new, &mut report, crate::logger::set_level)?;
+        self.current.send_replace(Arc::new(effective));
+
+        Ok(report)
+    }
+}
+
+/// Settings that differ between `old` and `new` but only take effect on
+/// restart.
+fn restart_required(old: &Config, new: &Config) -> Vec<String> {
+    let mut settings = Vec::new();
+    let mut restart = |changed: bool, setting: &str| {
+        if changed {
+            settings.push(setting.to_string());
+        }
+    };
+    restart(new.storage != old.storage, "storage");
+    restart(new.server.host != old.server.host, "server.host");
+    restart(new.server.port != old.server.port, "server.port");
+    restart(
+        new.server.reap_interval_secs != old.server.reap_interval_secs,
+        "server.reap_interval_secs",
+    );
+    restart(
+        new.server.expired_key_retention_secs != old.server.expired_key_retention_secs,
+        "server.expired_key_retention_secs",
+    );
+    restart(
+        new.server.shutdown_timeout_secs != old.server.shutdown_timeout_secs,
+        "server.shutdown_timeout_secs",
+    );
+    restart(
+        new.proxy.allowlist != old.proxy.allowlist,
+        "proxy.allowlist",
+    );
+    restart(new.server.tls != old.server.tls, "server.tls");
+    restart(
+        new.server.metrics_listen_addr != old.server.metrics_listen_addr,
+        "server.metrics_listen_addr",
+    );
+    restart(new.audit != old.audit, "audit");
+    restart(new.logging.format != old.logging.format, "logging.format");
+    restart(new.logging.file != old.logging.file, "logging.file");
+    restart(new.logging.otlp != old.logging.otlp, "logging.otlp");
+    settings
+}
+
+/// The running configuration `old` with the settings of `new` that apply
+/// live, recording in `report` the ones that changed. `set_level` installs
+/// a new log level, returning false when the environment overrides it.
+fn apply_live(
+    old: &Config,
+    new: Config,
+    report: &mut ReloadReport,
+    set_level: impl FnOnce(&str) -> Result<bool>,
+) -> Result<Config> {
+    if new.logging.level != old.logging.level {
+        if set_level(&new.logging.level)? {
+            report.applied.push("logging.level".to_string());
+        } else {
+            report.overridden.push("logging.level".to_string());
+        }
+    }
+
+    let mut effective = old.clone();
+    let mut apply = |changed: bool, setting: &str| {
+        if changed {
+            report.applied.push(setting.to_string());
+        }
+    };
+    apply(
+        new.server.trusted_proxies != old.server.trusted_proxies,
+        "server.trusted_proxies",
+    );
+    apply(
+        new.server.host_lease_secs != old.server.host_lease_secs,
+        "server.host_lease_secs",
+    );
+    apply(
+        new.server.max_ips_per_key != old.server.max_ips_per_key,
+        "server.max_ips_per_key",
+    );
+    apply(new.server.admin != old.server.admin, "server.admin");
+    effective.server.trusted_proxies = new.server.trusted_proxies;
+    effective.server.host_lease_secs = new.server.host_lease_secs;
+    effective.server.max_ips_per_key = new.server.max_ips_per_key;
+    effective.server.admin = new.server.admin;
+    effective.proxy.listen_addr = new.proxy.listen_addr;
+    effective.proxy.upstream_addr = new.proxy.upstream_addr;
+    effective.proxy.routes = new.proxy.routes;
+    effective.logging.level = new.logging.level;
+    Ok(effective)
+}
+
+impl std::fmt::Display for ReloadReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if self.applied.is_empty() {
+            write!(f, "no changes applied")?;
+        } else {
+            write!(f, "applied: {}", self.applied.join(", "))?;
+        }
+        if !self.restart_required.is_empty() {
+            write!(
+                f,
+                "; restart required for: {}",
+                self.restart_required.join(", ")
+            )?;
+        }
+        if !self.overridden.is_empty() {
+            write!(f, "; overridden: {}", self.overridden.join(", "))?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{AuditConfig, RouteConfig};
+    use crate::storage::SqliteStorage;
+    use tokio_util::sync::CancellationToken;
+
+    fn level_set(_level: &str) -> Result<bool> {
+        Ok(true)
+    }
+
+    #[test]
+    fn settings_needing_a_restart_are_reported() {
+        let old = Config::default();
+        let mut new = old.clone();
+        new.server.port += 1;
+        new.server.shutdown_timeout_secs += 1;
+        new.logging.format = crate::config::LogFormat::Json;
+        new.audit.storage = !old.audit.storage;
+
+        assert_eq!(
+            restart_required(&old, &new),
+            [
+                "server.port",
+                "server.shutdown_timeout_secs",
+                "audit",
+                "logging.format"
+            ]
+        );
+        let mut report = ReloadReport::default();
+        let effective = apply_live(&old, new, &mut report, level_set).unwrap();
+        assert!(report.applied.is_empty());
+        assert_eq!(effective, old);
+    }
+
+    #[test]
+    fn live_settings_are_applied() {
+        let old = Config::default();
+        let mut new = old.clone();
+        new.server.port += 1;
+        new.server.trusted_proxies = vec!["10.0.0.0/8".parse().unwrap()];
+        new.server.max_ips_per_key = Some(2);
+        new.logging.level = "debug".to_string();
+        new.proxy.routes = vec![RouteConfig::new(
+            "other",
+            "127.0.0.1:4001",
+            "127.0.0.1:4002",
+        )];
+
+        assert_eq!(restart_required(&old, &new), ["server.port"]);
+        let mut report = ReloadReport::default();
+        let effective = apply_live(&old, new.clone(), &mut report, level_set).unwrap();
+        assert_eq!(
+            report.applied,
+            [
+                "logging.level",
+                "server.trusted_proxies",
+                "server.max_ips_per_key"
+            ]
+        );
+        assert!(report.overridden.is_empty());
+        assert_eq!(effective.server.trusted_proxies, new.server.trusted_proxies);
+        assert_eq!(effective.server.max_ips_per_key, Some(2));
+        assert_eq!(effective.proxy.routes, new.proxy.routes);
+        assert_eq!(effective.logging.level, "debug");
+        // Restart-only settings keep their running value
+        assert_eq!(effective.server.port, old.server.port);
+    }
+
+    #[test]
+    fn a_log_level_masked_by_the_environment_is_overridden() {
+        let old = Config::default();
+        let mut new = old.clone();
+        new.logging.level = "debug".to_string();
+
+        let mut report = ReloadReport::default();
+        apply_live(&old, new, &mut report, |_| Ok(false)).unwrap();
+        assert!(report.applied.is_empty());
+        assert_eq!(report.overridden, ["logging.level"]);
+
+        let mut new = old.clone();
+        new.logging.level = "no such level[".to_string();
+        let failed = apply_live(&old, new, &mut report, |_| anyhow::bail!("invalid"));
+        assert!(failed.is_err());
+    }
+
+    #[tokio::test]
+    async fn a_rejected_reload_changes_nothing() {
+        let storage: Arc<dyn crate::storage::StorageBackend> =
+            Arc::new(SqliteStorage::in_memory().await.unwrap());
+        let audit = AuditConfig {
+            storage: false,
+            file: None,
+        };
+        let audit = crate::audit::Auditor::start(&audit, storage.clone())
+            .await
+            .unwrap();
+        let mut config = Config::default();
+        config.proxy.routes = vec![RouteConfig::new("test", "127.0.0.1:0", "127.0.0.1:1")];
+        let shutdown = CancellationToken::new();
+        let proxy = Proxy::start(&config, storage, audit, shutdown.clone())
+            .await
+            .unwrap();
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("config.yaml");
+        let reloader = ConfigReloader::new(&path.to_string_lossy(), config.clone(), proxy);
+        let live = reloader.subscribe();
+        let write = |config: &Config| {
+            std::fs::write(&path, serde_yaml::to_string(config).unwrap()).unwrap();
+        };
+
+        // Missing file
+        assert!(reloader.reload().await.is_err());
+
+        // Invalid configuration
+        let mut invalid = config.clone();
+        invalid.server.trusted_proxies = vec!["10.0.0.0/8".parse().unwrap()];
+        invalid.proxy.allowlist.poll_ms = 0;
+        write(&invalid);
+        assert!(reloader.reload().await.is_err());
+
+        // A route that cannot bind
+        let taken = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut unbindable = config.clone();
+        unbindable.server.trusted_proxies = vec!["10.0.0.0/8".parse().unwrap()];
+        unbindable.proxy.routes.push(RouteConfig::new(
+            "taken",
+            &taken.local_addr().unwrap().to_string(),
+            "127.0.0.1:1",
+        ));
+        write(&unbindable);
+        assert!(reloader.reload().await.is_err());
+        assert_eq!(**live.borrow(), config);
+
+        // Once the file is fixed the same change goes through
+        let mut valid = config.clone();
+        valid.server.trusted_proxies = vec!["10.0.0.0/8".parse().unwrap()];
+        write(&valid);
+        let report = reloader.reload().await.unwrap();
+        assert_eq!(report.applied, ["server.trusted_proxies"]);
+        assert_eq!(**live.borrow(), valid);
+        shutdown.cancel();
+    }
+}
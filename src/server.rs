@@ -1,9 +1,27 @@
+use crate::audit::{AuditAction, AuditEvent, Auditor, Decision};
+use crate::metrics::metrics;
+use crate::models::RegistrationStatus;
+use crate::storage::HostRegistration;
+use actix_web::body::MessageBody;
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::http::header::HeaderMap;
+use actix_web::middleware::{from_fn, Next};
 use actix_web::{get, post, web, App, HttpResponse, HttpServer, Responder};
-use anyhow::Result;
+use anyhow::{Context, Result};
+use base64::{engine::general_purpose, Engine as _};
+use ipnet::IpNet;
+use opentelemetry::propagation::{Extractor, TextMapPropagator};
+use opentelemetry_sdk::propagation::TraceContextPropagator;
+use std::net::IpAddr;
 use std::sync::Arc;
-use tracing::{error, info};
-use utoipa::OpenApi;
+use std::time::Duration;
+use tokio_util::sync::CancellationToken;
+use tracing::{error, info, info_span, warn, Instrument};
+use tracing_opentelemetry::OpenTelemetrySpanExt;
+use utoipa::openapi::security::{Http, HttpAuthScheme, SecurityScheme};
+use utoipa::{Modify, OpenApi};
 use utoipa_swagger_ui::SwaggerUi;
+use uuid::Uuid;
 
 #[utoipa::path(
     get,
@@ -37,119 +55,609 @@ async fn healthcheck() -> impl Responder {
     get,
     path = "/ip",
     responses(
-        (status = 200, description = "Returns the client's IP address", body = String)
+        (status = 200, description = "Returns the client's IP address", body = String),
+        (status = 400, description = "Forwarding headers from a trusted proxy do not name a client")
     )
 )]
-#[tracing::instrument(name = "ip", skip(req))]
+#[tracing::instrument(name = "ip", skip(req, live_config))]
 #[get("/ip")]
-async fn return_client_ip(req: actix_web::HttpRequest) -> impl Responder {
-    match return_ip(&req) {
+async fn return_client_ip(
+    req: actix_web::HttpRequest,
+    live_config: web::Data<crate::reload::LiveConfig>,
+) -> impl Responder {
+    let server_config = live_config.borrow().server.clone();
+    match return_ip(&req, &server_config.trusted_proxies) {
         Some((source, ip)) => {
             info!("client IP determined via {}: {}", source, ip);
-            HttpResponse::Ok().body(ip)
+            HttpResponse::Ok().body(ip.to_string())
         }
         None => {
             error!("unable to determine client IP");
-            HttpResponse::InternalServerError().body("Unable to determine client IP")
+            HttpResponse::BadRequest().body("Unable to determine client IP")
         }
     }
 }
 
+/// Served on `server.metrics_listen_addr`, not with the public API
+#[get("/metrics")]
+async fn serve_metrics(
+    storage: web::Data<Arc<dyn crate::storage::StorageBackend>>,
+) -> impl Responder {
+    match metrics().render(storage.as_ref().as_ref()).await {
+        Ok(body) => HttpResponse::Ok()
+            .content_type("text/plain; version=0.0.4")
+            .body(body),
+        Err(e) => {
+            error!("Failed to render metrics: {}", e);
+            HttpResponse::InternalServerError().body("Unable to render metrics")
+        }
+    }
+}
+
+#[utoipa::path(
+    get,
+    path = "/challenge",
+    responses(
+        (status = 200, description = "Issues a registration challenge", body = ChallengeResponse),
+        (status = 400, description = "Unable to determine client IP"),
+        (status = 429, description = "Too many outstanding challenges for this client or in total")
+    )
+)]
+#[tracing::instrument(name = "challenge", skip(req, challenges, live_config))]
+#[get("/challenge")]
+async fn issue_challenge(
+    req: actix_web::HttpRequest,
+    challenges: web::Data<crate::challenge::ChallengeStore>,
+    live_config: web::Data<crate::reload::LiveConfig>,
+) -> impl Responder {
+    let trusted_proxies = live_config.borrow().server.trusted_proxies.clone();
+    let Some((_source, ip)) = return_ip(&req, &trusted_proxies) else {
+        return HttpResponse::BadRequest().body("Unable to determine client IP");
+    };
+    let Some((id, server_public_key, nonce)) = challenges.issue(ip) else {
+        return HttpResponse::TooManyRequests().body("Too many outstanding challenges");
+    };
+    let resp = crate::models::ChallengeResponse {
+        challenge_id: id.to_string(),
+        server_public_key,
+        nonce,
+        expires_in: challenges.ttl().as_secs(),
+    };
+    HttpResponse::Ok().json(resp)
+}
+
 #[utoipa::path(
     post,
     path = "/register",
     request_body(content = crate::models::RegisterRequest, description = "Request body containing public_key"),
     responses(
-        (status = 200, description = "Registers the client's IP address", body = String),
+        (status = 200, description = "Registers the client's IP address", body = RegisterResponse),
         (status = 400, description = "Invalid public_key or missing IP address"),
+        (status = 401, description = "Unknown, expired or failed challenge, or one issued to another address"),
+        (status = 403, description = "public_key has expired"),
+        (status = 409, description = "The address is held by another key or a static entry"),
         (status = 500, description = "Unable to register the IP address")
     )
 )]
-#[tracing::instrument(name = "register", skip(req))]
+#[tracing::instrument(name = "register", skip(req, challenges, live_config, audit))]
 #[post("/register")]
 async fn register_client_ip(
     req: actix_web::HttpRequest,
     body: web::Json<crate::models::RegisterRequest>,
     storage: web::Data<Arc<dyn crate::storage::StorageBackend>>,
+    challenges: web::Data<crate::challenge::ChallengeStore>,
+    live_config: web::Data<crate::reload::LiveConfig>,
+    audit: web::Data<Auditor>,
 ) -> impl Responder {
-    let public_key = &body.public_key;
+    let server_config = live_config.borrow().server.clone();
+    let client_ip = return_ip(&req, &server_config.trusted_proxies);
+    let attempt = Attempt {
+        endpoint: "register",
+        audit: &audit,
+        ip: client_ip.map(|(_source, ip)| ip),
+    };
+    let Some((_source, ip)) = client_ip else {
+        warn!("unable to determine client IP for registration");
+        return attempt.rejected(
+            None,
+            "no_client_ip",
+            HttpResponse::BadRequest().body("Unable to determine client IP"),
+        );
+    };
+    let key = match authenticate_key(&attempt, ip, &body, &storage, &challenges).await {
+        Ok(key) => key,
+        Err(resp) => return resp,
+    };
 
-    // Validate public_key exists in the database
-    info!("validating public key");
-    info!(storage = ?storage);
-    if !storage
-        .validate_public_key(public_key)
+    // Register client IP
+    let network = key.host_network(ip);
+    let expires_at = server_config.lease_expiry();
+    info!(key_id = %key.id, "registering client: {} as {}", ip, network);
+    let registration = match storage
+        .store_client_ip(network, key.id, expires_at, server_config.max_ips_per_key)
         .await
-        .unwrap_or(false)
     {
-        error!("public key attempted but not found");
-        return HttpResponse::BadRequest().body("Invalid public_key");
-    }
+        Ok(registration) => registration,
+        Err(e) => {
+            error!("Failed to store IP: {}", e);
+            return attempt.rejected(
+                Some(key.id),
+                "storage_error",
+                HttpResponse::InternalServerError().body("Failed to store IP"),
+            );
+        }
+    };
+    let (status, replaced_ips) = match registration {
+        HostRegistration::New => (RegistrationStatus::New, Vec::new()),
+        HostRegistration::Refreshed => (RegistrationStatus::Refreshed, Vec::new()),
+        HostRegistration::Moved { replaced } => {
+            info!(key_id = %key.id, "client moved from {:?} to {}", replaced, network);
+            (
+                RegistrationStatus::Moved,
+                replaced.iter().map(|net| net.to_string()).collect(),
+            )
+        }
+        HostRegistration::Taken => {
+            warn!(key_id = %key.id, "{} is held by another entry", network);
+            return attempt.rejected(
+                Some(key.id),
+                "network_taken",
+                HttpResponse::Conflict()
+                    .body(format!("{} is already allowed by another entry", network)),
+            );
+        }
+    };
+    let reason = match status {
+        RegistrationStatus::New => "new",
+        RegistrationStatus::Refreshed => "refreshed",
+        RegistrationStatus::Moved => "moved",
+    };
+    attempt.accepted(key.id, network, reason);
+    let resp = crate::models::RegisterResponse {
+        message: format!("IP {} registered successfully", network),
+        status,
+        replaced_ips,
+        expires_at,
+    };
+    HttpResponse::Ok().json(resp)
+}
 
-    // Register client IP
-    match return_ip(&req) {
-        Some((_source, ip)) => {
-            info!("registering client: {}", ip);
-            if let Err(e) = storage.store_client_ip(ip.clone()).await {
-                error!("Failed to store IP: {}", e);
-                return HttpResponse::InternalServerError().body("Failed to store IP");
-            }
+#[utoipa::path(
+    post,
+    path = "/renew",
+    request_body(content = crate::models::RegisterRequest, description = "Request body containing public_key"),
+    responses(
+        (status = 200, description = "Extends the lease on the client's IP address", body = RegisterResponse),
+        (status = 400, description = "Invalid public_key or missing IP address"),
+        (status = 401, description = "Unknown, expired or failed challenge, or one issued to another address"),
+        (status = 403, description = "public_key has expired"),
+        (status = 404, description = "No lease held by this key for the client's IP address"),
+        (status = 500, description = "Unable to renew the lease")
+    )
+)]
+#[tracing::instrument(name = "renew", skip(req, challenges, live_config, audit))]
+#[post("/renew")]
+async fn renew_client_ip(
+    req: actix_web::HttpRequest,
+    body: web::Json<crate::models::RegisterRequest>,
+    storage: web::Data<Arc<dyn crate::storage::StorageBackend>>,
+    challenges: web::Data<crate::challenge::ChallengeStore>,
+    live_config: web::Data<crate::reload::LiveConfig>,
+    audit: web::Data<Auditor>,
+) -> impl Responder {
+    let server_config = live_config.borrow().server.clone();
+    let client_ip = return_ip(&req, &server_config.trusted_proxies);
+    let attempt = Attempt {
+        endpoint: "renew",
+        audit: &audit,
+        ip: client_ip.map(|(_source, ip)| ip),
+    };
+    let Some((_source, ip)) = client_ip else {
+        warn!("unable to determine client IP for lease renewal");
+        return attempt.rejected(
+            None,
+            "no_client_ip",
+            HttpResponse::BadRequest().body("Unable to determine client IP"),
+        );
+    };
+    let key = match authenticate_key(&attempt, ip, &body, &storage, &challenges).await {
+        Ok(key) => key,
+        Err(resp) => return resp,
+    };
+
+    let network = key.host_network(ip);
+    let expires_at = server_config.lease_expiry();
+    info!(key_id = %key.id, "renewing lease for client: {} as {}", ip, network);
+    match storage.renew_client_ip(network, key.id, expires_at).await {
+        Ok(true) => {
+            attempt.accepted(key.id, network, "renewed");
             let resp = crate::models::RegisterResponse {
-                message: format!("IP {} registered successfully", ip),
+                message: format!("IP {} lease renewed successfully", network),
+                status: RegistrationStatus::Refreshed,
+                replaced_ips: Vec::new(),
+                expires_at,
             };
             HttpResponse::Ok().json(resp)
         }
+        Ok(false) => {
+            error!("no lease to renew for client: {}", network);
+            attempt.rejected(
+                Some(key.id),
+                "no_lease",
+                HttpResponse::NotFound().body("No lease held for this IP"),
+            )
+        }
+        Err(e) => {
+            error!("Failed to renew lease: {}", e);
+            attempt.rejected(
+                Some(key.id),
+                "storage_error",
+                HttpResponse::InternalServerError().body("Failed to renew lease"),
+            )
+        }
+    }
+}
+
+/// Check the challenge response in `body`, sent from `client_ip`, and
+/// return the key it proves possession of, or the error response to send
+/// back, recorded as a rejected `attempt`.
+async fn authenticate_key(
+    attempt: &Attempt<'_>,
+    client_ip: IpAddr,
+    body: &crate::models::RegisterRequest,
+    storage: &Arc<dyn crate::storage::StorageBackend>,
+    challenges: &crate::challenge::ChallengeStore,
+) -> std::result::Result<crate::storage::KeyRecord, HttpResponse> {
+    let public_key = &body.public_key;
+
+    // Challenges are single use, so consume it before anything else
+    let challenge = match uuid::Uuid::parse_str(&body.challenge_id)
+        .ok()
+        .and_then(|id| challenges.take(&id, client_ip))
+    {
+        Some(challenge) => challenge,
         None => {
-            error!("IP registration failed. Please try again");
-            HttpResponse::InternalServerError().body("something went wrong")
+            error!("registration attempted with unknown, expired or another client's challenge");
+            return Err(attempt.rejected(
+                None,
+                "unknown_challenge",
+                HttpResponse::Unauthorized().body("Unknown or expired challenge"),
+            ));
+        }
+    };
+
+    // Validate public_key exists in the database and has not expired
+    info!("validating public key");
+    let key = match storage.find_key(public_key).await {
+        Ok(Some(key)) if key.is_expired() => {
+            error!("expired public key attempted");
+            return Err(attempt.rejected(
+                Some(key.id),
+                "expired_key",
+                HttpResponse::Forbidden().body("Expired public_key"),
+            ));
+        }
+        Ok(Some(key)) => key,
+        Ok(None) => {
+            error!("public key attempted but not found");
+            return Err(attempt.rejected(
+                None,
+                "unknown_key",
+                HttpResponse::BadRequest().body("Invalid public_key"),
+            ));
         }
+        Err(e) => {
+            error!("Failed to validate public key: {}", e);
+            return Err(attempt.rejected(
+                None,
+                "storage_error",
+                HttpResponse::InternalServerError().body("Unable to validate public_key"),
+            ));
+        }
+    };
+
+    // Verify the caller holds the private key for public_key
+    info!("verifying challenge response");
+    if !verify_challenge(&challenge, public_key, &body.mac) {
+        error!("challenge response did not verify");
+        return Err(attempt.rejected(
+            Some(key.id),
+            "invalid_challenge_response",
+            HttpResponse::Unauthorized().body("Invalid challenge response"),
+        ));
+    }
+
+    Ok(key)
+}
+
+/// A host registration or renewal, counted and audited as it is accepted
+/// or rejected
+struct Attempt<'a> {
+    endpoint: &'static str,
+    audit: &'a Auditor,
+    ip: Option<IpAddr>,
+}
+
+impl Attempt<'_> {
+    fn event(&self, decision: Decision, key_id: Option<Uuid>, reason: &str) -> AuditEvent {
+        let action = match self.endpoint {
+            "renew" => AuditAction::HostRenewed,
+            _ => AuditAction::HostRegistered,
+        };
+        AuditEvent::new(action, "api", decision)
+            .with_key(key_id)
+            .with_ip(self.ip)
+            .with_reason(reason)
+    }
+
+    fn accepted(&self, key_id: Uuid, network: IpNet, reason: &str) {
+        metrics()
+            .registrations
+            .with_label_values(&[self.endpoint, "accepted", reason])
+            .inc();
+        let event = self
+            .event(Decision::Allow, Some(key_id), reason)
+            .with_network(Some(network));
+        self.audit.record(event);
+    }
+
+    fn rejected(&self, key_id: Option<Uuid>, reason: &str, response: HttpResponse) -> HttpResponse {
+        metrics()
+            .registrations
+            .with_label_values(&[self.endpoint, "rejected", reason])
+            .inc();
+        self.audit
+            .record_sampled(self.event(Decision::Deny, key_id, reason));
+        response
     }
 }
 
 #[derive(OpenApi)]
 #[openapi(
-    paths(index, healthcheck, return_client_ip, register_client_ip),
-    components(schemas(crate::models::HealthResponse, crate::models::RegisterRequest))
+    paths(
+        index,
+        healthcheck,
+        return_client_ip,
+        issue_challenge,
+        register_client_ip,
+        renew_client_ip,
+        crate::admin::list_keys,
+        crate::admin::create_key,
+        crate::admin::get_key,
+        crate::admin::revoke_key,
+        crate::admin::expire_key,
+        crate::admin::list_hosts,
+        crate::admin::create_host,
+        crate::admin::get_host,
+        crate::admin::delete_host
+    ),
+    components(schemas(
+        crate::models::HealthResponse,
+        crate::models::ChallengeResponse,
+        crate::models::RegisterRequest,
+        crate::models::RegisterResponse,
+        crate::models::RegistrationStatus,
+        crate::models::KeyStatus,
+        crate::models::KeyResponse,
+        crate::models::CreateKeyRequest,
+        crate::models::ExpireKeyRequest,
+        crate::models::RevokeKeyResponse,
+        crate::models::HostResponse,
+        crate::models::CreateHostRequest
+    )),
+    modifiers(&AdminSecurity)
 )]
 struct ApiDoc;
 
-pub async fn run_server(config_path: &str) -> Result<()> {
-    let config = crate::config::Config::load(config_path)?;
-    config.validate()?;
+/// The credentials accepted by the `/admin` endpoints
+struct AdminSecurity;
 
-    let storage = create_storage(&config).await?;
-    let storage = Arc::clone(&storage);
+impl Modify for AdminSecurity {
+    fn modify(&self, openapi: &mut utoipa::openapi::OpenApi) {
+        let components = openapi.components.get_or_insert_with(Default::default);
+        components.add_security_scheme(
+            "admin_token",
+            SecurityScheme::Http(Http::new(HttpAuthScheme::Bearer)),
+        );
+        components.add_security_scheme(
+            "admin_certificate",
+            SecurityScheme::MutualTls { description: None },
+        );
+    }
+}
 
-    let addr = format!("{}:{}", config.server.host, config.server.port);
-    info!("SHADE server running on http://{}", addr);
+/// Headers of an incoming request, as read by the trace context propagator
+struct RequestHeaders<'a>(&'a HeaderMap);
 
-    if matches!(config.storage.mode, crate::config::StorageMode::Socket) {
-        let socket_path = config.storage.socket_path.as_ref().unwrap();
-        let socket_server = crate::socket::SocketServer::new(socket_path, storage.clone()).await?;
-        tokio::spawn(async move {
-            if let Err(e) = socket_server.run().await {
-                eprintln!("Socket server error: {}", e);
-            }
-        });
+impl Extractor for RequestHeaders<'_> {
+    fn get(&self, key: &str) -> Option<&str> {
+        self.0.get(key).and_then(|value| value.to_str().ok())
+    }
+
+    fn keys(&self) -> Vec<&str> {
+        self.0.keys().map(|name| name.as_str()).collect()
     }
+}
+
+/// Run each request in a span that continues the caller's trace when it
+/// sends a W3C `traceparent` header.
+async fn trace_context(
+    req: ServiceRequest,
+    next: Next<impl MessageBody>,
+) -> Result<ServiceResponse<impl MessageBody>, actix_web::Error> {
+    let parent = TraceContextPropagator::new().extract(&RequestHeaders(req.headers()));
+    let span = info_span!("http_request", method = %req.method(), path = %req.path());
+    // Fails only when spans are not being exported
+    let _ = span.set_parent(parent);
+    next.call(req).instrument(span).await
+}
+
+/// Serve the HTTP API, and the admin socket in `socket` mode, until
+/// `shutdown` is cancelled.
+pub async fn run_server(
+    config: crate::config::Config,
+    storage: Arc<dyn crate::storage::StorageBackend>,
+    audit: Arc<Auditor>,
+    shutdown: CancellationToken,
+    reloader: Arc<crate::reload::ConfigReloader>,
+) -> Result<()> {
+    let addr = format!("{}:{}", config.server.host, config.server.port);
+    let tls = config
+        .server
+        .tls
+        .as_ref()
+        .map(crate::tls::api_server_config)
+        .transpose()?;
+    let scheme = if tls.is_some() { "https" } else { "http" };
+    info!("SHADE server running on {}://{}", scheme, addr);
+
+    let challenges = web::Data::new(crate::challenge::ChallengeStore::new(
+        crate::challenge::CHALLENGE_TTL,
+    ));
 
-    HttpServer::new(move || {
+    spawn_challenge_sweeper(challenges.clone().into_inner());
+    spawn_reaper(
+        storage.clone(),
+        Duration::from_secs(config.server.reap_interval_secs),
+        Duration::from_secs(config.server.expired_key_retention_secs),
+    );
+
+    let live_config = web::Data::new(reloader.subscribe());
+    let app_audit = web::Data::from(audit.clone());
+    let app_storage = storage.clone();
+
+    // Signals are handled by the caller so the proxy drains alongside us
+    let server = HttpServer::new(move || {
         App::new()
-            .app_data(web::Data::new(storage.clone()))
+            .wrap(from_fn(trace_context))
+            .app_data(web::Data::new(app_storage.clone()))
+            .app_data(challenges.clone())
+            .app_data(live_config.clone())
+            .app_data(app_audit.clone())
             .service(index)
             .service(healthcheck)
             .service(return_client_ip)
+            .service(issue_challenge)
             .service(register_client_ip)
+            .service(renew_client_ip)
+            .configure(crate::admin::configure)
             .service(
                 SwaggerUi::new("/swagger-ui/{_:.*}")
                     .url("/api-doc/openapi.json", ApiDoc::openapi()),
             )
     })
-    .bind(&addr)?
-    .run()
-    .await?;
+    .on_connect(crate::admin::on_connect)
+    .disable_signals()
+    .shutdown_timeout(config.server.shutdown_timeout_secs);
+    let server = match tls {
+        Some(tls) => server.bind_rustls_0_23(&addr, tls)?,
+        None => server.bind(&addr)?,
+    }
+    .run();
+
+    // Metrics are kept off the public API, on a listener of their own
+    let metrics_server = match &config.server.metrics_listen_addr {
+        Some(metrics_addr) => {
+            let metrics_storage = storage.clone();
+            let server = HttpServer::new(move || {
+                App::new()
+                    .app_data(web::Data::new(metrics_storage.clone()))
+                    .service(serve_metrics)
+            })
+            .workers(1)
+            .disable_signals()
+            .bind(metrics_addr)
+            .with_context(|| format!("failed to bind metrics listener {}", metrics_addr))?
+            .run();
+            info!("serving metrics on http://{}/metrics", metrics_addr);
+            let handle = server.handle();
+            tokio::spawn(server);
+            Some(handle)
+        }
+        None => None,
+    };
+
+    // Started once the HTTP port is bound, so a failed bind leaves no socket behind
+    let socket_task = if matches!(config.storage.mode, crate::config::StorageMode::Socket) {
+        let socket_path = config.storage.socket_path.as_ref().unwrap();
+        let socket_server = crate::socket::SocketServer::new(
+            socket_path,
+            &config.storage.socket,
+            storage.clone(),
+            audit,
+            reloader,
+        )
+        .await?;
+        let shutdown = shutdown.clone();
+        Some(tokio::spawn(async move {
+            if let Err(e) = socket_server.run(shutdown).await {
+                error!("socket server error: {:#}", e);
+            }
+        }))
+    } else {
+        None
+    };
+
+    let handle = server.handle();
+    let stop = shutdown.clone();
+    tokio::spawn(async move {
+        stop.cancelled().await;
+        if let Some(metrics_server) = metrics_server {
+            metrics_server.stop(true).await;
+        }
+        handle.stop(true).await;
+    });
+    let result = server.await;
+
+    // Whatever stopped the HTTP server stops the socket server with it
+    shutdown.cancel();
+    if let Some(socket_task) = socket_task {
+        socket_task.await?;
+    }
+    Ok(result?)
+}
+
+/// Periodically remove expired challenges, so unanswered ones stop counting
+/// towards the limits on outstanding challenges.
+fn spawn_challenge_sweeper(challenges: Arc<crate::challenge::ChallengeStore>) {
+    tokio::spawn(async move {
+        let mut ticker = tokio::time::interval(challenges.ttl() / 4);
+        loop {
+            ticker.tick().await;
+            challenges.sweep();
+        }
+    });
+}
 
-    Ok(())
+/// Periodically prune expired host leases and delete keys that expired more
+/// than `retention` ago. Keys inside the retention window are kept so
+/// `list-keys` can report them as expired; they are already rejected at
+/// registration.
+fn spawn_reaper(
+    storage: Arc<dyn crate::storage::StorageBackend>,
+    interval: Duration,
+    retention: Duration,
+) {
+    tokio::spawn(async move {
+        let mut ticker = tokio::time::interval(interval);
+        loop {
+            ticker.tick().await;
+            match storage.delete_expired_hosts(chrono::Utc::now()).await {
+                Ok(0) => {}
+                Ok(n) => info!("pruned {} expired host leases", n),
+                Err(e) => error!("Failed to prune expired host leases: {}", e),
+            }
+            let cutoff = match chrono::Duration::from_std(retention) {
+                Ok(retention) => chrono::Utc::now() - retention,
+                Err(_) => continue,
+            };
+            match storage.delete_expired_keys(cutoff).await {
+                Ok(0) => {}
+                Ok(n) => info!("reaped {} expired keys", n),
+                Err(e) => error!("Failed to reap expired keys: {}", e),
+            }
+        }
+    });
 }
 
 pub async fn create_storage(
@@ -164,26 +672,153 @@ pub async fn create_storage(
     Ok(storage)
 }
 
-fn return_ip(req: &actix_web::HttpRequest) -> Option<(&str, String)> {
-    // Attempt to extract the client IP from headers or peer address
-    (|| {
-        // Check common proxy headers first
-        if let Some(forwarded) = req.headers().get("x-forwarded-for")
-            && let Ok(forwarded_str) = forwarded.to_str()
-            && let Some(ip) = forwarded_str.split(',').next()
-        {
-            return Some(("x-forwarded-for", ip.trim().to_string()));
-        }
+fn verify_challenge(challenge: &crate::challenge::Challenge, public_key: &str, mac: &str) -> bool {
+    let Ok(peer) = crate::cert::decode_public_key(public_key) else {
+        return false;
+    };
+    let Ok(mac) = general_purpose::STANDARD.decode(mac.trim()) else {
+        return false;
+    };
+    crate::cert::verify_challenge_mac(&challenge.secret, &peer, &challenge.nonce, &mac)
+        .unwrap_or(false)
+}
+
+fn return_ip(
+    req: &actix_web::HttpRequest,
+    trusted_proxies: &[ipnet::IpNet],
+) -> Option<(&'static str, IpAddr)> {
+    // Forwarding headers are only honoured when the peer is a trusted proxy
+    let peer = req.peer_addr()?.ip();
+    crate::client_ip::resolve(peer, req.headers(), trusted_proxies)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::test as http;
+    use opentelemetry::trace::{SpanId, TraceId, TracerProvider};
+    use opentelemetry_sdk::trace::{InMemorySpanExporter, SdkTracerProvider};
+    use tracing_subscriber::layer::SubscriberExt;
+
+    const TRACE_ID: &str = "4bf92f3577b34da6a3ce929d0e0e4736";
+    const PARENT_ID: &str = "00f067aa0ba902b7";
 
-        if let Some(forwarded) = req.headers().get("forwarded")
-            && let Ok(forwarded_str) = forwarded.to_str()
-            && let Some(ip) = forwarded_str.split('=').nth(1)
-        {
-            return Some(("forwarded", ip.trim().to_string()));
+    /// Answer `challenge` with `private_key`, as `shade register-host` does.
+    fn answer(
+        challenge: crate::models::ChallengeResponse,
+        private_key: &str,
+    ) -> crate::models::RegisterRequest {
+        let secret = crate::cert::decode_private_key(private_key).unwrap();
+        let server_key = crate::cert::decode_public_key(&challenge.server_public_key).unwrap();
+        let nonce = general_purpose::STANDARD.decode(&challenge.nonce).unwrap();
+        let mac = crate::cert::challenge_mac(&secret, &server_key, &nonce).unwrap();
+        crate::models::RegisterRequest {
+            public_key: crate::cert::generate_public_from_private(private_key).unwrap(),
+            challenge_id: challenge.challenge_id,
+            mac: general_purpose::STANDARD.encode(mac),
         }
+    }
+
+    #[actix_web::test]
+    async fn hosts_register_by_answering_a_challenge() {
+        let storage: Arc<dyn crate::storage::StorageBackend> =
+            Arc::new(crate::storage::SqliteStorage::in_memory().await.unwrap());
+        let (private_key, public_key) = crate::cert::generate_keys().unwrap();
+        let key = crate::storage::KeyRecord::new(public_key, None).unwrap();
+        storage.register_key(key.clone()).await.unwrap();
+        let audit = crate::config::AuditConfig {
+            storage: false,
+            file: None,
+        };
+        let audit = Auditor::start(&audit, storage.clone()).await.unwrap();
+        let (_config, live_config) =
+            tokio::sync::watch::channel(Arc::new(crate::config::Config::default()));
+
+        let app = http::init_service(
+            App::new()
+                .app_data(web::Data::new(storage.clone()))
+                .app_data(web::Data::new(crate::challenge::ChallengeStore::new(
+                    crate::challenge::CHALLENGE_TTL,
+                )))
+                .app_data(web::Data::new(live_config))
+                .app_data(web::Data::from(audit))
+                .service(issue_challenge)
+                .service(register_client_ip),
+        )
+        .await;
+        let host = "192.0.2.10:40000".parse().unwrap();
+        let other = "192.0.2.11:40000".parse().unwrap();
+        let challenge = |peer| {
+            http::TestRequest::get()
+                .uri("/challenge")
+                .peer_addr(peer)
+                .to_request()
+        };
+        let register = |peer, body: &crate::models::RegisterRequest| {
+            http::TestRequest::post()
+                .uri("/register")
+                .peer_addr(peer)
+                .set_json(body)
+                .to_request()
+        };
+
+        let issued = http::call_and_read_body_json(&app, challenge(host)).await;
+        let body = answer(issued, &private_key);
+        let resp = http::call_service(&app, register(host, &body)).await;
+        assert_eq!(resp.status(), 200);
+        let registered: crate::models::RegisterResponse = http::read_body_json(resp).await;
+        assert!(matches!(registered.status, RegistrationStatus::New));
 
-        // Fallback to peer address
-        req.peer_addr()
-            .map(|addr| ("peer_addr", addr.ip().to_string()))
-    })()
+        // Challenges are single use
+        let resp = http::call_service(&app, register(host, &body)).await;
+        assert_eq!(resp.status(), 401);
+
+        // A response relayed from another address enrolls nothing
+        let issued = http::call_and_read_body_json(&app, challenge(host)).await;
+        let body = answer(issued, &private_key);
+        let resp = http::call_service(&app, register(other, &body)).await;
+        assert_eq!(resp.status(), 401);
+
+        let hosts = storage.list_hosts().await.unwrap();
+        assert_eq!(hosts.len(), 1);
+        assert_eq!(hosts[0].network, "192.0.2.10/32".parse::<IpNet>().unwrap());
+        assert_eq!(hosts[0].key_id, Some(key.id));
+    }
+
+    #[test]
+    fn requests_are_traced_continuing_the_callers_trace() {
+        let exporter = InMemorySpanExporter::default();
+        let provider = SdkTracerProvider::builder()
+            .with_simple_exporter(exporter.clone())
+            .build();
+        let subscriber = tracing_subscriber::registry()
+            .with(tracing_opentelemetry::layer().with_tracer(provider.tracer("test")));
+
+        tracing::subscriber::with_default(subscriber, || {
+            actix_web::rt::System::new().block_on(async {
+                let app = http::init_service(
+                    App::new()
+                        .wrap(from_fn(trace_context))
+                        .route("/ok", web::get().to(HttpResponse::Ok)),
+                )
+                .await;
+                let req = http::TestRequest::get()
+                    .uri("/ok")
+                    .insert_header(("traceparent", format!("00-{TRACE_ID}-{PARENT_ID}-01")))
+                    .to_request();
+                assert!(http::call_service(&app, req).await.status().is_success());
+            })
+        });
+
+        let spans = exporter.get_finished_spans().unwrap();
+        let span = spans
+            .iter()
+            .find(|span| span.name == "http_request")
+            .expect("no request span was exported");
+        assert_eq!(
+            span.span_context.trace_id(),
+            TraceId::from_hex(TRACE_ID).unwrap()
+        );
+        assert_eq!(span.parent_span_id, SpanId::from_hex(PARENT_ID).unwrap());
+    }
 }
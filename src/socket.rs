@@ -1,92 +1,275 @@
-use anyhow::Result;
+mod access;
+
+use crate::audit::{AuditAction, AuditEvent, Auditor, Decision};
+use crate::config::SocketConfig;
+use anyhow::{Context, Result};
+use chrono::{DateTime, Utc};
 use futures_util::{SinkExt, StreamExt};
+use ipnet::IpNet;
 use serde::{Deserialize, Serialize};
 use std::path::Path;
 use std::sync::Arc;
+use std::time::Duration;
 use tokio::net::{UnixListener, UnixStream};
 use tokio_util::codec::{Framed, LengthDelimitedCodec};
+use tokio_util::sync::CancellationToken;
+use tracing::{error, info, warn};
+use uuid::Uuid;
+
+use access::{Role, SocketAccess};
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub enum SocketMessage {
-    Register(crate::storage::KeyPair),
-    Revoke { id: String },
+    Register {
+        public_key: String,
+        expires_at: Option<DateTime<Utc>>,
+        #[serde(default)]
+        ipv4_prefix_len: Option<u8>,
+        #[serde(default)]
+        ipv6_prefix_len: Option<u8>,
+        #[serde(default)]
+        groups: Vec<String>,
+        #[serde(default)]
+        spki_sha256: Option<String>,
+    },
+    Revoke {
+        id: String,
+    },
     List,
+    AllowCidr {
+        network: IpNet,
+        key_id: Option<Uuid>,
+        expires_at: Option<DateTime<Utc>>,
+    },
+    /// Re-read the server's configuration file
+    Reload,
+}
+
+impl SocketMessage {
+    /// The least a peer must be allowed to send this message
+    fn required_role(&self) -> Role {
+        match self {
+            SocketMessage::List => Role::ReadOnly,
+            SocketMessage::Register { .. }
+            | SocketMessage::Revoke { .. }
+            | SocketMessage::AllowCidr { .. }
+            | SocketMessage::Reload => Role::Admin,
+        }
+    }
+
+    /// The audit event for a refused change to keys, networks or configuration
+    fn denied_event(&self, actor: &str) -> Option<AuditEvent> {
+        let event = match self {
+            SocketMessage::Register { .. } => {
+                AuditEvent::new(AuditAction::KeyRegistered, actor, Decision::Deny)
+            }
+            SocketMessage::Revoke { id } => {
+                AuditEvent::new(AuditAction::KeyRevoked, actor, Decision::Deny)
+                    .with_key(Uuid::parse_str(id).ok())
+            }
+            SocketMessage::AllowCidr {
+                network, key_id, ..
+            } => AuditEvent::new(AuditAction::CidrAllowed, actor, Decision::Deny)
+                .with_key(*key_id)
+                .with_network(Some(*network)),
+            SocketMessage::Reload => {
+                AuditEvent::new(AuditAction::AdminAccess, actor, Decision::Deny)
+            }
+            SocketMessage::List => return None,
+        };
+        Some(event.with_reason("permission_denied"))
+    }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub enum SocketResponse {
-    KeyRegistered(crate::storage::KeyPair),
-    KeyRevoked,
-    KeyList(Vec<crate::storage::KeyPair>),
+    KeyRegistered(crate::storage::KeyRecord),
+    KeyRevoked { hosts_removed: u64 },
+    KeyList(Vec<crate::storage::KeyRecord>),
+    CidrAllowed,
+    Reloaded(crate::reload::ReloadReport),
     Error(String),
 }
 
 pub struct SocketServer {
     listener: UnixListener,
+    path: String,
     storage: Arc<dyn crate::storage::StorageBackend>,
+    audit: Arc<Auditor>,
+    reloader: Arc<crate::reload::ConfigReloader>,
+    access: Arc<SocketAccess>,
 }
 
+/// How long to wait before accepting again after a failed accept
+const ACCEPT_BACKOFF: Duration = Duration::from_millis(100);
+
 impl SocketServer {
     pub async fn new(
         socket_path: &str,
+        config: &SocketConfig,
         storage: Arc<dyn crate::storage::StorageBackend>,
+        audit: Arc<Auditor>,
+        reloader: Arc<crate::reload::ConfigReloader>,
     ) -> Result<Self> {
         if Path::new(socket_path).exists() {
             std::fs::remove_file(socket_path)?;
         }
 
-        let listener = UnixListener::bind(socket_path)?;
-        Ok(Self { listener, storage })
+        let access = Arc::new(SocketAccess::new(config)?);
+        let listener = access::bind(socket_path, config)?;
+        Ok(Self {
+            listener,
+            path: socket_path.to_string(),
+            storage,
+            audit,
+            reloader,
+            access,
+        })
     }
 
-    pub async fn run(&self) -> Result<()> {
-        println!(
-            "Socket server listening on {}",
-            self.listener
-                .local_addr()?
-                .as_pathname()
-                .unwrap()
-                .to_string_lossy()
-        );
-
-        while let Ok((stream, _addr)) = self.listener.accept().await {
+    /// Serve requests until `shutdown` is cancelled, then remove the socket
+    /// file. Requests already being handled are left to finish.
+    pub async fn run(&self, shutdown: CancellationToken) -> Result<()> {
+        info!(path = %self.path, "socket server listening");
+
+        loop {
+            let accepted = tokio::select! {
+                accepted = self.listener.accept() => accepted,
+                _ = shutdown.cancelled() => break,
+            };
+            let stream = match accepted {
+                Ok((stream, _addr)) => stream,
+                Err(e) => {
+                    // Usually out of file descriptors; give connections
+                    // being served a moment to close some
+                    error!("error accepting socket connection: {}", e);
+                    tokio::select! {
+                        _ = tokio::time::sleep(ACCEPT_BACKOFF) => continue,
+                        _ = shutdown.cancelled() => break,
+                    }
+                }
+            };
             let storage = self.storage.clone();
+            let audit = self.audit.clone();
+            let reloader = self.reloader.clone();
+            let access = self.access.clone();
             tokio::spawn(async move {
-                if let Err(e) = Self::handle_connection(stream, storage).await {
-                    eprintln!("Error handling connection: {}", e);
+                if let Err(e) =
+                    Self::handle_connection(stream, storage, audit, reloader, access).await
+                {
+                    error!("error handling socket connection: {:#}", e);
                 }
             });
         }
 
+        std::fs::remove_file(&self.path)
+            .with_context(|| format!("failed to remove socket {}", self.path))?;
         Ok(())
     }
 
     async fn handle_connection(
         stream: UnixStream,
         storage: Arc<dyn crate::storage::StorageBackend>,
+        audit: Arc<Auditor>,
+        reloader: Arc<crate::reload::ConfigReloader>,
+        access: Arc<SocketAccess>,
     ) -> Result<()> {
+        let peer = stream.peer_cred()?;
+        let role = access.role(&peer);
+        let actor = format!("socket:uid={}", peer.uid());
         let mut framed = Framed::new(stream, LengthDelimitedCodec::new());
 
         while let Some(frame) = framed.next().await {
             let frame = frame?;
             let message: SocketMessage = serde_json::from_slice(&frame)?;
 
+            let required = message.required_role();
+            if role.is_none_or(|role| role < required) {
+                warn!(
+                    uid = peer.uid(),
+                    gid = peer.gid(),
+                    peer_pid = ?peer.pid(),
+                    ?required,
+                    "socket request denied"
+                );
+                if let Some(event) = message.denied_event(&actor) {
+                    audit.record(event);
+                }
+                let response = SocketResponse::Error(format!(
+                    "permission denied: uid {} may not send this request",
+                    peer.uid()
+                ));
+                framed.send(serde_json::to_vec(&response)?.into()).await?;
+                continue;
+            }
+
             let response = match message {
-                SocketMessage::Register(kp) => match storage.register_key(kp.clone()).await {
-                    Ok(_) => SocketResponse::KeyRegistered(kp),
+                SocketMessage::Register {
+                    public_key,
+                    expires_at,
+                    ipv4_prefix_len,
+                    ipv6_prefix_len,
+                    groups,
+                    spki_sha256,
+                } => match crate::storage::KeyRecord::new(public_key, expires_at)
+                    .and_then(|key| key.with_prefix_lens(ipv4_prefix_len, ipv6_prefix_len))
+                    .and_then(|key| key.with_groups(groups))
+                    .and_then(|key| key.with_spki_sha256(spki_sha256))
+                {
+                    Ok(key) => {
+                        let registered = storage.register_key(key.clone()).await;
+                        let event =
+                            AuditEvent::new(AuditAction::KeyRegistered, &actor, Decision::Allow)
+                                .with_key(Some(key.id));
+                        audit.record(event.with_outcome(&registered));
+                        match registered {
+                            Ok(_) => SocketResponse::KeyRegistered(key),
+                            Err(e) => SocketResponse::Error(e.to_string()),
+                        }
+                    }
                     Err(e) => SocketResponse::Error(e.to_string()),
                 },
                 SocketMessage::Revoke { id } => match uuid::Uuid::parse_str(&id) {
-                    Ok(uuid) => match storage.revoke_key(uuid).await {
-                        Ok(_) => SocketResponse::KeyRevoked,
-                        Err(e) => SocketResponse::Error(e.to_string()),
-                    },
+                    Ok(uuid) => {
+                        let revoked = storage.revoke_key(uuid).await;
+                        let event =
+                            AuditEvent::new(AuditAction::KeyRevoked, &actor, Decision::Allow)
+                                .with_key(Some(uuid));
+                        audit.record(event.with_found(&revoked));
+                        match revoked {
+                            Ok(Some(hosts_removed)) => SocketResponse::KeyRevoked { hosts_removed },
+                            Ok(None) => SocketResponse::Error(format!("key {} not found", uuid)),
+                            Err(e) => SocketResponse::Error(e.to_string()),
+                        }
+                    }
                     Err(e) => SocketResponse::Error(e.to_string()),
                 },
                 SocketMessage::List => match storage.list_keys().await {
                     Ok(keys) => SocketResponse::KeyList(keys),
                     Err(e) => SocketResponse::Error(e.to_string()),
                 },
+                SocketMessage::AllowCidr {
+                    network,
+                    key_id,
+                    expires_at,
+                } => {
+                    let allowed = storage.allow_network(network, key_id, expires_at).await;
+                    let event = AuditEvent::new(AuditAction::CidrAllowed, &actor, Decision::Allow)
+                        .with_key(key_id)
+                        .with_network(Some(network));
+                    audit.record(event.with_outcome(&allowed));
+                    match allowed {
+                        Ok(_) => SocketResponse::CidrAllowed,
+                        Err(e) => SocketResponse::Error(e.to_string()),
+                    }
+                }
+                SocketMessage::Reload => match reloader.reload().await {
+                    Ok(report) => {
+                        info!("configuration reloaded: {}", report);
+                        SocketResponse::Reloaded(report)
+                    }
+                    Err(e) => SocketResponse::Error(format!("{:#}", e)),
+                },
             };
 
             let response_bytes = serde_json::to_vec(&response)?;
@@ -0,0 +1,266 @@
+use crate::config::{SocketConfig, SocketPeers};
+use anyhow::{Context, Result};
+use nix::unistd::{Gid, Group, Uid, User};
+use std::collections::HashSet;
+use std::os::unix::fs::{DirBuilderExt, PermissionsExt};
+use std::path::{Path, PathBuf};
+use tokio::net::unix::UCred;
+use tokio::net::UnixListener;
+
+/// What a peer on the socket may do
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Role {
+    /// List keys
+    ReadOnly,
+    /// Everything, including changes to keys and networks
+    Admin,
+}
+
+/// The configured socket users, resolved to ids when the server starts.
+/// Changes to the user and group databases apply on restart.
+#[derive(Debug)]
+pub struct SocketAccess {
+    admins: Peers,
+    readers: Peers,
+}
+
+#[derive(Debug, Default)]
+struct Peers {
+    uids: HashSet<u32>,
+    gids: HashSet<u32>,
+}
+
+impl SocketAccess {
+    pub fn new(config: &SocketConfig) -> Result<Self> {
+        let mut admins = Peers::resolve(&config.admins)?;
+        // Root and the server's own user can reach the database anyway
+        admins.uids.insert(0);
+        admins.uids.insert(Uid::current().as_raw());
+        Ok(Self {
+            admins,
+            readers: Peers::resolve(&config.readers)?,
+        })
+    }
+
+    /// The role of the process on the other end of a connection, if any.
+    pub fn role(&self, peer: &UCred) -> Option<Role> {
+        self.role_of(peer.uid(), peer.gid())
+    }
+
+    fn role_of(&self, uid: u32, gid: u32) -> Option<Role> {
+        if self.admins.contains(uid, gid) {
+            Some(Role::Admin)
+        } else if self.readers.contains(uid, gid) {
+            Some(Role::ReadOnly)
+        } else {
+            None
+        }
+    }
+}
+
+impl Peers {
+    fn resolve(peers: &SocketPeers) -> Result<Self> {
+        let mut resolved = Peers::default();
+        for user in &peers.users {
+            resolved.uids.insert(user_id(user)?);
+        }
+        for group in &peers.groups {
+            let (gid, members) = group_members(group)?;
+            resolved.gids.insert(gid);
+            resolved.uids.extend(members);
+        }
+        Ok(resolved)
+    }
+
+    fn contains(&self, uid: u32, gid: u32) -> bool {
+        self.uids.contains(&uid) || self.gids.contains(&gid)
+    }
+}
+
+/// Bind the socket at `path` with its mode and ownership already applied.
+/// The socket is created inside a private 0700 directory next to `path` and
+/// only renamed into place once [`apply_permissions`] has run, so no peer can
+/// connect while it still has the default mode.
+pub fn bind(path: &str, config: &SocketConfig) -> Result<UnixListener> {
+    let target = Path::new(path);
+    let name = target
+        .file_name()
+        .with_context(|| format!("invalid socket path {}", path))?;
+    let staging = create_staging(target)?;
+    let staged = staging.join(name);
+    let result = UnixListener::bind(&staged)
+        .with_context(|| format!("failed to bind socket {}", path))
+        .and_then(|listener| {
+            apply_permissions(&staged.to_string_lossy(), config)?;
+            std::fs::rename(&staged, target)
+                .with_context(|| format!("failed to move socket into place at {}", path))?;
+            Ok(listener)
+        });
+    let _ = std::fs::remove_file(&staged);
+    let _ = std::fs::remove_dir(&staging);
+    result
+}
+
+/// Create the private directory a socket at `target` is bound in. Creation
+/// fails rather than reuse a directory that is already there.
+fn create_staging(target: &Path) -> Result<PathBuf> {
+    let name = target
+        .file_name()
+        .with_context(|| format!("invalid socket path {}", target.display()))?;
+    let parent = match target.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent,
+        _ => Path::new("."),
+    };
+    let staging = parent.join(format!(
+        ".{}.{}",
+        name.to_string_lossy(),
+        std::process::id()
+    ));
+    std::fs::DirBuilder::new()
+        .mode(0o700)
+        .create(&staging)
+        .with_context(|| format!("failed to create {}", staging.display()))?;
+    Ok(staging)
+}
+
+/// Set the mode, owner and group of the socket file at `path`.
+pub fn apply_permissions(path: &str, config: &SocketConfig) -> Result<()> {
+    let owner = config.owner.as_deref().map(user_id).transpose()?;
+    let group = config
+        .group
+        .as_deref()
+        .map(|group| group_members(group).map(|(gid, _)| gid))
+        .transpose()?;
+    if owner.is_some() || group.is_some() {
+        std::os::unix::fs::chown(path, owner, group)
+            .with_context(|| format!("failed to change owner of socket {}", path))?;
+    }
+    if let Some(mode) = config.file_mode()? {
+        std::fs::set_permissions(path, std::fs::Permissions::from_mode(mode))
+            .with_context(|| format!("failed to set mode of socket {}", path))?;
+    }
+    Ok(())
+}
+
+fn user_id(user: &str) -> Result<u32> {
+    if let Ok(uid) = user.parse() {
+        return Ok(uid);
+    }
+    User::from_name(user)
+        .with_context(|| format!("failed to look up user {:?}", user))?
+        .map(|user| user.uid.as_raw())
+        .ok_or_else(|| anyhow::anyhow!("unknown user {:?}", user))
+}
+
+/// A group's gid, and the uids of the users it lists as members.
+fn group_members(group: &str) -> Result<(u32, Vec<u32>)> {
+    let gid: Option<u32> = group.parse().ok();
+    let entry = match gid {
+        Some(gid) => Group::from_gid(Gid::from_raw(gid)),
+        None => Group::from_name(group),
+    }
+    .with_context(|| format!("failed to look up group {:?}", group))?;
+    match (entry, gid) {
+        (Some(entry), _) => {
+            let members = entry
+                .mem
+                .iter()
+                .filter_map(|member| User::from_name(member).ok().flatten())
+                .map(|user| user.uid.as_raw())
+                .collect();
+            Ok((entry.gid.as_raw(), members))
+        }
+        // A gid with no entry still matches peers' primary group
+        (None, Some(gid)) => Ok((gid, Vec::new())),
+        (None, None) => anyhow::bail!("unknown group {:?}", group),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn peers(users: &[&str], groups: &[&str]) -> SocketPeers {
+        SocketPeers {
+            users: users.iter().map(|user| user.to_string()).collect(),
+            groups: groups.iter().map(|group| group.to_string()).collect(),
+        }
+    }
+
+    #[test]
+    fn peers_are_given_the_role_they_are_configured_with() {
+        // Numeric ids with no passwd or group entry still resolve
+        let config = SocketConfig {
+            admins: peers(&["70001"], &["70002"]),
+            readers: peers(&["70003"], &["70004"]),
+            ..SocketConfig::default()
+        };
+        let access = SocketAccess::new(&config).unwrap();
+        let own = Uid::current().as_raw();
+
+        assert_eq!(access.role_of(0, 0), Some(Role::Admin));
+        assert_eq!(access.role_of(own, 70099), Some(Role::Admin));
+        assert_eq!(access.role_of(70001, 70099), Some(Role::Admin));
+        assert_eq!(access.role_of(70099, 70002), Some(Role::Admin));
+        assert_eq!(access.role_of(70003, 70099), Some(Role::ReadOnly));
+        assert_eq!(access.role_of(70099, 70004), Some(Role::ReadOnly));
+        // An admin that is also listed as a reader keeps the higher role
+        assert_eq!(access.role_of(70001, 70004), Some(Role::Admin));
+        assert_eq!(access.role_of(70099, 70099), None);
+    }
+
+    #[test]
+    fn unknown_names_are_rejected() {
+        let config = SocketConfig {
+            readers: peers(&["no-such-user-shade"], &[]),
+            ..SocketConfig::default()
+        };
+        assert!(SocketAccess::new(&config).is_err());
+    }
+
+    #[tokio::test]
+    async fn sockets_are_bound_with_their_mode_and_no_staging_left() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("shade.sock");
+        let config = SocketConfig {
+            mode: Some("0640".to_string()),
+            ..SocketConfig::default()
+        };
+
+        let _listener = bind(&path.to_string_lossy(), &config).unwrap();
+        let mode = std::fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o7777, 0o640);
+        let entries: Vec<_> = std::fs::read_dir(dir.path())
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name())
+            .collect();
+        assert_eq!(entries, ["shade.sock"]);
+        tokio::net::UnixStream::connect(&path).await.unwrap();
+    }
+
+    #[test]
+    fn sockets_are_staged_in_a_private_directory() {
+        let dir = tempfile::tempdir().unwrap();
+        let target = dir.path().join("shade.sock");
+
+        let staging = create_staging(&target).unwrap();
+        assert_eq!(staging.parent(), Some(dir.path()));
+        let mode = std::fs::metadata(&staging).unwrap().permissions().mode();
+        assert_eq!(mode & 0o7777, 0o700);
+        // A directory already in the way, however it got there, is not reused
+        assert!(create_staging(&target).is_err());
+    }
+
+    #[tokio::test]
+    async fn binding_fails_cleanly_when_the_mode_is_invalid() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("shade.sock");
+        let config = SocketConfig {
+            mode: Some("rw-rw----".to_string()),
+            ..SocketConfig::default()
+        };
+
+        assert!(bind(&path.to_string_lossy(), &config).is_err());
+        assert_eq!(std::fs::read_dir(dir.path()).unwrap().count(), 0);
+    }
+}
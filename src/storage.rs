@@ -1,46 +1,270 @@
-use anyhow::Result;
+use anyhow::{Context, Result};
 use async_trait::async_trait;
+use base64::{engine::general_purpose, Engine as _};
 use chrono::{DateTime, Utc};
+use ipnet::IpNet;
 use serde::{Deserialize, Serialize};
 use std::fmt::Debug;
+use std::net::IpAddr;
+use tokio::sync::broadcast;
 use uuid::Uuid;
 
+/// A registered client key. Only the public half is ever held by the server.
 #[derive(Debug, Clone, Serialize, Deserialize)]
-pub struct KeyPair {
+pub struct KeyRecord {
     pub id: Uuid,
     pub public_key: String,
-    pub private_key: String,
     pub created_at: DateTime<Utc>,
     pub expires_at: Option<DateTime<Utc>>,
+    /// Widen hosts registered with this key to a network of this prefix length
+    #[serde(default)]
+    pub ipv4_prefix_len: Option<u8>,
+    #[serde(default)]
+    pub ipv6_prefix_len: Option<u8>,
+    /// Groups used to grant the key access to restricted proxy routes
+    #[serde(default)]
+    pub groups: Vec<String>,
+    /// Base64 SHA-256 of the SPKI of a client certificate pinned to this key
+    #[serde(default)]
+    pub spki_sha256: Option<String>,
 }
 
-impl KeyPair {
-    pub fn new(private_key: String, expires_at: Option<DateTime<Utc>>) -> anyhow::Result<Self> {
-        let public_key = crate::cert::generate_public_from_private(&private_key)?;
+impl KeyRecord {
+    pub fn new(public_key: String, expires_at: Option<DateTime<Utc>>) -> anyhow::Result<Self> {
+        // Reject anything that is not a well-formed x25519 public key
+        crate::cert::decode_public_key(&public_key)
+            .context("public_key is not a valid base64 encoded x25519 key")?;
         Ok(Self {
             id: Uuid::new_v4(),
-            private_key,
-            public_key,
+            public_key: public_key.trim().to_string(),
             created_at: Utc::now(),
             expires_at,
+            ipv4_prefix_len: None,
+            ipv6_prefix_len: None,
+            groups: Vec::new(),
+            spki_sha256: None,
         })
     }
+
+    pub fn with_groups(mut self, groups: Vec<String>) -> anyhow::Result<Self> {
+        for group in &groups {
+            let valid = !group.is_empty()
+                && group
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.'));
+            if !valid {
+                anyhow::bail!(
+                    "invalid group name {:?}: use letters, digits, '-', '_' or '.'",
+                    group
+                );
+            }
+        }
+        self.groups = groups;
+        self.groups.sort();
+        self.groups.dedup();
+        Ok(self)
+    }
+
+    pub fn with_spki_sha256(mut self, spki_sha256: Option<String>) -> anyhow::Result<Self> {
+        if let Some(pin) = &spki_sha256 {
+            let digest = general_purpose::STANDARD
+                .decode(pin.trim())
+                .context("spki_sha256 is not valid base64")?;
+            if digest.len() != 32 {
+                anyhow::bail!("spki_sha256 must be a base64 encoded SHA-256 digest");
+            }
+        }
+        self.spki_sha256 = spki_sha256.map(|pin| pin.trim().to_string());
+        Ok(self)
+    }
+
+    pub fn with_prefix_lens(
+        mut self,
+        ipv4_prefix_len: Option<u8>,
+        ipv6_prefix_len: Option<u8>,
+    ) -> anyhow::Result<Self> {
+        if ipv4_prefix_len.is_some_and(|len| len > 32) {
+            anyhow::bail!("ipv4_prefix_len must be between 0 and 32");
+        }
+        if ipv6_prefix_len.is_some_and(|len| len > 128) {
+            anyhow::bail!("ipv6_prefix_len must be between 0 and 128");
+        }
+        self.ipv4_prefix_len = ipv4_prefix_len;
+        self.ipv6_prefix_len = ipv6_prefix_len;
+        Ok(self)
+    }
+
+    /// The network enrolled when a host registers from `ip` with this key.
+    /// IPv4-mapped IPv6 addresses are treated as IPv4.
+    pub fn host_network(&self, ip: IpAddr) -> IpNet {
+        let ip = ip.to_canonical();
+        let prefix_len = match ip {
+            IpAddr::V4(_) => self.ipv4_prefix_len.unwrap_or(32),
+            IpAddr::V6(_) => self.ipv6_prefix_len.unwrap_or(128),
+        };
+        // Prefix lengths are validated on construction
+        IpNet::new(ip, prefix_len)
+            .map(|net| net.trunc())
+            .unwrap_or_else(|_| IpNet::from(ip))
+    }
+
+    pub fn is_expired(&self) -> bool {
+        self.expires_at
+            .is_some_and(|expires_at| expires_at <= Utc::now())
+    }
+
+    pub fn status(&self) -> KeyStatus {
+        if self.is_expired() {
+            KeyStatus::Expired
+        } else {
+            KeyStatus::Active
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KeyStatus {
+    Active,
+    Expired,
+}
+
+impl std::fmt::Display for KeyStatus {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            KeyStatus::Active => write!(f, "active"),
+            KeyStatus::Expired => write!(f, "expired"),
+        }
+    }
 }
 
 pub struct HostPair {
-    pub ip: String,
+    pub network: IpNet,
+    pub key_id: Option<Uuid>, // None for hosts enrolled before keys were tracked
     pub created_at: DateTime<Utc>,
+    pub expires_at: Option<DateTime<Utc>>,
+}
+
+/// A host entry as seen by the proxy: the network it allows and when it
+/// stops being valid, whichever of its lease and its key expires first.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AllowEntry {
+    pub network: IpNet,
+    pub key_id: Option<Uuid>,
+    pub groups: Vec<String>, // groups of the owning key
+    pub expires_at: Option<DateTime<Utc>>,
+}
+
+/// Notification published by a storage backend whenever the set of allowed
+/// hosts changes. Entries that simply run past their expiry are not
+/// announced; consumers are expected to check `expires_at` themselves.
+#[derive(Debug, Clone)]
+pub enum HostChange {
+    /// An entry was added, or its owner or expiry changed.
+    Upserted(AllowEntry),
+    /// An entry was removed.
+    Removed(IpNet),
+    /// A key was revoked along with every entry it owned.
+    KeyRevoked(Uuid),
+}
+
+/// Truncate `network` to its prefix and express IPv4-mapped IPv6 prefixes
+/// (`::ffff:0:0/96` and longer) as plain IPv4 networks.
+pub fn normalize_network(network: IpNet) -> IpNet {
+    if let IpNet::V6(v6) = network
+        && let Some(v4) = v6.addr().to_ipv4_mapped()
+        && v6.prefix_len() >= 96
+        && let Ok(net) = ipnet::Ipv4Net::new(v4, v6.prefix_len() - 96)
+    {
+        return IpNet::V4(net.trunc());
+    }
+    network.trunc()
+}
+
+/// What a host registration did to the enrolled hosts for its key.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum HostRegistration {
+    /// The IP was not previously enrolled by this key.
+    New,
+    /// The IP was already enrolled by this key; its lease and timestamps were refreshed.
+    Refreshed,
+    /// The key was at its IP limit, so its oldest IPs were replaced by this one.
+    Moved { replaced: Vec<IpNet> },
+    /// The network is held by another key's unexpired lease or by a static
+    /// entry; nothing was changed.
+    Taken,
 }
 
 #[async_trait]
 pub trait StorageBackend: Send + Sync + Debug {
-    async fn register_key(&self, keypair: KeyPair) -> Result<()>;
-    async fn revoke_key(&self, id: Uuid) -> Result<()>;
-    async fn list_keys(&self) -> Result<Vec<KeyPair>>;
-    async fn validate_public_key(&self, public_key: &str) -> Result<bool>;
-    async fn validate_host_ip(&self, ip_address: &str) -> Result<bool>;
-    async fn store_client_ip(&self, ip_address: String) -> Result<()>;
+    async fn register_key(&self, key: KeyRecord) -> Result<()>;
+    /// Delete a key along with every host it enrolled, returning the number
+    /// of hosts removed, or None when there is no such key.
+    async fn revoke_key(&self, id: Uuid) -> Result<Option<u64>>;
+    async fn list_keys(&self) -> Result<Vec<KeyRecord>>;
+    /// Count keys as (active, expired).
+    async fn count_keys(&self) -> Result<(u64, u64)>;
+    async fn get_key(&self, id: Uuid) -> Result<Option<KeyRecord>>;
+    /// Set when a key expires. Hosts it enrolled are kept, but stop being
+    /// allowed along with it. Returns false when there is no such key.
+    async fn expire_key(&self, id: Uuid, expires_at: DateTime<Utc>) -> Result<bool>;
+    /// Look up a key by its public half, preferring an unexpired record when
+    /// the same public key has been registered more than once.
+    async fn find_key(&self, public_key: &str) -> Result<Option<KeyRecord>>;
+    /// Look up the key a client certificate is pinned to by the base64
+    /// SHA-256 of its SPKI, preferring an unexpired record like `find_key`.
+    async fn find_key_by_spki(&self, spki_sha256: &str) -> Result<Option<KeyRecord>>;
+    async fn delete_expired_keys(&self, expired_before: DateTime<Utc>) -> Result<u64>;
+    /// Every unexpired host entry that `ip` falls inside.
+    async fn find_allow_entries(&self, ip: IpAddr) -> Result<Vec<AllowEntry>>;
+    /// Enroll `network` for `key_id`, refreshing it if already enrolled.
+    /// When `max_ips_per_key` is reached the key's least recently seen
+    /// entries are replaced.
+    async fn store_client_ip(
+        &self,
+        network: IpNet,
+        key_id: Uuid,
+        expires_at: Option<DateTime<Utc>>,
+        max_ips_per_key: Option<u32>,
+    ) -> Result<HostRegistration>;
+    /// Extend the lease on `network` if it is held by `key_id`. Returns
+    /// false when there is no such lease to renew.
+    async fn renew_client_ip(
+        &self,
+        network: IpNet,
+        key_id: Uuid,
+        expires_at: Option<DateTime<Utc>>,
+    ) -> Result<bool>;
+    /// Add a static allow entry, optionally owned by a key so that revoking
+    /// the key removes it.
+    async fn allow_network(
+        &self,
+        network: IpNet,
+        key_id: Option<Uuid>,
+        expires_at: Option<DateTime<Utc>>,
+    ) -> Result<()>;
+    async fn delete_expired_hosts(&self, now: DateTime<Utc>) -> Result<u64>;
     async fn list_hosts(&self) -> Result<Vec<HostPair>>;
+    async fn count_hosts(&self) -> Result<u64>;
+    /// Delete the host entry for exactly `network`. Returns false when there
+    /// is no such entry.
+    async fn delete_host(&self, network: IpNet) -> Result<bool>;
+    /// Every host entry that is currently valid, for loading the proxy's
+    /// in-memory allowlist.
+    async fn list_allow_entries(&self) -> Result<Vec<AllowEntry>>;
+    /// A counter bumped by every change to hosts or keys, including those
+    /// made by other processes, so the allowlist can tell when to reload.
+    async fn allowlist_version(&self) -> Result<i64>;
+    /// Append an event to the audit log.
+    async fn record_audit_event(&self, event: &crate::audit::AuditEvent) -> Result<()>;
+    /// Audit events matching `filter`, oldest first.
+    async fn query_audit_events(
+        &self,
+        filter: &crate::audit::AuditFilter,
+    ) -> Result<Vec<crate::audit::AuditEvent>>;
+    /// Receive a `HostChange` for every change made through this backend.
+    fn subscribe(&self) -> broadcast::Receiver<HostChange>;
+    /// Wait for in-flight queries and release the underlying connections.
+    async fn close(&self);
 }
 
 pub mod sqlite;
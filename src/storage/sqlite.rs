@@ -1,14 +1,27 @@
-use super::StorageBackend;
+use super::{AllowEntry, HostChange, HostRegistration, StorageBackend};
+use crate::audit::{AuditEvent, AuditFilter};
+use crate::metrics::metrics;
 use anyhow::Result;
 use async_trait::async_trait;
-use chrono::Utc;
-use sqlx::{Pool, Row, Sqlite, SqlitePool};
+use chrono::{DateTime, Utc};
+use ipnet::IpNet;
+use sqlx::sqlite::SqliteRow;
+use sqlx::{Pool, QueryBuilder, Row, Sqlite, SqlitePool};
 use std::fmt::Debug;
+use std::net::IpAddr;
+use tokio::sync::broadcast;
+use tracing::info;
 use uuid::Uuid;
 
+static MIGRATION_LOCK: tokio::sync::Mutex<()> = tokio::sync::Mutex::const_new(());
+
+/// Pending change notifications held per subscriber before it is told it lagged
+const CHANGE_CAPACITY: usize = 1024;
+
 #[derive(Debug)]
 pub struct SqliteStorage {
     pool: Pool<Sqlite>,
+    changes: broadcast::Sender<HostChange>,
 }
 
 impl SqliteStorage {
@@ -18,103 +31,931 @@ impl SqliteStorage {
         {
             std::fs::File::create(path)?;
         }
-        let pool = SqlitePool::connect(database_url).await?;
-        println!("Running initial DB migration");
+        Self::with_pool(SqlitePool::connect(database_url).await?).await
+    }
+
+    /// A fresh private database, for tests. It lives on the pool's one
+    /// connection, which is never closed.
+    #[cfg(test)]
+    pub async fn in_memory() -> Result<Self> {
+        let pool = sqlx::sqlite::SqlitePoolOptions::new()
+            .max_connections(1)
+            .min_connections(1)
+            .idle_timeout(None)
+            .max_lifetime(None)
+            .connect("sqlite::memory:")
+            .await?;
+        Self::with_pool(pool).await
+    }
+
+    async fn with_pool(pool: Pool<Sqlite>) -> Result<Self> {
+        info!("running database migrations");
+
+        // `shade server` opens storage for the proxy and the HTTP server at the
+        // same time; serialise migrations so they don't race on a fresh database
+        let _guard = MIGRATION_LOCK.lock().await;
+        sqlx::migrate!("src/migrations").run(&pool).await?;
+
+        let (changes, _) = broadcast::channel(CHANGE_CAPACITY);
+        let storage = Self { pool, changes };
+        storage.backfill_host_networks().await?;
+
+        Ok(storage)
+    }
+
+    /// Convert hosts stored before entries were networks into `/32` or
+    /// `/128` prefixes with range bounds.
+    async fn backfill_host_networks(&self) -> Result<()> {
+        let legacy: Vec<String> =
+            sqlx::query_scalar("SELECT ip_address FROM client_ips WHERE net_start IS NULL")
+                .fetch_all(&self.pool)
+                .await?;
+
+        for ip_address in legacy {
+            let network = match ip_address.parse::<IpNet>() {
+                Ok(network) => network,
+                Err(_) => IpNet::from(ip_address.parse::<IpAddr>()?.to_canonical()),
+            };
+            let (start, end) = network_bounds(&network);
+            sqlx::query(
+                r#"
+                UPDATE OR REPLACE client_ips SET ip_address = ?, net_start = ?, net_end = ?
+                WHERE ip_address = ?
+                "#,
+            )
+            .bind(network.to_string())
+            .bind(&start[..])
+            .bind(&end[..])
+            .bind(&ip_address)
+            .execute(&self.pool)
+            .await?;
+        }
+
+        Ok(())
+    }
+
+    fn notify(&self, change: HostChange) {
+        // Nobody listening (e.g. a one-off CLI command) is not an error
+        let _ = self.changes.send(change);
+    }
 
-        let migration_sql = include_str!("../migrations/001_initial.sql");
-        sqlx::query(migration_sql).execute(&pool).await?;
+    async fn notify_upserted(&self, network: &IpNet) -> Result<()> {
+        let row = sqlx::query(
+            r#"
+            SELECT c.ip_address, c.key_id, c.expires_at,
+                k.expires_at AS key_expires_at, k.groups AS key_groups
+            FROM client_ips c LEFT JOIN keys k ON k.id = c.key_id
+            WHERE c.ip_address = ?
+            "#,
+        )
+        .bind(network.to_string())
+        .fetch_optional(&self.pool)
+        .await?;
+
+        if let Some(row) = row {
+            self.notify(HostChange::Upserted(allow_entry_from_row(row)?));
+        }
+        Ok(())
+    }
+}
 
-        Ok(Self { pool })
+/// Map an address to the 16 byte form used for range bounds, with IPv4
+/// (including IPv4-mapped IPv6) stored as `::ffff:a.b.c.d`.
+fn address_bytes(ip: IpAddr) -> [u8; 16] {
+    match ip.to_canonical() {
+        IpAddr::V4(v4) => v4.to_ipv6_mapped().octets(),
+        IpAddr::V6(v6) => v6.octets(),
     }
 }
 
+fn network_bounds(network: &IpNet) -> ([u8; 16], [u8; 16]) {
+    (
+        address_bytes(network.network()),
+        address_bytes(network.broadcast()),
+    )
+}
+
 #[async_trait]
 impl StorageBackend for SqliteStorage {
-    async fn validate_public_key(&self, public_key: &str) -> Result<bool> {
-        let result = sqlx::query_scalar("SELECT EXISTS(SELECT 1 FROM keys WHERE public_key = ?)")
-            .bind(public_key)
-            .fetch_one(&self.pool)
-            .await?;
-        Ok(result)
+    async fn find_key(&self, public_key: &str) -> Result<Option<super::KeyRecord>> {
+        let _timer = metrics().storage_timer("find_key");
+        let rows = sqlx::query(
+            r#"
+            SELECT id, public_key, created_at, expires_at, ipv4_prefix_len, ipv6_prefix_len, groups, spki_sha256
+            FROM keys WHERE public_key = ?
+            "#,
+        )
+        .bind(public_key)
+        .fetch_all(&self.pool)
+        .await?;
+
+        preferred_key(rows)
     }
-    async fn validate_host_ip(&self, ip_address: &str) -> Result<bool> {
-        let exists =
-            sqlx::query_scalar("SELECT EXISTS(SELECT 1 FROM client_ips WHERE ip_address = ?)")
-                .bind(ip_address)
-                .fetch_one(&self.pool)
-                .await?;
-        Ok(exists)
+    async fn find_key_by_spki(&self, spki_sha256: &str) -> Result<Option<super::KeyRecord>> {
+        let _timer = metrics().storage_timer("find_key_by_spki");
+        let rows = sqlx::query(
+            r#"
+            SELECT id, public_key, created_at, expires_at, ipv4_prefix_len, ipv6_prefix_len, groups, spki_sha256
+            FROM keys WHERE spki_sha256 = ?
+            "#,
+        )
+        .bind(spki_sha256)
+        .fetch_all(&self.pool)
+        .await?;
+
+        preferred_key(rows)
     }
+    async fn delete_expired_keys(&self, expired_before: DateTime<Utc>) -> Result<u64> {
+        let _timer = metrics().storage_timer("delete_expired_keys");
+        let mut tx = self.pool.begin().await?;
+        sqlx::query(
+            r#"
+            DELETE FROM client_ips WHERE key_id IN (
+                SELECT id FROM keys
+                WHERE expires_at IS NOT NULL AND julianday(expires_at) <= julianday(?)
+            )
+            "#,
+        )
+        .bind(expired_before)
+        .execute(&mut *tx)
+        .await?;
+        let result = sqlx::query(
+            "DELETE FROM keys WHERE expires_at IS NOT NULL AND julianday(expires_at) <= julianday(?)",
+        )
+        .bind(expired_before)
+        .execute(&mut *tx)
+        .await?;
+        tx.commit().await?;
+        Ok(result.rows_affected())
+    }
+    async fn find_allow_entries(&self, ip: IpAddr) -> Result<Vec<AllowEntry>> {
+        let _timer = metrics().storage_timer("find_allow_entries");
+        // Hosts stop validating as soon as their lease or the key that
+        // enrolled them expires
+        let rows = sqlx::query(
+            r#"
+            SELECT c.ip_address, c.key_id, c.expires_at,
+                k.expires_at AS key_expires_at, k.groups AS key_groups
+            FROM client_ips c LEFT JOIN keys k ON k.id = c.key_id
+            WHERE c.net_start <= ?1 AND c.net_end >= ?1
+            AND (c.expires_at IS NULL OR julianday(c.expires_at) > julianday(?2))
+            AND (c.key_id IS NULL
+                OR k.expires_at IS NULL
+                OR julianday(k.expires_at) > julianday(?2))
+            "#,
+        )
+        .bind(&address_bytes(ip)[..])
+        .bind(Utc::now())
+        .fetch_all(&self.pool)
+        .await?;
 
-    async fn register_key(&self, keypair: super::KeyPair) -> Result<()> {
-        let id = keypair.id;
-        let now = Utc::now();
+        rows.into_iter().map(allow_entry_from_row).collect()
+    }
 
+    async fn register_key(&self, key: super::KeyRecord) -> Result<()> {
+        let _timer = metrics().storage_timer("register_key");
         sqlx::query(
             r#"
-            INSERT INTO keys (id, public_key, private_key, created_at, expires_at)
-            VALUES (?, ?, ?, ?, ?)
+            INSERT INTO keys (id, public_key, created_at, expires_at, ipv4_prefix_len, ipv6_prefix_len, groups, spki_sha256)
+            VALUES (?, ?, ?, ?, ?, ?, ?, ?)
             "#,
         )
-        .bind(id.to_string())
-        .bind(&keypair.public_key)
-        .bind(&keypair.private_key)
-        .bind(now)
-        .bind(keypair.expires_at)
+        .bind(key.id.to_string())
+        .bind(&key.public_key)
+        .bind(key.created_at)
+        .bind(key.expires_at)
+        .bind(key.ipv4_prefix_len)
+        .bind(key.ipv6_prefix_len)
+        .bind(key.groups.join(","))
+        .bind(&key.spki_sha256)
         .execute(&self.pool)
         .await?;
 
         Ok(())
     }
-    async fn revoke_key(&self, id: Uuid) -> Result<()> {
-        sqlx::query("DELETE FROM keys WHERE id = ?")
+    async fn revoke_key(&self, id: Uuid) -> Result<Option<u64>> {
+        let _timer = metrics().storage_timer("revoke_key");
+        let mut tx = self.pool.begin().await?;
+        let hosts = sqlx::query("DELETE FROM client_ips WHERE key_id = ?")
             .bind(id.to_string())
-            .execute(&self.pool)
+            .execute(&mut *tx)
             .await?;
-        Ok(())
+        let keys = sqlx::query("DELETE FROM keys WHERE id = ?")
+            .bind(id.to_string())
+            .execute(&mut *tx)
+            .await?;
+        // Dropping the transaction rolls it back
+        if keys.rows_affected() == 0 {
+            return Ok(None);
+        }
+        tx.commit().await?;
+        self.notify(HostChange::KeyRevoked(id));
+        Ok(Some(hosts.rows_affected()))
     }
-    async fn store_client_ip(&self, ip_address: String) -> Result<()> {
-        sqlx::query("INSERT INTO client_ips (ip_address, created_at) VALUES (?, ?)")
-            .bind(ip_address)
-            .bind(chrono::Utc::now())
-            .execute(&self.pool)
+    async fn store_client_ip(
+        &self,
+        network: IpNet,
+        key_id: Uuid,
+        expires_at: Option<DateTime<Utc>>,
+        max_ips_per_key: Option<u32>,
+    ) -> Result<HostRegistration> {
+        let _timer = metrics().storage_timer("store_client_ip");
+        let now = Utc::now();
+        let key_id = key_id.to_string();
+        let entry = network.to_string();
+        let mut tx = self.pool.begin().await?;
+
+        let existing: Option<(Option<String>, Option<DateTime<Utc>>)> =
+            sqlx::query_as("SELECT key_id, expires_at FROM client_ips WHERE ip_address = ?")
+                .bind(&entry)
+                .fetch_optional(&mut *tx)
+                .await?;
+
+        let displaced = match existing {
+            Some((Some(owner), _)) if owner == key_id => {
+                sqlx::query(
+                    "UPDATE client_ips SET updated_at = ?, expires_at = ? WHERE ip_address = ?",
+                )
+                .bind(now)
+                .bind(expires_at)
+                .bind(&entry)
+                .execute(&mut *tx)
+                .await?;
+                tx.commit().await?;
+                self.notify_upserted(&network).await?;
+                return Ok(HostRegistration::Refreshed);
+            }
+            // Another key's lease, or a static entry, is only replaced once it has expired
+            Some((_, held_until)) if held_until.is_none_or(|held_until| held_until > now) => {
+                return Ok(HostRegistration::Taken);
+            }
+            Some(_) => true,
+            None => false,
+        };
+
+        let mut replaced = Vec::new();
+        if let Some(max) = max_ips_per_key {
+            let existing: Vec<String> = sqlx::query_scalar(
+                r#"
+                SELECT ip_address FROM client_ips
+                WHERE key_id = ? AND ip_address != ?
+                ORDER BY COALESCE(updated_at, created_at) ASC
+                "#,
+            )
+            .bind(&key_id)
+            .bind(&entry)
+            .fetch_all(&mut *tx)
             .await?;
 
+            // Make room for the new IP by dropping the least recently seen
+            let excess = (existing.len() + 1).saturating_sub(max as usize);
+            for ip in existing.into_iter().take(excess) {
+                sqlx::query("DELETE FROM client_ips WHERE ip_address = ?")
+                    .bind(&ip)
+                    .execute(&mut *tx)
+                    .await?;
+                replaced.push(ip.parse()?);
+            }
+        }
+
+        let (start, end) = network_bounds(&network);
+        sqlx::query(
+            r#"
+            INSERT INTO client_ips (ip_address, net_start, net_end, key_id, created_at, updated_at, expires_at)
+            VALUES (?, ?, ?, ?, ?, ?, ?)
+            ON CONFLICT (ip_address) DO UPDATE SET
+                key_id = excluded.key_id,
+                created_at = excluded.created_at,
+                updated_at = excluded.updated_at,
+                expires_at = excluded.expires_at
+            "#,
+        )
+        .bind(&entry)
+        .bind(&start[..])
+        .bind(&end[..])
+        .bind(&key_id)
+        .bind(now)
+        .bind(now)
+        .bind(expires_at)
+        .execute(&mut *tx)
+        .await?;
+        tx.commit().await?;
+
+        for network in &replaced {
+            self.notify(HostChange::Removed(*network));
+        }
+        if displaced {
+            self.notify(HostChange::Removed(network));
+        }
+        self.notify_upserted(&network).await?;
+
+        if replaced.is_empty() {
+            Ok(HostRegistration::New)
+        } else {
+            Ok(HostRegistration::Moved { replaced })
+        }
+    }
+    async fn renew_client_ip(
+        &self,
+        network: IpNet,
+        key_id: Uuid,
+        expires_at: Option<DateTime<Utc>>,
+    ) -> Result<bool> {
+        let _timer = metrics().storage_timer("renew_client_ip");
+        let result = sqlx::query(
+            r#"
+            UPDATE client_ips SET expires_at = ?1, updated_at = ?2
+            WHERE ip_address = ?3 AND key_id = ?4
+            AND (expires_at IS NULL OR julianday(expires_at) > julianday(?2))
+            "#,
+        )
+        .bind(expires_at)
+        .bind(Utc::now())
+        .bind(network.to_string())
+        .bind(key_id.to_string())
+        .execute(&self.pool)
+        .await?;
+
+        let renewed = result.rows_affected() > 0;
+        if renewed {
+            self.notify_upserted(&network).await?;
+        }
+        Ok(renewed)
+    }
+    async fn allow_network(
+        &self,
+        network: IpNet,
+        key_id: Option<Uuid>,
+        expires_at: Option<DateTime<Utc>>,
+    ) -> Result<()> {
+        let _timer = metrics().storage_timer("allow_network");
+        let network = super::normalize_network(network);
+        let now = Utc::now();
+        let (start, end) = network_bounds(&network);
+        sqlx::query(
+            r#"
+            INSERT INTO client_ips (ip_address, net_start, net_end, key_id, created_at, updated_at, expires_at)
+            VALUES (?, ?, ?, ?, ?, ?, ?)
+            ON CONFLICT (ip_address) DO UPDATE SET
+                key_id = excluded.key_id,
+                updated_at = excluded.updated_at,
+                expires_at = excluded.expires_at
+            "#,
+        )
+        .bind(network.to_string())
+        .bind(&start[..])
+        .bind(&end[..])
+        .bind(key_id.map(|id| id.to_string()))
+        .bind(now)
+        .bind(now)
+        .bind(expires_at)
+        .execute(&self.pool)
+        .await?;
+        self.notify_upserted(&network).await?;
+
         Ok(())
     }
+    async fn delete_expired_hosts(&self, now: DateTime<Utc>) -> Result<u64> {
+        let _timer = metrics().storage_timer("delete_expired_hosts");
+        let result = sqlx::query(
+            "DELETE FROM client_ips WHERE expires_at IS NOT NULL AND julianday(expires_at) <= julianday(?)",
+        )
+        .bind(now)
+        .execute(&self.pool)
+        .await?;
+        Ok(result.rows_affected())
+    }
+    async fn count_hosts(&self) -> Result<u64> {
+        let _timer = metrics().storage_timer("count_hosts");
+        let count: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM client_ips")
+            .fetch_one(&self.pool)
+            .await?;
+        Ok(count as u64)
+    }
     async fn list_hosts(&self) -> Result<Vec<super::HostPair>> {
-        let rows = sqlx::query("SELECT ip_address, created_at FROM client_ips")
+        let _timer = metrics().storage_timer("list_hosts");
+        let rows = sqlx::query("SELECT ip_address, key_id, created_at, expires_at FROM client_ips")
             .fetch_all(&self.pool)
             .await?;
 
         let hosts = rows
             .into_iter()
-            .map(|row| super::HostPair {
-                ip: row.get("ip_address"),
-                created_at: row.get("created_at"),
+            .map(|row| {
+                let key_id = row
+                    .get::<Option<String>, _>("key_id")
+                    .map(|id| Uuid::parse_str(&id))
+                    .transpose()?;
+                Ok(super::HostPair {
+                    network: row.get::<String, _>("ip_address").parse()?,
+                    key_id,
+                    created_at: row.get("created_at"),
+                    expires_at: row.get("expires_at"),
+                })
             })
-            .collect();
+            .collect::<Result<Vec<_>>>()?;
 
         Ok(hosts)
     }
 
-    async fn list_keys(&self) -> Result<Vec<super::KeyPair>> {
-        let rows =
-            sqlx::query("SELECT id, public_key, private_key, created_at, expires_at FROM keys")
-                .fetch_all(&self.pool)
-                .await?;
+    async fn delete_host(&self, network: IpNet) -> Result<bool> {
+        let _timer = metrics().storage_timer("delete_host");
+        let network = super::normalize_network(network);
+        let result = sqlx::query("DELETE FROM client_ips WHERE ip_address = ?")
+            .bind(network.to_string())
+            .execute(&self.pool)
+            .await?;
+        let deleted = result.rows_affected() > 0;
+        if deleted {
+            self.notify(HostChange::Removed(network));
+        }
+        Ok(deleted)
+    }
+
+    async fn list_keys(&self) -> Result<Vec<super::KeyRecord>> {
+        let _timer = metrics().storage_timer("list_keys");
+        let rows = sqlx::query(
+            "SELECT id, public_key, created_at, expires_at, ipv4_prefix_len, ipv6_prefix_len, groups, spki_sha256 FROM keys",
+        )
+            .fetch_all(&self.pool)
+            .await?;
+
+        rows.into_iter().map(key_from_row).collect()
+    }
+
+    async fn count_keys(&self) -> Result<(u64, u64)> {
+        let _timer = metrics().storage_timer("count_keys");
+        let (total, expired): (i64, i64) = sqlx::query_as(
+            r#"
+            SELECT COUNT(*),
+                COUNT(CASE WHEN julianday(expires_at) <= julianday(?) THEN 1 END)
+            FROM keys
+            "#,
+        )
+        .bind(Utc::now())
+        .fetch_one(&self.pool)
+        .await?;
+        Ok(((total - expired) as u64, expired as u64))
+    }
+
+    async fn get_key(&self, id: Uuid) -> Result<Option<super::KeyRecord>> {
+        let _timer = metrics().storage_timer("get_key");
+        let row = sqlx::query(
+            r#"
+            SELECT id, public_key, created_at, expires_at, ipv4_prefix_len, ipv6_prefix_len, groups, spki_sha256
+            FROM keys WHERE id = ?
+            "#,
+        )
+        .bind(id.to_string())
+        .fetch_optional(&self.pool)
+        .await?;
+
+        row.map(key_from_row).transpose()
+    }
+
+    async fn expire_key(&self, id: Uuid, expires_at: DateTime<Utc>) -> Result<bool> {
+        let _timer = metrics().storage_timer("expire_key");
+        let result = sqlx::query("UPDATE keys SET expires_at = ? WHERE id = ?")
+            .bind(expires_at)
+            .bind(id.to_string())
+            .execute(&self.pool)
+            .await?;
+        if result.rows_affected() == 0 {
+            return Ok(false);
+        }
+
+        // Entries carry their key's expiry, so announce each again
+        let rows = sqlx::query(
+            r#"
+            SELECT c.ip_address, c.key_id, c.expires_at,
+                k.expires_at AS key_expires_at, k.groups AS key_groups
+            FROM client_ips c JOIN keys k ON k.id = c.key_id
+            WHERE c.key_id = ?
+            "#,
+        )
+        .bind(id.to_string())
+        .fetch_all(&self.pool)
+        .await?;
+        for row in rows {
+            self.notify(HostChange::Upserted(allow_entry_from_row(row)?));
+        }
+        Ok(true)
+    }
+
+    async fn list_allow_entries(&self) -> Result<Vec<AllowEntry>> {
+        let _timer = metrics().storage_timer("list_allow_entries");
+        let rows = sqlx::query(
+            r#"
+            SELECT c.ip_address, c.key_id, c.expires_at,
+                k.expires_at AS key_expires_at, k.groups AS key_groups
+            FROM client_ips c LEFT JOIN keys k ON k.id = c.key_id
+            WHERE (c.expires_at IS NULL OR julianday(c.expires_at) > julianday(?1))
+            AND (c.key_id IS NULL
+                OR k.expires_at IS NULL
+                OR julianday(k.expires_at) > julianday(?1))
+            "#,
+        )
+        .bind(Utc::now())
+        .fetch_all(&self.pool)
+        .await?;
+
+        rows.into_iter().map(allow_entry_from_row).collect()
+    }
+
+    async fn allowlist_version(&self) -> Result<i64> {
+        let version = sqlx::query_scalar("SELECT version FROM allowlist_version")
+            .fetch_one(&self.pool)
+            .await?;
+        Ok(version)
+    }
+
+    async fn record_audit_event(&self, event: &AuditEvent) -> Result<()> {
+        let _timer = metrics().storage_timer("record_audit_event");
+        sqlx::query(
+            r#"
+            INSERT INTO audit_events (timestamp, action, actor, key_id, ip, network, route, decision, reason, repeats)
+            VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?)
+            "#,
+        )
+        .bind(event.timestamp)
+        .bind(event.action.as_str())
+        .bind(&event.actor)
+        .bind(event.key_id.map(|id| id.to_string()))
+        .bind(event.ip.map(|ip| ip.to_string()))
+        .bind(event.network.map(|net| net.to_string()))
+        .bind(&event.route)
+        .bind(event.decision.as_str())
+        .bind(&event.reason)
+        .bind(event.repeats as i64)
+        .execute(&self.pool)
+        .await?;
+
+        Ok(())
+    }
+
+    async fn query_audit_events(&self, filter: &AuditFilter) -> Result<Vec<AuditEvent>> {
+        let _timer = metrics().storage_timer("query_audit_events");
+        let mut query = QueryBuilder::new(
+            "SELECT timestamp, action, actor, key_id, ip, network, route, decision, reason, repeats FROM audit_events WHERE 1 = 1",
+        );
+        if let Some(since) = filter.since {
+            query
+                .push(" AND julianday(timestamp) >= julianday(")
+                .push_bind(since)
+                .push(")");
+        }
+        if let Some(key_id) = filter.key_id {
+            query.push(" AND key_id = ").push_bind(key_id.to_string());
+        }
+        if let Some(ip) = filter.ip {
+            query
+                .push(" AND ip = ")
+                .push_bind(ip.to_canonical().to_string());
+        }
+        // Newest first so the limit keeps the most recent events
+        query.push(" ORDER BY id DESC");
+        if let Some(limit) = filter.limit {
+            query.push(" LIMIT ").push_bind(limit);
+        }
+        let rows = query.build().fetch_all(&self.pool).await?;
 
-        let keys = rows
+        let mut events = rows
             .into_iter()
-            .map(|row| super::KeyPair {
-                id: Uuid::parse_str(row.get::<String, _>("id").as_str()).unwrap(),
-                public_key: row.get("public_key"),
-                private_key: row.get("private_key"),
-                created_at: row.get("created_at"),
-                expires_at: row.get("expires_at"),
-            })
+            .map(audit_event_from_row)
+            .collect::<Result<Vec<_>>>()?;
+        events.reverse();
+        Ok(events)
+    }
+
+    fn subscribe(&self) -> broadcast::Receiver<HostChange> {
+        self.changes.subscribe()
+    }
+
+    async fn close(&self) {
+        self.pool.close().await;
+    }
+}
+
+fn allow_entry_from_row(row: SqliteRow) -> Result<AllowEntry> {
+    let key_id = row
+        .get::<Option<String>, _>("key_id")
+        .map(|id| Uuid::parse_str(&id))
+        .transpose()?;
+    let lease_expires_at: Option<DateTime<Utc>> = row.get("expires_at");
+    let key_expires_at: Option<DateTime<Utc>> = row.get("key_expires_at");
+    // The entry lapses with whichever of its lease and its key ends first
+    let expires_at = match (lease_expires_at, key_expires_at) {
+        (Some(lease), Some(key)) => Some(lease.min(key)),
+        (lease, key) => lease.or(key),
+    };
+    Ok(AllowEntry {
+        network: row.get::<String, _>("ip_address").parse()?,
+        key_id,
+        groups: split_groups(row.get("key_groups")),
+        expires_at,
+    })
+}
+
+fn split_groups(groups: Option<String>) -> Vec<String> {
+    groups
+        .unwrap_or_default()
+        .split(',')
+        .filter(|group| !group.is_empty())
+        .map(str::to_string)
+        .collect()
+}
+
+fn key_from_row(row: SqliteRow) -> Result<super::KeyRecord> {
+    Ok(super::KeyRecord {
+        id: Uuid::parse_str(row.get::<String, _>("id").as_str())?,
+        public_key: row.get("public_key"),
+        created_at: row.get("created_at"),
+        expires_at: row.get("expires_at"),
+        ipv4_prefix_len: row.get("ipv4_prefix_len"),
+        ipv6_prefix_len: row.get("ipv6_prefix_len"),
+        groups: split_groups(row.get("groups")),
+        spki_sha256: row.get("spki_sha256"),
+    })
+}
+
+fn audit_event_from_row(row: SqliteRow) -> Result<AuditEvent> {
+    Ok(AuditEvent {
+        timestamp: row.get("timestamp"),
+        action: row.get::<String, _>("action").parse()?,
+        actor: row.get("actor"),
+        key_id: row
+            .get::<Option<String>, _>("key_id")
+            .map(|id| Uuid::parse_str(&id))
+            .transpose()?,
+        ip: row
+            .get::<Option<String>, _>("ip")
+            .map(|ip| ip.parse())
+            .transpose()?,
+        network: row
+            .get::<Option<String>, _>("network")
+            .map(|net| net.parse())
+            .transpose()?,
+        route: row.get("route"),
+        decision: row.get::<String, _>("decision").parse()?,
+        reason: row.get("reason"),
+        repeats: row.get::<i64, _>("repeats") as u64,
+    })
+}
+
+/// The record to use when a lookup matches several keys: an active one,
+/// then the one that expires last.
+fn preferred_key(rows: Vec<SqliteRow>) -> Result<Option<super::KeyRecord>> {
+    let mut keys = rows
+        .into_iter()
+        .map(key_from_row)
+        .collect::<Result<Vec<_>>>()?;
+    keys.sort_by_key(|k| (!k.is_expired(), k.expires_at.is_none(), k.expires_at));
+
+    Ok(keys.pop())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::KeyRecord;
+    use chrono::Duration;
+
+    async fn with_key() -> (SqliteStorage, Uuid) {
+        let storage = SqliteStorage::in_memory().await.unwrap();
+        let (_, public_key) = crate::cert::generate_keys().unwrap();
+        let key = KeyRecord::new(public_key, None).unwrap();
+        storage.register_key(key.clone()).await.unwrap();
+        (storage, key.id)
+    }
+
+    fn host() -> IpNet {
+        "192.0.2.1/32".parse().unwrap()
+    }
+
+    #[tokio::test]
+    async fn leases_are_renewed_by_their_key() {
+        let (storage, key_id) = with_key().await;
+        let now = Utc::now();
+        let registered = storage
+            .store_client_ip(host(), key_id, Some(now + Duration::hours(1)), None)
+            .await
+            .unwrap();
+        assert!(matches!(registered, HostRegistration::New));
+
+        let renewed_until = now + Duration::hours(2);
+        assert!(storage
+            .renew_client_ip(host(), key_id, Some(renewed_until))
+            .await
+            .unwrap());
+        let entries = storage.list_allow_entries().await.unwrap();
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].expires_at, Some(renewed_until));
+
+        // Only the key holding the lease may renew it
+        assert!(!storage
+            .renew_client_ip(host(), Uuid::new_v4(), Some(renewed_until))
+            .await
+            .unwrap());
+        let other: IpNet = "192.0.2.2/32".parse().unwrap();
+        assert!(!storage
+            .renew_client_ip(other, key_id, Some(renewed_until))
+            .await
+            .unwrap());
+    }
+
+    #[tokio::test]
+    async fn expired_leases_stop_validating_and_are_pruned() {
+        let (storage, key_id) = with_key().await;
+        let now = Utc::now();
+        storage
+            .store_client_ip(host(), key_id, Some(now - Duration::seconds(1)), None)
+            .await
+            .unwrap();
+        let ip = "192.0.2.1".parse().unwrap();
+
+        assert!(storage.find_allow_entries(ip).await.unwrap().is_empty());
+        assert!(storage.list_allow_entries().await.unwrap().is_empty());
+        // A lapsed lease has to be registered again, not renewed
+        assert!(!storage
+            .renew_client_ip(host(), key_id, Some(now + Duration::hours(1)))
+            .await
+            .unwrap());
+        assert_eq!(storage.delete_expired_hosts(now).await.unwrap(), 1);
+        assert!(storage.list_hosts().await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn hosts_without_a_lease_do_not_expire() {
+        let (storage, key_id) = with_key().await;
+        storage
+            .store_client_ip(host(), key_id, None, None)
+            .await
+            .unwrap();
+
+        let later = Utc::now() + Duration::days(365);
+        assert_eq!(storage.delete_expired_hosts(later).await.unwrap(), 0);
+        let ip = "192.0.2.1".parse().unwrap();
+        assert_eq!(storage.find_allow_entries(ip).await.unwrap().len(), 1);
+    }
+
+    async fn another_key(storage: &SqliteStorage) -> Uuid {
+        let (_, public_key) = crate::cert::generate_keys().unwrap();
+        let key = KeyRecord::new(public_key, None).unwrap();
+        storage.register_key(key.clone()).await.unwrap();
+        key.id
+    }
+
+    #[tokio::test]
+    async fn migrations_apply_once() {
+        let storage = SqliteStorage::in_memory().await.unwrap();
+        let applied: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM _sqlx_migrations")
+            .fetch_one(&storage.pool)
+            .await
+            .unwrap();
+        let files = std::fs::read_dir(concat!(env!("CARGO_MANIFEST_DIR"), "/src/migrations"))
+            .unwrap()
+            .count();
+        assert_eq!(applied as usize, files);
+
+        // Opening an up to date database again changes nothing
+        let reopened = SqliteStorage::with_pool(storage.pool.clone())
+            .await
+            .unwrap();
+        assert_eq!(reopened.allowlist_version().await.unwrap(), 0);
+        assert!(reopened.list_keys().await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn revoking_a_key_removes_its_hosts() {
+        let (storage, key_id) = with_key().await;
+        let other = another_key(&storage).await;
+        let enrolled: IpNet = "192.0.2.2/32".parse().unwrap();
+        let owned: IpNet = "198.51.100.0/24".parse().unwrap();
+        let unowned: IpNet = "203.0.113.0/24".parse().unwrap();
+        let others: IpNet = "192.0.2.3/32".parse().unwrap();
+        storage
+            .store_client_ip(host(), key_id, None, None)
+            .await
+            .unwrap();
+        storage
+            .store_client_ip(enrolled, key_id, None, None)
+            .await
+            .unwrap();
+        storage
+            .allow_network(owned, Some(key_id), None)
+            .await
+            .unwrap();
+        storage.allow_network(unowned, None, None).await.unwrap();
+        storage
+            .store_client_ip(others, other, None, None)
+            .await
+            .unwrap();
+        let mut changes = storage.subscribe();
+
+        assert_eq!(storage.revoke_key(key_id).await.unwrap(), Some(3));
+        assert!(storage.get_key(key_id).await.unwrap().is_none());
+        let mut left: Vec<IpNet> = storage
+            .list_hosts()
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|host| host.network)
             .collect();
+        left.sort();
+        assert_eq!(left, [others, unowned]);
+        assert!(matches!(
+            changes.try_recv().unwrap(),
+            HostChange::KeyRevoked(id) if id == key_id
+        ));
+
+        // A key that is already gone is not found, and nothing is announced
+        assert_eq!(storage.revoke_key(key_id).await.unwrap(), None);
+        assert!(changes.try_recv().is_err());
+        assert_eq!(storage.list_hosts().await.unwrap().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn hosts_are_listed_with_their_key() {
+        let (storage, key_id) = with_key().await;
+        let lease = Utc::now() + Duration::hours(1);
+        storage
+            .store_client_ip(host(), key_id, Some(lease), None)
+            .await
+            .unwrap();
+        let network: IpNet = "2001:db8::/48".parse().unwrap();
+        storage.allow_network(network, None, None).await.unwrap();
+
+        let mut hosts = storage.list_hosts().await.unwrap();
+        hosts.sort_by_key(|host| host.network);
+        assert_eq!(hosts.len(), 2);
+        assert_eq!(hosts[0].network, host());
+        assert_eq!(hosts[0].key_id, Some(key_id));
+        assert_eq!(hosts[0].expires_at, Some(lease));
+        assert_eq!(hosts[1].network, network);
+        assert_eq!(hosts[1].key_id, None);
+        assert_eq!(hosts[1].expires_at, None);
+    }
+
+    #[tokio::test]
+    async fn registering_a_held_address_is_refused() {
+        let (storage, key_id) = with_key().await;
+        let other = another_key(&storage).await;
+        let now = Utc::now();
+        let register =
+            |key_id, expires_at| storage.store_client_ip(host(), key_id, expires_at, None);
+
+        assert_eq!(register(key_id, None).await.unwrap(), HostRegistration::New);
+        assert_eq!(
+            register(key_id, Some(now + Duration::hours(1)))
+                .await
+                .unwrap(),
+            HostRegistration::Refreshed
+        );
+        assert_eq!(
+            register(other, None).await.unwrap(),
+            HostRegistration::Taken
+        );
+
+        // Once the lease lapses another key may take the address over
+        storage
+            .renew_client_ip(host(), key_id, Some(now - Duration::seconds(1)))
+            .await
+            .unwrap();
+        assert_eq!(register(other, None).await.unwrap(), HostRegistration::New);
+        let hosts = storage.list_hosts().await.unwrap();
+        assert_eq!(hosts[0].key_id, Some(other));
+
+        // Static networks are never taken over
+        let network: IpNet = "198.51.100.0/24".parse().unwrap();
+        storage.allow_network(network, None, None).await.unwrap();
+        assert_eq!(
+            storage
+                .store_client_ip(network, key_id, None, None)
+                .await
+                .unwrap(),
+            HostRegistration::Taken
+        );
+    }
+
+    #[tokio::test]
+    async fn keys_at_their_limit_move_to_the_new_address() {
+        let (storage, key_id) = with_key().await;
+        let moved: IpNet = "192.0.2.2/32".parse().unwrap();
+        storage
+            .store_client_ip(host(), key_id, None, Some(1))
+            .await
+            .unwrap();
 
-        Ok(keys)
+        assert_eq!(
+            storage
+                .store_client_ip(moved, key_id, None, Some(1))
+                .await
+                .unwrap(),
+            HostRegistration::Moved {
+                replaced: vec![host()]
+            }
+        );
+        let hosts = storage.list_hosts().await.unwrap();
+        assert_eq!(hosts.len(), 1);
+        assert_eq!(hosts[0].network, moved);
     }
 }
@@ -0,0 +1,230 @@
+use crate::config::{ApiTlsConfig, ClientAuth, TlsConfig, UpstreamTlsConfig};
+use anyhow::{Context, Result};
+use base64::{engine::general_purpose, Engine as _};
+use sha2::{Digest, Sha256};
+use std::net::SocketAddr;
+use std::sync::Arc;
+use tokio_rustls::rustls::client::danger::HandshakeSignatureValid;
+use tokio_rustls::rustls::crypto::{ring, CryptoProvider, WebPkiSupportedAlgorithms};
+use tokio_rustls::rustls::pki_types::{CertificateDer, PrivateKeyDer, ServerName, UnixTime};
+use tokio_rustls::rustls::server::danger::{ClientCertVerified, ClientCertVerifier};
+use tokio_rustls::rustls::{
+    ClientConfig, DigitallySignedStruct, DistinguishedName, RootCertStore, ServerConfig,
+    SignatureScheme,
+};
+use tokio_rustls::TlsConnector;
+
+fn provider() -> Arc<CryptoProvider> {
+    Arc::new(ring::default_provider())
+}
+
+/// Build the listener side TLS configuration for a route.
+pub fn server_config(tls: &TlsConfig) -> Result<Arc<ServerConfig>> {
+    let client_auth = match tls.client_auth {
+        ClientAuth::None => None,
+        ClientAuth::Key => Some(true),
+    };
+    build_server_config(&tls.cert_file, &tls.key_file, client_auth).map(Arc::new)
+}
+
+/// Build the TLS configuration for the HTTP API. Clients may present a
+/// certificate, which is checked against the admin pins per request.
+pub fn api_server_config(tls: &ApiTlsConfig) -> Result<ServerConfig> {
+    build_server_config(&tls.cert_file, &tls.key_file, Some(false))
+}
+
+/// `client_auth` asks for a client certificate, requiring one when true.
+fn build_server_config(
+    cert_file: &str,
+    key_file: &str,
+    client_auth: Option<bool>,
+) -> Result<ServerConfig> {
+    let certs = load_certs(cert_file)?;
+    let key = load_key(key_file)?;
+    let provider = provider();
+    let builder = ServerConfig::builder_with_provider(Arc::clone(&provider))
+        .with_safe_default_protocol_versions()?;
+    let builder = match client_auth {
+        None => builder.with_no_client_auth(),
+        Some(mandatory) => builder.with_client_cert_verifier(Arc::new(PinnedClientCert {
+            algorithms: provider.signature_verification_algorithms,
+            mandatory,
+        })),
+    };
+    builder
+        .with_single_cert(certs, key)
+        .with_context(|| format!("invalid certificate or key in {}", cert_file))
+}
+
+/// Base64 SHA-256 of a certificate's DER encoded SubjectPublicKeyInfo, the
+/// pin stored against keys for `client_auth: key` routes.
+pub fn spki_sha256(cert: &CertificateDer<'_>) -> Result<String> {
+    let cert = webpki::EndEntityCert::try_from(cert)
+        .map_err(|e| anyhow::anyhow!("invalid certificate: {}", e))?;
+    let digest = Sha256::digest(cert.subject_public_key_info().as_ref());
+    Ok(general_purpose::STANDARD.encode(digest))
+}
+
+/// The pin of the first certificate in a PEM file.
+pub fn spki_sha256_from_file(path: &str) -> Result<String> {
+    let certs = load_certs(path)?;
+    spki_sha256(&certs[0]).with_context(|| format!("invalid certificate in {}", path))
+}
+
+/// Requires a client certificate and proof of its private key, but trusts
+/// no issuer. Whether the certificate belongs to a registered key is
+/// checked against storage once the handshake completes.
+#[derive(Debug)]
+struct PinnedClientCert {
+    algorithms: WebPkiSupportedAlgorithms,
+    // Otherwise clients without a certificate are let through
+    mandatory: bool,
+}
+
+impl ClientCertVerifier for PinnedClientCert {
+    fn client_auth_mandatory(&self) -> bool {
+        self.mandatory
+    }
+
+    fn root_hint_subjects(&self) -> &[DistinguishedName] {
+        &[]
+    }
+
+    fn verify_client_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _now: UnixTime,
+    ) -> Result<ClientCertVerified, tokio_rustls::rustls::Error> {
+        webpki::EndEntityCert::try_from(end_entity).map_err(|_| {
+            tokio_rustls::rustls::Error::InvalidCertificate(
+                tokio_rustls::rustls::CertificateError::BadEncoding,
+            )
+        })?;
+        Ok(ClientCertVerified::assertion())
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, tokio_rustls::rustls::Error> {
+        tokio_rustls::rustls::crypto::verify_tls12_signature(message, cert, dss, &self.algorithms)
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, tokio_rustls::rustls::Error> {
+        tokio_rustls::rustls::crypto::verify_tls13_signature(message, cert, dss, &self.algorithms)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.algorithms.supported_schemes()
+    }
+}
+
+/// Build the connector used to speak TLS to a route's upstreams, trusting
+/// `ca_file` if set and the bundled web PKI roots otherwise.
+pub fn connector(tls: &UpstreamTlsConfig) -> Result<TlsConnector> {
+    let mut roots = RootCertStore::empty();
+    match &tls.ca_file {
+        Some(ca_file) => {
+            for cert in load_certs(ca_file)? {
+                roots
+                    .add(cert)
+                    .with_context(|| format!("invalid CA certificate in {}", ca_file))?;
+            }
+        }
+        None => roots.extend(webpki_roots::TLS_SERVER_ROOTS.iter().cloned()),
+    }
+    let config = ClientConfig::builder_with_provider(provider())
+        .with_safe_default_protocol_versions()?
+        .with_root_certificates(roots)
+        .with_no_client_auth();
+    Ok(TlsConnector::from(Arc::new(config)))
+}
+
+/// The name to verify an upstream's certificate against: `server_name` if
+/// configured, otherwise the backend's IP address.
+pub fn upstream_server_name(
+    tls: &UpstreamTlsConfig,
+    backend: SocketAddr,
+) -> Result<ServerName<'static>> {
+    match &tls.server_name {
+        Some(name) => ServerName::try_from(name.clone())
+            .with_context(|| format!("invalid upstream server_name {:?}", name)),
+        None => Ok(ServerName::IpAddress(backend.ip().into())),
+    }
+}
+
+/// Whether an SNI `name` matches one of `server_names`. A leading `*.`
+/// matches exactly one label.
+pub fn sni_matches(server_names: &[String], name: &str) -> bool {
+    server_names
+        .iter()
+        .any(|pattern| match pattern.strip_prefix("*.") {
+            Some(suffix) => name
+                .split_once('.')
+                .is_some_and(|(_, rest)| rest.eq_ignore_ascii_case(suffix)),
+            None => pattern.eq_ignore_ascii_case(name),
+        })
+}
+
+fn load_certs(path: &str) -> Result<Vec<CertificateDer<'static>>> {
+    let pem = std::fs::read(path).with_context(|| format!("failed to read {}", path))?;
+    let certs = rustls_pemfile::certs(&mut pem.as_slice())
+        .collect::<Result<Vec<_>, _>>()
+        .with_context(|| format!("invalid PEM in {}", path))?;
+    if certs.is_empty() {
+        anyhow::bail!("no certificates found in {}", path);
+    }
+    Ok(certs)
+}
+
+fn load_key(path: &str) -> Result<PrivateKeyDer<'static>> {
+    let pem = std::fs::read(path).with_context(|| format!("failed to read {}", path))?;
+    rustls_pemfile::private_key(&mut pem.as_slice())
+        .with_context(|| format!("invalid PEM in {}", path))?
+        .with_context(|| format!("no private key found in {}", path))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn names(names: &[&str]) -> Vec<String> {
+        names.iter().map(|name| name.to_string()).collect()
+    }
+
+    #[test]
+    fn exact_names_ignore_case() {
+        let names = names(&["api.example.com"]);
+        assert!(sni_matches(&names, "api.example.com"));
+        assert!(sni_matches(&names, "API.Example.COM"));
+        assert!(!sni_matches(&names, "example.com"));
+        assert!(!sni_matches(&names, "www.api.example.com"));
+    }
+
+    #[test]
+    fn wildcards_match_one_label() {
+        let names = names(&["*.example.com"]);
+        assert!(sni_matches(&names, "api.example.com"));
+        assert!(sni_matches(&names, "WWW.EXAMPLE.com"));
+        assert!(!sni_matches(&names, "example.com"));
+        assert!(!sni_matches(&names, "a.b.example.com"));
+        assert!(!sni_matches(&names, "api.example.org"));
+    }
+
+    #[test]
+    fn any_name_may_match() {
+        let names = names(&["a.example.com", "*.example.org"]);
+        assert!(sni_matches(&names, "a.example.com"));
+        assert!(sni_matches(&names, "b.example.org"));
+        assert!(!sni_matches(&names, "b.example.com"));
+        assert!(!sni_matches(&[], "a.example.com"));
+    }
+}
@@ -0,0 +1,391 @@
+use crate::config::{BalanceStrategy, HealthCheckConfig, RouteConfig};
+use crate::metrics::metrics;
+use anyhow::Result;
+use futures_util::future::join_all;
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use std::net::{IpAddr, SocketAddr};
+use std::sync::atomic::{AtomicBool, AtomicU32, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::net::TcpStream;
+use tokio_util::sync::CancellationToken;
+use tracing::{info, warn};
+
+/// The backends of one route, with their health and open connection counts.
+#[derive(Debug)]
+pub struct UpstreamPool {
+    route: String,
+    backends: Vec<Arc<Backend>>,
+    strategy: BalanceStrategy,
+    dial_attempts: usize,
+    connect_timeout: Duration,
+    next: AtomicUsize,
+}
+
+#[derive(Debug)]
+struct Backend {
+    addr: SocketAddr,
+    healthy: AtomicBool,
+    active: AtomicUsize,
+    // Consecutive health check results against the current state
+    streak: AtomicU32,
+}
+
+/// Counts a connection against its backend until dropped.
+#[derive(Debug)]
+pub struct BackendGuard {
+    backend: Arc<Backend>,
+}
+
+impl BackendGuard {
+    pub fn addr(&self) -> SocketAddr {
+        self.backend.addr
+    }
+}
+
+impl Drop for BackendGuard {
+    fn drop(&mut self) {
+        self.backend.active.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+impl UpstreamPool {
+    pub fn new(route: &RouteConfig) -> Result<Self> {
+        let backends = route
+            .upstream_addrs()
+            .into_iter()
+            .map(|addr| {
+                Ok(Arc::new(Backend {
+                    addr: addr.parse()?,
+                    healthy: AtomicBool::new(true),
+                    active: AtomicUsize::new(0),
+                    streak: AtomicU32::new(0),
+                }))
+            })
+            .collect::<Result<Vec<_>>>()?;
+        let dial_attempts = route
+            .dial_attempts
+            .map_or(backends.len(), |attempts| attempts as usize);
+
+        Ok(Self {
+            route: route.name.clone(),
+            backends,
+            strategy: route.balance,
+            dial_attempts,
+            connect_timeout: Duration::from_millis(route.limits.connect_timeout_ms),
+            next: AtomicUsize::new(0),
+        })
+    }
+
+    /// Connect to a backend for `client_ip`, moving on to the next candidate
+    /// when a dial fails or takes longer than the connect timeout, up to
+    /// `dial_attempts` backends.
+    pub async fn dial(&self, client_ip: IpAddr) -> Result<(TcpStream, BackendGuard)> {
+        let mut last_error = None;
+        for backend in self
+            .candidates(client_ip)
+            .into_iter()
+            .take(self.dial_attempts)
+        {
+            let started = Instant::now();
+            let dial = tokio::time::timeout(self.connect_timeout, TcpStream::connect(backend.addr));
+            match dial.await.unwrap_or_else(|_| {
+                Err(std::io::Error::new(
+                    std::io::ErrorKind::TimedOut,
+                    "connect timed out",
+                ))
+            }) {
+                Ok(stream) => {
+                    metrics()
+                        .dial_seconds
+                        .with_label_values(&[&self.route])
+                        .observe(started.elapsed().as_secs_f64());
+                    backend.active.fetch_add(1, Ordering::Relaxed);
+                    return Ok((stream, BackendGuard { backend }));
+                }
+                Err(e) => {
+                    metrics()
+                        .dial_failures
+                        .with_label_values(&[self.route.as_str(), &backend.addr.to_string()])
+                        .inc();
+                    warn!(
+                        "route {}: failed to connect to upstream {}: {}",
+                        self.route, backend.addr, e
+                    );
+                    last_error = Some(e);
+                }
+            }
+        }
+        match last_error {
+            Some(e) => Err(anyhow::anyhow!("no upstream reachable, last error: {}", e)),
+            None => Err(anyhow::anyhow!("no upstreams configured")),
+        }
+    }
+
+    /// How long a dial, including any upstream TLS handshake, may take.
+    pub fn connect_timeout(&self) -> Duration {
+        self.connect_timeout
+    }
+
+    /// Pick a backend for `client_ip` without dialing it, for datagram
+    /// routes where there is no connection to attempt.
+    pub fn pick(&self, client_ip: IpAddr) -> Option<BackendGuard> {
+        let backend = self.candidates(client_ip).into_iter().next()?;
+        backend.active.fetch_add(1, Ordering::Relaxed);
+        Some(BackendGuard { backend })
+    }
+
+    /// Backends in the order they should be tried. Ejected backends are
+    /// skipped unless every backend is ejected, in which case all of them
+    /// are tried rather than failing outright.
+    fn candidates(&self, client_ip: IpAddr) -> Vec<Arc<Backend>> {
+        let healthy: Vec<Arc<Backend>> = self
+            .backends
+            .iter()
+            .filter(|backend| backend.healthy.load(Ordering::Relaxed))
+            .cloned()
+            .collect();
+        let mut candidates = if healthy.is_empty() {
+            self.backends.clone()
+        } else {
+            healthy
+        };
+        if candidates.is_empty() {
+            return candidates;
+        }
+
+        match self.strategy {
+            BalanceStrategy::RoundRobin => {
+                let start = self.next.fetch_add(1, Ordering::Relaxed) % candidates.len();
+                candidates.rotate_left(start);
+            }
+            BalanceStrategy::LeastConn => {
+                // Rotate first so ties are spread round-robin
+                let start = self.next.fetch_add(1, Ordering::Relaxed) % candidates.len();
+                candidates.rotate_left(start);
+                candidates.sort_by_key(|backend| backend.active.load(Ordering::Relaxed));
+            }
+            BalanceStrategy::ConsistentHash => {
+                // Rendezvous hashing: a client only moves when its backend
+                // leaves the pool, and then to its next highest scoring one
+                let client_ip = client_ip.to_canonical();
+                candidates.sort_by_key(|backend| {
+                    let mut hasher = DefaultHasher::new();
+                    client_ip.hash(&mut hasher);
+                    backend.addr.hash(&mut hasher);
+                    std::cmp::Reverse(hasher.finish())
+                });
+            }
+        }
+        candidates
+    }
+
+    /// Periodically dial every backend, ejecting and restoring them as
+    /// their checks fail and recover, until `stop` is cancelled.
+    pub fn spawn_health_checks(
+        self: &Arc<Self>,
+        config: HealthCheckConfig,
+        stop: CancellationToken,
+    ) {
+        if !config.enabled {
+            return;
+        }
+        let pool = Arc::clone(self);
+        tokio::spawn(async move {
+            let timeout = Duration::from_millis(config.timeout_ms);
+            let mut ticker = tokio::time::interval(Duration::from_secs(config.interval_secs));
+            loop {
+                tokio::select! {
+                    _ = ticker.tick() => {}
+                    _ = stop.cancelled() => return,
+                }
+                // Dial all at once, so unresponsive backends do not hold up the rest
+                let checks = pool.backends.iter().map(|backend| async move {
+                    let dial = tokio::time::timeout(timeout, TcpStream::connect(backend.addr));
+                    (backend, matches!(dial.await, Ok(Ok(_))))
+                });
+                for (backend, up) in join_all(checks).await {
+                    pool.record_check(backend, up, &config);
+                }
+            }
+        });
+    }
+
+    fn record_check(&self, backend: &Backend, up: bool, config: &HealthCheckConfig) {
+        let healthy = backend.healthy.load(Ordering::Relaxed);
+        if up == healthy {
+            backend.streak.store(0, Ordering::Relaxed);
+            return;
+        }
+        let streak = backend.streak.fetch_add(1, Ordering::Relaxed) + 1;
+        let threshold = if healthy {
+            config.unhealthy_threshold
+        } else {
+            config.healthy_threshold
+        };
+        if streak >= threshold {
+            backend.healthy.store(up, Ordering::Relaxed);
+            backend.streak.store(0, Ordering::Relaxed);
+            if up {
+                info!(
+                    "route {}: upstream {} is healthy again",
+                    self.route, backend.addr
+                );
+            } else {
+                warn!(
+                    "route {}: ejecting unhealthy upstream {}",
+                    self.route, backend.addr
+                );
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pool(strategy: BalanceStrategy) -> UpstreamPool {
+        let mut route = RouteConfig::new("test", "127.0.0.1:0", "127.0.0.1:4001");
+        route.upstreams = vec!["127.0.0.1:4002".to_string(), "127.0.0.1:4003".to_string()];
+        route.balance = strategy;
+        UpstreamPool::new(&route).unwrap()
+    }
+
+    fn ports(pool: &UpstreamPool, client_ip: &str) -> Vec<u16> {
+        pool.candidates(client_ip.parse().unwrap())
+            .iter()
+            .map(|backend| backend.addr.port())
+            .collect()
+    }
+
+    #[test]
+    fn round_robin_rotates_through_the_backends() {
+        let pool = pool(BalanceStrategy::RoundRobin);
+        assert_eq!(ports(&pool, "192.0.2.1"), [4001, 4002, 4003]);
+        assert_eq!(ports(&pool, "192.0.2.1"), [4002, 4003, 4001]);
+        assert_eq!(ports(&pool, "192.0.2.2"), [4003, 4001, 4002]);
+        assert_eq!(ports(&pool, "192.0.2.1"), [4001, 4002, 4003]);
+    }
+
+    #[test]
+    fn least_conn_prefers_the_least_busy_backend() {
+        let pool = pool(BalanceStrategy::LeastConn);
+        pool.backends[0].active.store(2, Ordering::Relaxed);
+        pool.backends[2].active.store(1, Ordering::Relaxed);
+        assert_eq!(ports(&pool, "192.0.2.1"), [4002, 4003, 4001]);
+
+        // Guards count against their backend until dropped
+        let guard = pool.pick("192.0.2.1".parse().unwrap()).unwrap();
+        assert_eq!(guard.addr().port(), 4002);
+        pool.backends[2].active.store(0, Ordering::Relaxed);
+        assert_eq!(ports(&pool, "192.0.2.1")[0], 4003);
+        drop(guard);
+        pool.backends[2].active.store(1, Ordering::Relaxed);
+        assert_eq!(ports(&pool, "192.0.2.1")[0], 4002);
+    }
+
+    #[test]
+    fn least_conn_spreads_ties_round_robin() {
+        let pool = pool(BalanceStrategy::LeastConn);
+        let first: Vec<u16> = (0..3).map(|_| ports(&pool, "192.0.2.1")[0]).collect();
+        assert_eq!(first, [4001, 4002, 4003]);
+    }
+
+    #[test]
+    fn consistent_hash_keeps_clients_on_their_backend() {
+        let pool = pool(BalanceStrategy::ConsistentHash);
+        let order = ports(&pool, "192.0.2.1");
+        assert_eq!(ports(&pool, "192.0.2.1"), order);
+        assert_eq!(ports(&pool, "::ffff:192.0.2.1"), order);
+
+        // Clients are spread over every backend
+        let mut chosen: Vec<u16> = (0..64)
+            .map(|host| ports(&pool, &format!("192.0.2.{}", host))[0])
+            .collect();
+        chosen.sort();
+        chosen.dedup();
+        assert_eq!(chosen, [4001, 4002, 4003]);
+
+        // Losing its backend moves a client to its next choice only
+        let first = pool
+            .backends
+            .iter()
+            .find(|backend| backend.addr.port() == order[0])
+            .unwrap();
+        first.healthy.store(false, Ordering::Relaxed);
+        assert_eq!(ports(&pool, "192.0.2.1"), order[1..]);
+    }
+
+    #[test]
+    fn ejected_backends_are_tried_only_when_all_are() {
+        let pool = pool(BalanceStrategy::RoundRobin);
+        pool.backends[1].healthy.store(false, Ordering::Relaxed);
+        for _ in 0..3 {
+            assert!(!ports(&pool, "192.0.2.1").contains(&4002));
+        }
+
+        for backend in &pool.backends {
+            backend.healthy.store(false, Ordering::Relaxed);
+        }
+        assert_eq!(ports(&pool, "192.0.2.1").len(), 3);
+    }
+
+    #[test]
+    fn checks_eject_and_restore_at_their_thresholds() {
+        let pool = pool(BalanceStrategy::RoundRobin);
+        let config = HealthCheckConfig {
+            unhealthy_threshold: 3,
+            healthy_threshold: 2,
+            ..HealthCheckConfig::default()
+        };
+        let backend = &pool.backends[0];
+        let healthy = || backend.healthy.load(Ordering::Relaxed);
+
+        // A passing check resets the count of failures
+        for up in [false, false, true, false, false] {
+            pool.record_check(backend, up, &config);
+        }
+        assert!(healthy());
+        pool.record_check(backend, false, &config);
+        assert!(!healthy());
+
+        pool.record_check(backend, true, &config);
+        pool.record_check(backend, false, &config);
+        pool.record_check(backend, true, &config);
+        assert!(!healthy());
+        pool.record_check(backend, true, &config);
+        assert!(healthy());
+    }
+
+    #[tokio::test]
+    async fn health_checks_eject_unreachable_backends() {
+        let live = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let dead = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let dead_addr = dead.local_addr().unwrap().to_string();
+        drop(dead);
+        let mut route = RouteConfig::new(
+            "test",
+            "127.0.0.1:0",
+            &live.local_addr().unwrap().to_string(),
+        );
+        route.upstreams = vec![dead_addr];
+        let pool = Arc::new(UpstreamPool::new(&route).unwrap());
+        let config = HealthCheckConfig {
+            enabled: true,
+            unhealthy_threshold: 1,
+            ..HealthCheckConfig::default()
+        };
+        let stop = CancellationToken::new();
+        pool.spawn_health_checks(config, stop.clone());
+
+        let deadline = Instant::now() + Duration::from_secs(2);
+        while pool.backends[1].healthy.load(Ordering::Relaxed) {
+            assert!(Instant::now() < deadline, "unreachable backend not ejected");
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        assert!(pool.backends[0].healthy.load(Ordering::Relaxed));
+        stop.cancel();
+    }
+}